@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use egui::{Button, ComboBox, Slider};
+use serde::{Deserialize, Serialize};
+use crate::params::ParamValue;
+use crate::timelapse;
+use crate::timelapse::{Capture, Session, Timelapse, Trigger};
+use crate::weights::WeightHandle;
+
+/// How a keyframe blends into the next one
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    /// Holds the keyframe until the next one
+    Step,
+}
+
+impl Easing {
+    pub const ALL: [Easing; 5] = [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut, Easing::Step];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::EaseIn => "ease in",
+            Easing::EaseOut => "ease out",
+            Easing::EaseInOut => "ease in out",
+            Easing::Step => "step",
+        }
+    }
+
+    pub fn apply(&self, t: f32) -> f32 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+            Easing::Step => 0.0,
+        }
+    }
+}
+
+/// Brush inputs at a frame of the animation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Keyframe {
+    pub frame: u32,
+    pub weights: Vec<WeightHandle>,
+    /// Linear brush colour
+    pub color: [f32; 4],
+    /// Values of every parameter of the brush the keyframe was set with
+    pub params: HashMap<String, ParamValue>,
+    /// Easing towards the next keyframe
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl Keyframe {
+    /// Blends towards `next` by `t`. Handles only one of them has stay where they are.
+    fn lerp(&self, next: &Keyframe, t: f32) -> Keyframe {
+        let weights = self.weights.iter().enumerate().map(|(i, a)| match next.weights.get(i) {
+            Some(b) => WeightHandle {
+                pos: a.pos.lerp(b.pos, t),
+                strength: a.strength + (b.strength - a.strength) * t,
+                radius: a.radius + (b.radius - a.radius) * t,
+            },
+            None => *a,
+        }).collect();
+
+        let params = self.params.iter().map(|(name, a)| {
+            let value = next.params.get(name).map(|b| a.lerp(b, t)).unwrap_or(*a);
+            (name.clone(), value)
+        }).collect();
+
+        Keyframe {
+            frame: self.frame,
+            weights,
+            color: std::array::from_fn(|i| self.color[i] + (next.color[i] - self.color[i]) * t),
+            params,
+            easing: self.easing,
+        }
+    }
+}
+
+/// Keyframed brush inputs and the export of the resulting frames
+pub struct Animation {
+    /// Sorted by frame, at most one per frame
+    pub keyframes: Vec<Keyframe>,
+    pub length: u32,
+    /// Frame shown while previewing and where new keyframes go
+    pub current: u32,
+    /// Draw the current frame of the animation instead of the ui state
+    pub preview: bool,
+    pub format: timelapse::Format,
+    pub fps: u16,
+    pub path: String,
+    /// Output and the frame being drawn while rendering
+    rendering: Option<(Timelapse, u32)>,
+    finished: Option<Session>,
+}
+
+impl Animation {
+    pub fn new() -> Self {
+        Self {
+            keyframes: vec![],
+            length: 60,
+            current: 0,
+            preview: false,
+            format: timelapse::Format::PngSequence,
+            fps: 24,
+            path: "animation".to_owned(),
+            rendering: None,
+            finished: None,
+        }
+    }
+
+    pub fn set_keyframes(&mut self, mut keyframes: Vec<Keyframe>) {
+        keyframes.sort_by_key(|k| k.frame);
+        keyframes.dedup_by_key(|k| k.frame);
+        self.length = self.length.max(keyframes.last().map(|k| k.frame + 1).unwrap_or(0));
+        self.keyframes = keyframes;
+    }
+
+    /// Replaces the keyframe at `keyframe.frame`, if any
+    pub fn insert(&mut self, keyframe: Keyframe) {
+        match self.keyframes.binary_search_by_key(&keyframe.frame, |k| k.frame) {
+            Ok(i) => self.keyframes[i] = keyframe,
+            Err(i) => self.keyframes.insert(i, keyframe),
+        }
+        self.length = self.length.max(self.keyframes.last().unwrap().frame + 1);
+    }
+
+    pub fn remove(&mut self, frame: u32) {
+        self.keyframes.retain(|k| k.frame != frame);
+    }
+
+    /// Brush inputs at `frame`, held before the first and after the last keyframe
+    pub fn sample(&self, frame: u32) -> Option<Keyframe> {
+        let next = self.keyframes.partition_point(|k| k.frame <= frame);
+        let previous = next.checked_sub(1).and_then(|i| self.keyframes.get(i));
+        match (previous, self.keyframes.get(next)) {
+            (Some(a), Some(b)) => {
+                let t = (frame - a.frame) as f32 / (b.frame - a.frame) as f32;
+                Some(a.lerp(b, a.easing.apply(t)))
+            }
+            (Some(a), None) => Some(a.clone()),
+            (None, _) => self.keyframes.first().cloned(),
+        }
+    }
+
+    pub fn is_rendering(&self) -> bool {
+        self.rendering.is_some()
+    }
+
+    /// Frame the brush should draw this frame, if the animation drives it
+    pub fn active_frame(&self) -> Option<u32> {
+        match &self.rendering {
+            Some((_, frame)) => Some(*frame),
+            None => self.preview.then_some(self.current),
+        }
+    }
+
+    pub fn start_render(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut output = Timelapse::new();
+        output.trigger = Trigger::Frames;
+        output.every = 1;
+        output.format = self.format;
+        output.fps = self.fps;
+        output.path = self.path.clone();
+        output.start()?;
+        self.rendering = Some((output, 0));
+        Ok(())
+    }
+
+    pub fn stop_render(&mut self) {
+        if let Some((mut output, _)) = self.rendering.take() {
+            output.stop();
+            self.finished = output.take_finished();
+        }
+    }
+
+    /// Called once the active frame has been drawn, returns where it goes while rendering
+    pub fn capture(&mut self) -> Option<Capture> {
+        let (output, frame) = self.rendering.as_mut()?;
+        let path = output.capture(false);
+        *frame += 1;
+        if *frame >= self.length {
+            self.stop_render();
+        }
+        path
+    }
+
+    /// Render that completed since the last call
+    pub fn take_finished(&mut self) -> Option<Session> {
+        self.finished.take()
+    }
+
+    /// `current` is the state a new keyframe at the current frame takes
+    pub fn ui(&mut self, ui: &mut egui::Ui, current: Keyframe) {
+        ui.add(Slider::new(&mut self.length, 1..=2000).text("animation frames"));
+        ui.add(Slider::new(&mut self.current, 0..=self.length - 1).text("frame"));
+        self.current = self.current.min(self.length - 1);
+        ui.checkbox(&mut self.preview, "preview animation");
+
+        let at_current = self.keyframes.iter().position(|k| k.frame == self.current);
+        ui.horizontal(|ui| {
+            if ui.button("set keyframe").clicked() {
+                let easing = at_current.map(|i| self.keyframes[i].easing).unwrap_or_default();
+                self.insert(Keyframe { frame: self.current, easing, ..current });
+            }
+            if ui.add_enabled(at_current.is_some(), Button::new("delete keyframe")).clicked() {
+                self.remove(self.current);
+            }
+        });
+        if let Some(i) = at_current {
+            let keyframe = &mut self.keyframes[i];
+            ComboBox::from_label("easing")
+                .selected_text(keyframe.easing.name())
+                .show_ui(ui, |ui| {
+                    for easing in Easing::ALL {
+                        ui.selectable_value(&mut keyframe.easing, easing, easing.name());
+                    }
+                });
+        }
+
+        // Jump to keyframes
+        ui.horizontal_wrapped(|ui| {
+            for keyframe in &self.keyframes {
+                if ui.add(Button::new(format!("{}", keyframe.frame)).selected(keyframe.frame == self.current)).clicked() {
+                    self.current = keyframe.frame;
+                }
+            }
+        });
+
+        ui.add_enabled_ui(!self.is_rendering(), |ui| {
+            ComboBox::from_label("animation format")
+                .selected_text(self.format.name())
+                .show_ui(ui, |ui| {
+                    for format in timelapse::Format::ALL {
+                        ui.selectable_value(&mut self.format, format, format.name());
+                    }
+                });
+            if self.format != timelapse::Format::PngSequence {
+                ui.add(Slider::new(&mut self.fps, 1..=60).text("animation fps"));
+            }
+            ui.text_edit_singleline(&mut self.path);
+        });
+
+        match &self.rendering {
+            Some((_, frame)) => {
+                ui.label(format!("rendering {}/{}", frame, self.length));
+                if ui.button("cancel").clicked() {
+                    self.stop_render();
+                }
+            }
+            None => {
+                if ui.add_enabled(!self.keyframes.is_empty(), Button::new("render animation")).clicked() {
+                    if let Err(e) = self.start_render() {
+                        println!("Failed to start rendering to {}: {}", self.path, e);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use ash::vk::{BufferUsageFlags, DescriptorSetLayoutBinding, DescriptorType, DeviceSize, PushConstantRange, ShaderStageFlags};
+use bytemuck::{Pod, Zeroable};
+use cen::graphics::pipeline_store::{PipelineConfig, PipelineKey};
+use cen::graphics::Renderer;
+use cen::vulkan::{Buffer, CommandBuffer, DescriptorSetLayout};
+use egui::Vec2;
+use gpu_allocator::MemoryLocation;
+use serde::{Deserialize, Serialize};
+use crate::gradient::RAMP_SIZE;
+use crate::params;
+use crate::params::ParamDecl;
+use crate::stroke::{Dab, MAX_DABS};
+use crate::weights::{GpuWeight, MAX_WEIGHTS};
+
+/// Shared header every brush source gets appended to
+const BRUSH_HEADER: &str = "shaders/brush.comp";
+const SCAN_INTERVAL: Duration = Duration::from_millis(500);
+/// Declares the blend mode a brush starts out with, `// @blend difference`
+const BLEND_MARKER: &str = "@blend";
+/// Binding of the uniform buffer holding the declared brush parameters
+pub const PARAMS_BINDING: u32 = 3;
+pub const MAX_PARAMS_SIZE: usize = 1024;
+/// Binding of the storage buffer holding the dabs of the current stroke
+pub const DABS_BINDING: u32 = 4;
+/// Binding of the storage buffer holding the weight handles
+pub const WEIGHTS_BINDING: u32 = 5;
+/// Binding of the selection mask image
+pub const SELECTION_BINDING: u32 = 6;
+/// Binding of the storage buffer holding the colour ramp
+pub const GRADIENT_BINDING: u32 = 7;
+
+/// How the engine puts the colour a brush outputs onto the layer, weighted by the coverage
+/// it outputs. Matches the switch in shaders/brush.comp.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum BrushBlend {
+    #[default]
+    Normal,
+    Add,
+    Multiply,
+    Screen,
+    Overlay,
+    Difference,
+    SoftLight,
+    /// Removes coverage from the layer, the colour is ignored
+    Erase,
+}
+
+impl BrushBlend {
+    pub const ALL: [BrushBlend; 8] = [
+        BrushBlend::Normal, BrushBlend::Add, BrushBlend::Multiply, BrushBlend::Screen,
+        BrushBlend::Overlay, BrushBlend::Difference, BrushBlend::SoftLight, BrushBlend::Erase,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BrushBlend::Normal => "normal",
+            BrushBlend::Add => "add",
+            BrushBlend::Multiply => "multiply",
+            BrushBlend::Screen => "screen",
+            BrushBlend::Overlay => "overlay",
+            BrushBlend::Difference => "difference",
+            BrushBlend::SoftLight => "soft light",
+            BrushBlend::Erase => "erase",
+        }
+    }
+}
+
+/// Blend mode a brush declares, normal if it doesn't
+fn declared_blend(source: &str) -> Result<BrushBlend, String> {
+    for (i, line) in source.lines().enumerate() {
+        let Some(name) = line.trim_start().strip_prefix("//").and_then(|l| l.trim_start().strip_prefix(BLEND_MARKER)) else {
+            continue;
+        };
+        let name = name.trim();
+        return BrushBlend::ALL.into_iter()
+            .find(|b| b.name() == name)
+            .ok_or_else(|| format!("line {}: unknown blend mode `{}`", i + 1, name));
+    }
+    Ok(BrushBlend::Normal)
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+pub struct PushConstants {
+    pub color: [f32; 4],
+    pub background: [f32; 4],
+    pub cursor_a: Vec2,
+    pub cursor_b: Vec2,
+    pub weight_a: Vec2,
+    pub weight_b: Vec2,
+    pub tilt: Vec2,
+    pub pressure: f32,
+    pub dab_count: u32,
+    /// Top left of the dispatched region
+    pub offset: [i32; 2],
+    pub weight_count: u32,
+    /// `BrushBlend` the brush output is applied with
+    pub blend: u32,
+}
+
+/// Buffers a brush dispatch reads its parameters, dabs, weights and colour ramp from. Every
+/// frame in flight needs its own set, the cpu writes them while earlier frames still run.
+pub struct BrushBuffers {
+    pub params: Buffer,
+    pub dabs: Buffer,
+    pub weights: Buffer,
+    pub gradient: Buffer,
+}
+
+impl BrushBuffers {
+    pub fn new(renderer: &mut Renderer) -> Self {
+        let mut buffer = |size: usize, usage: BufferUsageFlags| Buffer::new(
+            &renderer.device,
+            &mut renderer.allocator,
+            MemoryLocation::CpuToGpu,
+            size as DeviceSize,
+            usage
+        );
+        Self {
+            params: buffer(MAX_PARAMS_SIZE, BufferUsageFlags::UNIFORM_BUFFER),
+            dabs: buffer(MAX_DABS * size_of::<Dab>(), BufferUsageFlags::STORAGE_BUFFER),
+            weights: buffer(MAX_WEIGHTS * size_of::<GpuWeight>(), BufferUsageFlags::STORAGE_BUFFER),
+            gradient: buffer(RAMP_SIZE * size_of::<[f32; 4]>(), BufferUsageFlags::STORAGE_BUFFER),
+        }
+    }
+}
+
+pub struct Brush {
+    pub name: String,
+    pub pipeline: PipelineKey,
+    pub params: Vec<ParamDecl>,
+    /// Blend mode the brush starts out with
+    pub blend: BrushBlend,
+    source: PathBuf,
+    modified: SystemTime,
+}
+
+/// Compute brushes loaded from a directory of `.comp` files, reloaded when a file changes
+pub struct BrushLibrary {
+    dir: PathBuf,
+    /// Per process, so running instances don't overwrite each other's shaders
+    generated_dir: PathBuf,
+    brushes: Vec<Brush>,
+    header_modified: SystemTime,
+    last_scan: Option<Instant>,
+    /// Pipelines of reloaded and deleted brushes, kept until the frames that use them have finished
+    retired: Vec<PipelineKey>,
+    /// Pipelines no frame uses anymore, removed from the pipeline store on the next update
+    freed: Arc<Mutex<Vec<PipelineKey>>>,
+}
+
+impl BrushLibrary {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            generated_dir: std::env::temp_dir().join("imlove").join(format!("brushes-{}", std::process::id())),
+            brushes: vec![],
+            header_modified: SystemTime::UNIX_EPOCH,
+            last_scan: None,
+            retired: vec![],
+            freed: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.brushes.iter().map(|b| b.name.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Brush> {
+        self.brushes.iter().find(|b| b.name == name)
+    }
+
+    /// Picks up new, changed and deleted brush files. Cheap to call every frame.
+    pub fn update(&mut self, renderer: &mut Renderer) {
+        for pipeline in self.freed.lock().unwrap().drain(..) {
+            renderer.pipeline_store().remove(pipeline);
+        }
+
+        if self.last_scan.is_some_and(|t| t.elapsed() < SCAN_INTERVAL) {
+            return;
+        }
+        self.last_scan = Some(Instant::now());
+
+        // A changed header affects every brush
+        let header_modified = modified(Path::new(BRUSH_HEADER));
+        let rebuild_all = header_modified != self.header_modified;
+        self.header_modified = header_modified;
+
+        let mut sources: Vec<PathBuf> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|e| e == "comp"))
+                .collect(),
+            Err(e) => {
+                println!("Couldn't read brush directory {}: {}", self.dir.display(), e);
+                vec![]
+            }
+        };
+        sources.sort();
+
+        let (kept, deleted): (Vec<Brush>, Vec<Brush>) = std::mem::take(&mut self.brushes).into_iter().partition(|b| sources.contains(&b.source));
+        self.brushes = kept;
+        self.retired.extend(deleted.into_iter().map(|b| b.pipeline));
+
+        for source in sources {
+            let name = source.file_stem().unwrap().to_string_lossy().into_owned();
+            let modified = modified(&source);
+
+            match self.brushes.iter().position(|b| b.source == source) {
+                Some(i) if rebuild_all || self.brushes[i].modified != modified => {
+                    // Keep the old pipeline around if the new version doesn't compile
+                    self.brushes[i].modified = modified;
+                    if let Some((pipeline, params, blend)) = self.build(renderer, &name, &source) {
+                        self.retired.push(std::mem::replace(&mut self.brushes[i].pipeline, pipeline));
+                        self.brushes[i].params = params;
+                        self.brushes[i].blend = blend;
+                        println!("Reloaded brush {}", name);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    if let Some((pipeline, params, blend)) = self.build(renderer, &name, &source) {
+                        self.brushes.push(Brush { name, pipeline, params, blend, source, modified });
+                    }
+                }
+            }
+        }
+
+        self.brushes.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Removes the pipelines of reloaded and deleted brushes from the pipeline store once
+    /// `command_buffer` has finished, earlier frames may still use them
+    pub fn release(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer) {
+        if self.retired.is_empty() {
+            return;
+        }
+        let retired = std::mem::take(&mut self.retired);
+        let freed = self.freed.clone();
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            freed.lock().unwrap().extend(retired);
+        }));
+    }
+
+    /// Combines the header, the parameter block and the brush source and creates a pipeline for it
+    fn build(&self, renderer: &mut Renderer, name: &str, source: &Path) -> Option<(PipelineKey, Vec<ParamDecl>, BrushBlend)> {
+        let (header, brush) = match fs::read_to_string(BRUSH_HEADER).and_then(|header| Ok((header, fs::read_to_string(source)?))) {
+            Ok(sources) => sources,
+            Err(e) => {
+                println!("Couldn't read brush {}: {}", name, e);
+                return None;
+            }
+        };
+
+        let params = match params::parse(&brush) {
+            Ok(params) => params,
+            Err(e) => {
+                println!("Invalid parameters in brush {}: {}", name, e);
+                return None;
+            }
+        };
+        let blend = match declared_blend(&brush) {
+            Ok(blend) => blend,
+            Err(e) => {
+                println!("Invalid blend mode in brush {}: {}", name, e);
+                return None;
+            }
+        };
+
+        let shader = format!("{}\n{}\n#line 1\n{}", header, params::glsl_block(&params, PARAMS_BINDING), brush);
+        let generated = self.generated_dir.join(format!("{}.comp", name));
+        if let Err(e) = fs::create_dir_all(&self.generated_dir).and_then(|_| fs::write(&generated, shader)) {
+            println!("Couldn't prepare brush {}: {}", name, e);
+            return None;
+        }
+
+        let bindings = [
+            DescriptorSetLayoutBinding::default()
+                .binding(0)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_IMAGE)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(1)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_IMAGE)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(2)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_IMAGE)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(PARAMS_BINDING)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::UNIFORM_BUFFER)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(DABS_BINDING)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_BUFFER)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(WEIGHTS_BINDING)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_BUFFER)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(SELECTION_BINDING)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_IMAGE)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(GRADIENT_BINDING)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_BUFFER)
+                .stage_flags(ShaderStageFlags::COMPUTE)
+        ];
+
+        let layout = vec![DescriptorSetLayout::new_push_descriptor(
+            &renderer.device,
+            &bindings
+        )];
+
+        let push_constants = vec![PushConstantRange::default()
+            .size(size_of::<PushConstants>() as u32)
+            .stage_flags(ShaderStageFlags::COMPUTE)
+            .offset(0)
+        ];
+
+        let macros: HashMap<String, String> = HashMap::new();
+        match renderer.pipeline_store().insert(PipelineConfig {
+            shader_path: generated,
+            descriptor_set_layouts: layout,
+            push_constant_ranges: push_constants,
+            macros,
+        }) {
+            Ok(pipeline) => Some((pipeline, params, blend)),
+            Err(e) => {
+                println!("Failed to build brush {}: {:?}", name, e);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for BrushLibrary {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.generated_dir);
+    }
+}
+
+fn modified(path: &Path) -> SystemTime {
+    fs::metadata(path).and_then(|m| m.modified()).unwrap_or(SystemTime::UNIX_EPOCH)
+}
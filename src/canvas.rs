@@ -0,0 +1,330 @@
+use std::sync::{Arc, Mutex};
+use egui::{Button, ComboBox, DragValue, Slider};
+use image::imageops;
+use image::imageops::FilterType;
+use image::{Rgba, RgbaImage};
+use crate::color::ColorSpace;
+
+/// Original and layers after a canvas operation, filled in once they have been read back
+pub type CanvasResult = Arc<Mutex<Option<(RgbaImage, Vec<(u64, RgbaImage)>)>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+    Lanczos,
+}
+
+impl Filter {
+    pub const ALL: [Filter; 3] = [Filter::Nearest, Filter::Bilinear, Filter::Lanczos];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::Nearest => "nearest",
+            Filter::Bilinear => "bilinear",
+            Filter::Lanczos => "lanczos",
+        }
+    }
+
+    fn filter_type(&self) -> FilterType {
+        match self {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Bilinear => FilterType::Triangle,
+            Filter::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CanvasOp {
+    Resample { width: u32, height: u32, filter: Filter },
+    /// x, y, width, height
+    Crop([u32; 4]),
+    /// Clockwise quarter turns
+    RotateQuarter(u32),
+    /// Clockwise rotation in degrees, the canvas grows to fit the corners
+    Rotate(f32),
+    FlipHorizontal,
+    FlipVertical,
+    /// New size with the old canvas placed at `anchor`, 0 to 1 from the top left
+    CanvasSize { width: u32, height: u32, anchor: [f32; 2] },
+}
+
+impl CanvasOp {
+    /// Applies the operation to one layer, new pixels get `fill`
+    pub fn apply(&self, image: &RgbaImage, fill: Rgba<u8>) -> RgbaImage {
+        match *self {
+            CanvasOp::Resample { width, height, filter } => imageops::resize(image, width.max(1), height.max(1), filter.filter_type()),
+            CanvasOp::Crop([x, y, width, height]) => imageops::crop_imm(image, x, y, width.max(1), height.max(1)).to_image(),
+            CanvasOp::RotateQuarter(turns) => match turns % 4 {
+                1 => imageops::rotate90(image),
+                2 => imageops::rotate180(image),
+                3 => imageops::rotate270(image),
+                _ => image.clone(),
+            },
+            CanvasOp::Rotate(degrees) => rotate(image, degrees, fill),
+            CanvasOp::FlipHorizontal => imageops::flip_horizontal(image),
+            CanvasOp::FlipVertical => imageops::flip_vertical(image),
+            CanvasOp::CanvasSize { width, height, anchor } => {
+                let mut canvas = RgbaImage::from_pixel(width.max(1), height.max(1), fill);
+                let x = ((width as f32 - image.width() as f32) * anchor[0]).round() as i64;
+                let y = ((height as f32 - image.height() as f32) * anchor[1]).round() as i64;
+                imageops::replace(&mut canvas, image, x, y);
+                canvas
+            }
+        }
+    }
+}
+
+/// Rotates around the centre with bilinear sampling into a canvas that fits the rotated corners
+fn rotate(image: &RgbaImage, degrees: f32, fill: Rgba<u8>) -> RgbaImage {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    let width = (w * cos.abs() + h * sin.abs()).round().max(1.0) as u32;
+    let height = (w * sin.abs() + h * cos.abs()).round().max(1.0) as u32;
+
+    let sample = |x: i64, y: i64| {
+        if x < 0 || y < 0 || x >= image.width() as i64 || y >= image.height() as i64 {
+            fill.0.map(|c| c as f32)
+        } else {
+            image.get_pixel(x as u32, y as u32).0.map(|c| c as f32)
+        }
+    };
+
+    RgbaImage::from_fn(width, height, |x, y| {
+        // Back from the destination pixel centre into the source
+        let dx = x as f32 + 0.5 - width as f32 / 2.0;
+        let dy = y as f32 + 0.5 - height as f32 / 2.0;
+        let sx = cos * dx + sin * dy + w / 2.0 - 0.5;
+        let sy = -sin * dx + cos * dy + h / 2.0 - 0.5;
+
+        let (x0, y0) = (sx.floor() as i64, sy.floor() as i64);
+        let (fx, fy) = (sx - sx.floor(), sy - sy.floor());
+        let [a, b, c, d] = [sample(x0, y0), sample(x0 + 1, y0), sample(x0, y0 + 1), sample(x0 + 1, y0 + 1)];
+        Rgba(std::array::from_fn(|i| {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            (top + (bottom - top) * fy).round() as u8
+        }))
+    })
+}
+
+/// Settings for the canvas operations and the one requested from the ui
+pub struct Canvas {
+    pub request: Option<CanvasOp>,
+    pub result: CanvasResult,
+    filter: Filter,
+    width: u32,
+    height: u32,
+    keep_aspect: bool,
+    degrees: f32,
+    anchor: [f32; 2],
+    /// sRGB colour the background gets where the canvas grows, other layers stay transparent
+    fill: [f32; 4],
+}
+
+impl Canvas {
+    pub fn new() -> Self {
+        Self {
+            request: None,
+            result: Arc::new(Mutex::new(None)),
+            filter: Filter::Lanczos,
+            width: 0,
+            height: 0,
+            keep_aspect: true,
+            degrees: 0.0,
+            anchor: [0.5, 0.5],
+            fill: [1.0, 1.0, 1.0, 1.0],
+        }
+    }
+
+    /// Fill for a layer, the background is filled and the layers above stay transparent
+    pub fn fill_for(&self, background: bool) -> Rgba<u8> {
+        if background {
+            let [r, g, b] = ColorSpace::Srgb.to_linear([self.fill[0], self.fill[1], self.fill[2]]);
+            Rgba([r, g, b, self.fill[3]].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))
+        } else {
+            Rgba([0; 4])
+        }
+    }
+
+    /// `size` is the current canvas size, `selection` finds the bounds of the selection if there is one
+    pub fn ui(&mut self, ui: &mut egui::Ui, size: [u32; 2], selection: impl FnOnce() -> Option<[u32; 4]>) {
+        if self.width == 0 || self.height == 0 {
+            [self.width, self.height] = size;
+        }
+
+        ui.horizontal(|ui| {
+            let aspect = size[1] as f32 / size[0] as f32;
+            let width = ui.add(DragValue::new(&mut self.width).range(1..=16384).prefix("w "));
+            let height = ui.add(DragValue::new(&mut self.height).range(1..=16384).prefix("h "));
+            ui.checkbox(&mut self.keep_aspect, "keep aspect");
+            if self.keep_aspect && width.changed() {
+                self.height = (self.width as f32 * aspect).round().max(1.0) as u32;
+            }
+            if self.keep_aspect && height.changed() {
+                self.width = (self.height as f32 / aspect).round().max(1.0) as u32;
+            }
+        });
+        ComboBox::from_label("resample filter")
+            .selected_text(self.filter.name())
+            .show_ui(ui, |ui| {
+                for filter in Filter::ALL {
+                    ui.selectable_value(&mut self.filter, filter, filter.name());
+                }
+            });
+        ui.horizontal(|ui| {
+            if ui.button("resample").clicked() {
+                self.request = Some(CanvasOp::Resample { width: self.width, height: self.height, filter: self.filter });
+            }
+            if ui.button("canvas size").clicked() {
+                self.request = Some(CanvasOp::CanvasSize { width: self.width, height: self.height, anchor: self.anchor });
+            }
+        });
+
+        // Where the old canvas ends up when the size changes
+        ui.horizontal(|ui| {
+            ui.label("anchor");
+            egui::Grid::new("canvas anchor").spacing([2.0, 2.0]).show(ui, |ui| {
+                for y in [0.0, 0.5, 1.0] {
+                    for x in [0.0, 0.5, 1.0] {
+                        if ui.add(Button::new("").min_size(egui::vec2(14.0, 14.0)).selected(self.anchor == [x, y])).clicked() {
+                            self.anchor = [x, y];
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+            ui.color_edit_button_rgba_unmultiplied(&mut self.fill).on_hover_text("background fill");
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("crop to selection").clicked() {
+                self.request = selection().map(CanvasOp::Crop);
+            }
+            if ui.button("flip h").clicked() {
+                self.request = Some(CanvasOp::FlipHorizontal);
+            }
+            if ui.button("flip v").clicked() {
+                self.request = Some(CanvasOp::FlipVertical);
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("⟲ 90").clicked() {
+                self.request = Some(CanvasOp::RotateQuarter(3));
+            }
+            if ui.button("180").clicked() {
+                self.request = Some(CanvasOp::RotateQuarter(2));
+            }
+            if ui.button("⟳ 90").clicked() {
+                self.request = Some(CanvasOp::RotateQuarter(1));
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.add(Slider::new(&mut self.degrees, -180.0..=180.0).suffix("°"));
+            if ui.button("rotate").clicked() {
+                self.request = Some(CanvasOp::Rotate(self.degrees));
+            }
+        });
+    }
+
+    /// Canvas sizes in the ui follow the canvas after an operation
+    pub fn reset_size(&mut self) {
+        self.width = 0;
+        self.height = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILL: Rgba<u8> = Rgba([9, 9, 9, 9]);
+
+    /// Every pixel holds its own coordinates
+    fn numbered(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 0, 255]))
+    }
+
+    fn at(image: &RgbaImage, x: u32, y: u32) -> [u8; 2] {
+        let [r, g, ..] = image.get_pixel(x, y).0;
+        [r, g]
+    }
+
+    #[test]
+    fn crop() {
+        let image = numbered(4, 3);
+        let cropped = CanvasOp::Crop([1, 1, 2, 2]).apply(&image, FILL);
+        assert_eq!(cropped.dimensions(), (2, 2));
+        assert_eq!([at(&cropped, 0, 0), at(&cropped, 1, 1)], [[1, 1], [2, 2]]);
+        assert_eq!(CanvasOp::Crop([0, 0, 0, 0]).apply(&image, FILL).dimensions(), (1, 1));
+    }
+
+    #[test]
+    fn quarter_turns_and_flips() {
+        let image = numbered(3, 2);
+        let turned = CanvasOp::RotateQuarter(1).apply(&image, FILL);
+        assert_eq!(turned.dimensions(), (2, 3));
+        // Clockwise, so the bottom left corner ends up in the top left
+        assert_eq!([at(&turned, 0, 0), at(&turned, 1, 0), at(&turned, 0, 2)], [[0, 1], [0, 0], [2, 1]]);
+        assert_eq!(CanvasOp::RotateQuarter(2).apply(&image, FILL), imageops::rotate180(&image));
+        assert_eq!(CanvasOp::RotateQuarter(7).apply(&image, FILL), imageops::rotate270(&image));
+        assert_eq!(CanvasOp::RotateQuarter(4).apply(&image, FILL), image);
+
+        let flipped = CanvasOp::FlipHorizontal.apply(&image, FILL);
+        assert_eq!([at(&flipped, 0, 0), at(&flipped, 2, 1)], [[2, 0], [0, 1]]);
+        let flipped = CanvasOp::FlipVertical.apply(&image, FILL);
+        assert_eq!([at(&flipped, 0, 0), at(&flipped, 2, 1)], [[0, 1], [2, 0]]);
+    }
+
+    #[test]
+    fn free_rotation() {
+        let image = numbered(3, 2);
+        assert_eq!(CanvasOp::Rotate(0.0).apply(&image, FILL), image);
+        assert_eq!(CanvasOp::Rotate(90.0).apply(&image, FILL), imageops::rotate90(&image));
+        assert_eq!(CanvasOp::Rotate(-90.0).apply(&image, FILL), imageops::rotate270(&image));
+
+        // Grows to fit the corners, which get the fill
+        let square = RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 255]));
+        let rotated = CanvasOp::Rotate(45.0).apply(&square, FILL);
+        assert_eq!(rotated.dimensions(), (14, 14));
+        assert_eq!([*rotated.get_pixel(0, 0), *rotated.get_pixel(13, 13), *rotated.get_pixel(7, 7)], [FILL, FILL, Rgba([255, 0, 0, 255])]);
+    }
+
+    #[test]
+    fn canvas_size_places_the_image_at_the_anchor() {
+        let image = numbered(3, 2);
+        let grown = |anchor| CanvasOp::CanvasSize { width: 5, height: 4, anchor }.apply(&image, FILL);
+
+        let top_left = grown([0.0, 0.0]);
+        assert_eq!(top_left.dimensions(), (5, 4));
+        assert_eq!([at(&top_left, 2, 1), at(&top_left, 3, 1)], [[2, 1], [9, 9]]);
+        let centre = grown([0.5, 0.5]);
+        assert_eq!([at(&centre, 1, 1), at(&centre, 0, 0), at(&centre, 3, 2)], [[0, 0], [9, 9], [2, 1]]);
+        let bottom_right = grown([1.0, 1.0]);
+        assert_eq!([at(&bottom_right, 2, 2), at(&bottom_right, 4, 3), at(&bottom_right, 1, 3)], [[0, 0], [2, 1], [9, 9]]);
+
+        // Shrinking cuts off the side away from the anchor
+        let shrunk = CanvasOp::CanvasSize { width: 2, height: 1, anchor: [1.0, 1.0] }.apply(&image, FILL);
+        assert_eq!([at(&shrunk, 0, 0), at(&shrunk, 1, 0)], [[1, 1], [2, 1]]);
+    }
+
+    #[test]
+    fn resample() {
+        let image = numbered(2, 2);
+        let scaled = CanvasOp::Resample { width: 4, height: 4, filter: Filter::Nearest }.apply(&image, FILL);
+        assert_eq!([at(&scaled, 1, 1), at(&scaled, 2, 1), at(&scaled, 3, 3)], [[0, 0], [1, 0], [1, 1]]);
+        let empty = CanvasOp::Resample { width: 0, height: 3, filter: Filter::Lanczos }.apply(&image, FILL);
+        assert_eq!(empty.dimensions(), (1, 3));
+    }
+
+    #[test]
+    fn only_the_background_is_filled() {
+        let mut canvas = Canvas::new();
+        assert_eq!(canvas.fill_for(true), Rgba([255; 4]));
+        assert_eq!(canvas.fill_for(false), Rgba([0; 4]));
+        // The fill is picked in sRGB and stored linear like the layers
+        canvas.fill = [0.5, 0.5, 0.5, 1.0];
+        assert_eq!(canvas.fill_for(true), Rgba([55, 55, 55, 255]));
+    }
+}
@@ -0,0 +1,874 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::tiff::TiffEncoder;
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageError, ImageFormat, ImageReader, ImageResult, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// Colour space of image files. On the gpu everything is linear with sRGB primaries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+    /// Linear with sRGB primaries, the working space itself
+    Linear,
+}
+
+/// Linear Display P3 to linear sRGB, both D65
+const P3_TO_SRGB: [[f32; 3]; 3] = [
+    [1.2249401, -0.2249404, 0.0],
+    [-0.0420569, 1.0420571, 0.0],
+    [-0.0196376, -0.0786361, 1.0982735],
+];
+
+const SRGB_TO_P3: [[f32; 3]; 3] = [
+    [0.8224621, 0.1775380, 0.0],
+    [0.0331941, 0.9668058, 0.0],
+    [0.0170827, 0.0723974, 0.9105199],
+];
+
+impl ColorSpace {
+    pub const ALL: [ColorSpace; 3] = [ColorSpace::Srgb, ColorSpace::DisplayP3, ColorSpace::Linear];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ColorSpace::Srgb => "sRGB",
+            ColorSpace::DisplayP3 => "Display P3",
+            ColorSpace::Linear => "linear sRGB",
+        }
+    }
+
+    /// Converts an encoded colour in this space to linear sRGB
+    pub fn to_linear(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb => rgb.map(srgb_to_linear),
+            ColorSpace::DisplayP3 => mul(&P3_TO_SRGB, rgb.map(srgb_to_linear)),
+            ColorSpace::Linear => rgb,
+        }
+    }
+
+    /// Converts a linear sRGB colour to an encoded colour in this space
+    pub fn from_linear(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb => rgb.map(linear_to_srgb),
+            ColorSpace::DisplayP3 => mul(&SRGB_TO_P3, rgb).map(linear_to_srgb),
+            ColorSpace::Linear => rgb,
+        }
+    }
+
+    /// Recognises matrix/trc profiles by their red primary and the tone curve, gray profiles by the
+    /// curve alone. Profiles without those, like lut based ones, by their description.
+    pub fn from_icc(profile: &[u8]) -> Option<ColorSpace> {
+        let samples = [0.25, 0.5, 0.75];
+        if let Some(curve) = samples.iter().map(|x| icc::tone(profile, *x)).collect::<Option<Vec<_>>>() {
+            let follows = |decode: fn(f32) -> f32| curve.iter().zip(samples).all(|(y, x)| (y - decode(x)).abs() < 0.01);
+            let primaries = match icc::xyz_tag(profile, b"rXYZ") {
+                Some(red) => [ColorSpace::Srgb, ColorSpace::DisplayP3].into_iter()
+                    .find(|space| icc::primaries(*space)[0].iter().zip(red).all(|(a, b)| (a - b).abs() < 0.01))?,
+                // Gray, the same on every primary
+                None => ColorSpace::Srgb,
+            };
+            // Close enough to sRGB that a plain 2.2 gamma counts as well
+            return match primaries {
+                ColorSpace::Srgb if follows(|x| x) => Some(ColorSpace::Linear),
+                _ if follows(srgb_to_linear) => Some(primaries),
+                _ => None,
+            };
+        }
+
+        let description = icc::description(profile)?.to_lowercase();
+        if description.contains("p3") {
+            Some(ColorSpace::DisplayP3)
+        } else if description.contains("linear") {
+            Some(ColorSpace::Linear)
+        } else if description.contains("srgb") {
+            Some(ColorSpace::Srgb)
+        } else {
+            None
+        }
+    }
+
+    /// Icc v4 profile describing this space
+    pub fn icc_profile(&self) -> Vec<u8> {
+        icc::profile(*self)
+    }
+}
+
+/// Exact piecewise sRGB decoding
+pub fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Oklab lightness, a and b to linear sRGB
+pub fn oklab_to_linear(lab: [f32; 3]) -> [f32; 3] {
+    let [l, a, b] = lab;
+    let lms = [
+        l + 0.3963377774 * a + 0.2158037573 * b,
+        l - 0.1055613458 * a - 0.0638541728 * b,
+        l - 0.0894841775 * a - 1.2914855480 * b,
+    ].map(|v| v * v * v);
+    mul(&[
+        [4.0767416621, -3.3077115913, 0.2309699292],
+        [-1.2684380046, 2.6097574011, -0.3413193965],
+        [-0.0041960863, -0.7034186147, 1.7076147010],
+    ], lms)
+}
+
+/// Linear sRGB to Oklab lightness, a and b
+pub fn linear_to_oklab(rgb: [f32; 3]) -> [f32; 3] {
+    let lms = mul(&[
+        [0.4122214708, 0.5363325363, 0.0514459929],
+        [0.2119034982, 0.6806995451, 0.1073969566],
+        [0.0883024619, 0.2817188376, 0.6299787005],
+    ], rgb).map(f32::cbrt);
+    mul(&[
+        [0.2104542553, 0.7936177850, -0.0040720468],
+        [1.9779984951, -2.4285922050, 0.4505937099],
+        [0.0259040371, 0.7827717662, -0.8086757660],
+    ], lms)
+}
+
+/// Okhsl hue, saturation and lightness, all in [0, 1], to linear sRGB
+pub fn okhsl_to_linear(hsl: [f32; 3]) -> [f32; 3] {
+    let [h, s, l] = hsl;
+    if l >= 1.0 {
+        return [1.0; 3];
+    }
+    if l <= 0.0 {
+        return [0.0; 3];
+    }
+
+    let (b_, a_) = (std::f32::consts::TAU * h).sin_cos();
+    let lightness = toe_inv(l);
+    let [c_0, c_mid, c_max] = chroma_steps(lightness, a_, b_);
+
+    let (mid, mid_inv) = (0.8, 1.25);
+    let c = if s < mid {
+        let t = mid_inv * s;
+        let k_1 = mid * c_0;
+        let k_2 = 1.0 - k_1 / c_mid;
+        t * k_1 / (1.0 - k_2 * t)
+    } else {
+        let t = (s - mid) / (1.0 - mid);
+        let k_1 = (1.0 - mid) * c_mid * c_mid * mid_inv * mid_inv / c_0;
+        let k_2 = 1.0 - k_1 / (c_max - c_mid);
+        c_mid + t * k_1 / (1.0 - k_2 * t)
+    };
+    oklab_to_linear([lightness, c * a_, c * b_])
+}
+
+/// Linear sRGB to Okhsl hue, saturation and lightness
+pub fn linear_to_okhsl(rgb: [f32; 3]) -> [f32; 3] {
+    let [lightness, a, b] = linear_to_oklab(rgb);
+    let c = (a * a + b * b).sqrt();
+    if c < 1e-6 || lightness <= 0.0 || lightness >= 1.0 {
+        return [0.0, 0.0, toe(lightness).clamp(0.0, 1.0)];
+    }
+    let (a_, b_) = (a / c, b / c);
+    let h = 0.5 + 0.5 * (-b).atan2(-a) / std::f32::consts::PI;
+    let [c_0, c_mid, c_max] = chroma_steps(lightness, a_, b_);
+
+    let (mid, mid_inv) = (0.8, 1.25);
+    let s = if c < c_mid {
+        let k_1 = mid * c_0;
+        let k_2 = 1.0 - k_1 / c_mid;
+        let t = c / (k_1 + k_2 * c);
+        t * mid
+    } else {
+        let k_1 = (1.0 - mid) * c_mid * c_mid * mid_inv * mid_inv / c_0;
+        let k_2 = 1.0 - k_1 / (c_max - c_mid);
+        let t = (c - c_mid) / (k_1 + k_2 * (c - c_mid));
+        mid + (1.0 - mid) * t
+    };
+    [h, s, toe(lightness)]
+}
+
+/// Okhsv hue, saturation and value, all in [0, 1], to linear sRGB
+pub fn okhsv_to_linear(hsv: [f32; 3]) -> [f32; 3] {
+    let [h, s, v] = hsv;
+    if v <= 0.0 {
+        return [0.0; 3];
+    }
+    let (b_, a_) = (std::f32::consts::TAU * h).sin_cos();
+    let [s_max, t_max] = cusp_st(a_, b_);
+
+    let s_0 = 0.5;
+    let k = 1.0 - s_0 / s_max;
+    let l_v = 1.0 - s * s_0 / (s_0 + t_max - t_max * k * s);
+    let c_v = s * t_max * s_0 / (s_0 + t_max - t_max * k * s);
+
+    let mut lightness = v * l_v;
+    let mut c = v * c_v;
+
+    // Compensate for the toe and the curved top of the gamut
+    let l_vt = toe_inv(l_v);
+    let c_vt = c_v * l_vt / l_v;
+    let l_new = toe_inv(lightness);
+    c *= l_new / lightness;
+    lightness = l_new;
+
+    let [r, g, b] = oklab_to_linear([l_vt, a_ * c_vt, b_ * c_vt]);
+    let scale = (1.0 / r.max(g).max(b).max(0.0)).cbrt();
+    oklab_to_linear([lightness * scale, c * scale * a_, c * scale * b_])
+}
+
+/// Linear sRGB to Okhsv hue, saturation and value
+pub fn linear_to_okhsv(rgb: [f32; 3]) -> [f32; 3] {
+    let [mut lightness, a, b] = linear_to_oklab(rgb);
+    let c = (a * a + b * b).sqrt();
+    if c < 1e-6 || lightness <= 0.0 {
+        return [0.0, 0.0, toe(lightness).clamp(0.0, 1.0)];
+    }
+    let (a_, b_) = (a / c, b / c);
+    let h = 0.5 + 0.5 * (-b).atan2(-a) / std::f32::consts::PI;
+    let [s_max, t_max] = cusp_st(a_, b_);
+
+    let s_0 = 0.5;
+    let k = 1.0 - s_0 / s_max;
+
+    // Where the colour lies on the triangle towards the cusp
+    let t = t_max / (c + lightness * t_max);
+    let l_v = t * lightness;
+    let c_v = t * c;
+
+    let l_vt = toe_inv(l_v);
+    let c_vt = c_v * l_vt / l_v;
+    let [r, g, b] = oklab_to_linear([l_vt, a_ * c_vt, b_ * c_vt]);
+    let scale = (1.0 / r.max(g).max(b).max(0.0)).cbrt();
+
+    lightness = toe(lightness / scale);
+
+    let v = lightness / l_v;
+    let s = (s_0 + t_max) * c_v / (t_max * s_0 + t_max * k * c_v);
+    [h, s, v]
+}
+
+/// Whether a linear sRGB colour lies inside the gamut, with a little slack for rounding
+pub fn in_gamut(rgb: [f32; 3]) -> bool {
+    rgb.iter().all(|v| (-1e-4..=1.0 + 1e-4).contains(v))
+}
+
+/// Brings a linear sRGB colour into the gamut by reducing its Oklab chroma, keeping lightness and hue
+pub fn clip_to_gamut(rgb: [f32; 3]) -> [f32; 3] {
+    if in_gamut(rgb) {
+        return rgb.map(|v| v.clamp(0.0, 1.0));
+    }
+    let [l, a, b] = linear_to_oklab(rgb);
+    let l = l.clamp(0.0, 1.0);
+    let (mut low, mut high) = (0.0, 1.0);
+    for _ in 0..20 {
+        let t = (low + high) / 2.0;
+        if in_gamut(oklab_to_linear([l, a * t, b * t])) { low = t } else { high = t }
+    }
+    oklab_to_linear([l, a * low, b * low]).map(|v| v.clamp(0.0, 1.0))
+}
+
+/// Lightness estimate of Okhsl and Okhsv, closer to CIELAB lightness than Oklab's
+fn toe(x: f32) -> f32 {
+    let (k_1, k_2) = (0.206, 0.03);
+    let k_3 = (1.0 + k_1) / (1.0 + k_2);
+    0.5 * (k_3 * x - k_1 + ((k_3 * x - k_1) * (k_3 * x - k_1) + 4.0 * k_2 * k_3 * x).sqrt())
+}
+
+fn toe_inv(x: f32) -> f32 {
+    let (k_1, k_2) = (0.206, 0.03);
+    let k_3 = (1.0 + k_1) / (1.0 + k_2);
+    (x * x + k_1 * x) / (k_3 * (x + k_2))
+}
+
+/// Largest saturation C / L for the hue `a_`, `b_` (normalized) that still fits in sRGB,
+/// a polynomial guess refined with a Halley step
+fn max_saturation(a: f32, b: f32) -> f32 {
+    // Pure blue sits right where the red and green regions meet and rounding can drop it into
+    // the blue polynomial, so green gets a little slack and is tested first
+    let (k, w) = if 1.81444104 * a - 1.19445276 * b > 1.0 - 1e-4 {
+        ([0.73956515, -0.45954404, 0.08285427, 0.12541070, 0.14503204], [-1.2684380046, 2.6097574011, -0.3413193965])
+    } else if -1.88170328 * a - 0.80936493 * b > 1.0 {
+        ([1.19086277, 1.76576728, 0.59662641, 0.75515197, 0.56771245], [4.0767416621, -3.3077115913, 0.2309699292])
+    } else {
+        ([1.35733652, -0.00915799, -1.15130210, -0.50559606, 0.00692167], [-0.0041960863, -0.7034186147, 1.7076147010])
+    };
+    let mut s = k[0] + k[1] * a + k[2] * b + k[3] * a * a + k[4] * a * b;
+
+    let k_lms = [
+        0.3963377774 * a + 0.2158037573 * b,
+        -0.1055613458 * a - 0.0638541728 * b,
+        -0.0894841775 * a - 1.2914855480 * b,
+    ];
+    let lms_ = k_lms.map(|k| 1.0 + s * k);
+    let lms = lms_.map(|v| v * v * v);
+    let lms_ds = [0, 1, 2].map(|i| 3.0 * k_lms[i] * lms_[i] * lms_[i]);
+    let lms_ds2 = [0, 1, 2].map(|i| 6.0 * k_lms[i] * k_lms[i] * lms_[i]);
+    let dot = |v: [f32; 3]| w[0] * v[0] + w[1] * v[1] + w[2] * v[2];
+    let (f, f1, f2) = (dot(lms), dot(lms_ds), dot(lms_ds2));
+    s -= f * f1 / (f1 * f1 - 0.5 * f * f2);
+    s
+}
+
+/// Lightness and chroma of the most saturated colour of a hue
+fn find_cusp(a: f32, b: f32) -> [f32; 2] {
+    let s_cusp = max_saturation(a, b);
+    let [r, g, b] = oklab_to_linear([1.0, s_cusp * a, s_cusp * b]);
+    let l_cusp = (1.0 / r.max(g).max(b)).cbrt();
+    [l_cusp, l_cusp * s_cusp]
+}
+
+/// Cusp as the slopes of the lower and upper edge of the gamut triangle
+fn cusp_st(a: f32, b: f32) -> [f32; 2] {
+    let [l, c] = find_cusp(a, b);
+    [c / l, c / (1.0 - l)]
+}
+
+/// Where the line from (`l_0`, 0) to (`l_1`, `c_1`) leaves the gamut, as a fraction of its length
+fn gamut_intersection(a: f32, b: f32, l_1: f32, c_1: f32, l_0: f32, cusp: [f32; 2]) -> f32 {
+    let [cusp_l, cusp_c] = cusp;
+    if (l_1 - l_0) * cusp_c - (cusp_l - l_0) * c_1 <= 0.0 {
+        // Lower half, the triangle is exact
+        return cusp_c * l_0 / (c_1 * cusp_l + cusp_c * (l_0 - l_1));
+    }
+
+    // Upper half, start at the triangle and take a Halley step towards the curved edge
+    let t = cusp_c * (l_0 - 1.0) / (c_1 * (cusp_l - 1.0) + cusp_c * (l_0 - l_1));
+    let (dl, dc) = (l_1 - l_0, c_1);
+    let k_lms = [
+        0.3963377774 * a + 0.2158037573 * b,
+        -0.1055613458 * a - 0.0638541728 * b,
+        -0.0894841775 * a - 1.2914855480 * b,
+    ];
+    let lms_dt = k_lms.map(|k| dl + dc * k);
+
+    let l = l_0 * (1.0 - t) + t * l_1;
+    let c = t * c_1;
+    let lms_ = k_lms.map(|k| l + c * k);
+    let lms = lms_.map(|v| v * v * v);
+    let ldt = [0, 1, 2].map(|i| 3.0 * lms_dt[i] * lms_[i] * lms_[i]);
+    let ldt2 = [0, 1, 2].map(|i| 6.0 * lms_dt[i] * lms_dt[i] * lms_[i]);
+
+    let step = |w: [f32; 3]| {
+        let dot = |v: [f32; 3]| w[0] * v[0] + w[1] * v[1] + w[2] * v[2];
+        let (x, x1, x2) = (dot(lms) - 1.0, dot(ldt), dot(ldt2));
+        let u = x1 / (x1 * x1 - 0.5 * x * x2);
+        if u >= 0.0 { -x * u } else { f32::MAX }
+    };
+    t + step([4.0767416621, -3.3077115913, 0.2309699292])
+        .min(step([-1.2684380046, 2.6097574011, -0.3413193965]))
+        .min(step([-0.0041960863, -0.7034186147, 1.7076147010]))
+}
+
+/// Chroma at saturation 0.8 and 1 of Okhsl, plus the one it starts out from, for lightness `l`
+fn chroma_steps(l: f32, a: f32, b: f32) -> [f32; 3] {
+    let cusp = find_cusp(a, b);
+    let c_max = gamut_intersection(a, b, l, 1.0, l, cusp);
+    let [s_max, t_max] = [cusp[1] / cusp[0], cusp[1] / (1.0 - cusp[0])];
+    let k = c_max / (l * s_max).min((1.0 - l) * t_max);
+
+    // Polynomial fit of a smoother triangle for the middle saturation
+    let s_mid = 0.11516993 + 1.0 / (7.44778970 + 4.15901240 * b
+        + a * (-2.19557347 + 1.75198401 * b
+        + a * (-2.13704948 - 10.02301043 * b
+        + a * (-4.24894561 + 5.38770819 * b + 4.69891013 * a))));
+    let t_mid = 0.11239642 + 1.0 / (1.61320320 - 0.68124379 * b
+        + a * (0.40370612 + 0.90148123 * b
+        + a * (-0.27087943 + 0.61223990 * b
+        + a * (0.00299215 - 0.45399568 * b - 0.14661872 * a))));
+    let (c_a, c_b) = (l * s_mid, (1.0 - l) * t_mid);
+    let c_mid = 0.9 * k * (1.0 / (1.0 / c_a.powi(4) + 1.0 / c_b.powi(4))).sqrt().sqrt();
+
+    let (c_a, c_b) = (l * 0.4, (1.0 - l) * 0.8);
+    let c_0 = (1.0 / (1.0 / (c_a * c_a) + 1.0 / (c_b * c_b))).sqrt();
+    [c_0, c_mid, c_max]
+}
+
+/// Parses `oklch(l c h)`, with l in [0, 1] or a percentage and h in degrees, or `#rrggbb`,
+/// to linear sRGB
+pub fn parse(text: &str) -> Result<[f32; 3], String> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix('#') {
+        let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)
+            .ok_or_else(|| format!("expected `#rrggbb`, got `{}`", text))?;
+        return Ok(ColorSpace::Srgb.to_linear([16, 8, 0].map(|shift| ((value >> shift) & 0xff) as f32 / 255.0)));
+    }
+
+    let args = text.strip_prefix("oklch(").and_then(|t| t.strip_suffix(')'))
+        .ok_or_else(|| format!("expected `oklch(l c h)` or `#rrggbb`, got `{}`", text))?;
+    let values: Vec<f32> = args.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.strip_suffix('%') {
+            Some(percent) => percent.parse::<f32>().map(|p| p / 100.0),
+            None => v.trim_end_matches("deg").parse::<f32>(),
+        })
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("invalid number in `{}`", text))?;
+    let [l, c, h] = values.as_slice() else {
+        return Err(format!("expected three values in `{}`", text));
+    };
+
+    let h = h.to_radians();
+    Ok(oklab_to_linear([*l, c * h.cos(), c * h.sin()]))
+}
+
+fn mul(m: &[[f32; 3]; 3], v: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+fn to_u8(v: f32) -> u8 {
+    (v.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Converts every pixel with `f`, alpha is always linear and left alone
+fn convert(mut image: RgbaImage, f: impl Fn([f32; 3]) -> [f32; 3]) -> RgbaImage {
+    for pixel in image.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        let rgb = f([r, g, b].map(|v| v as f32 / 255.0)).map(to_u8);
+        pixel.0[..3].copy_from_slice(&rgb);
+    }
+    image
+}
+
+pub fn decode(image: RgbaImage, space: ColorSpace) -> RgbaImage {
+    if space == ColorSpace::Linear {
+        return image;
+    }
+    convert(image, |rgb| space.to_linear(rgb))
+}
+
+pub fn encode(image: RgbaImage, space: ColorSpace) -> RgbaImage {
+    if space == ColorSpace::Linear {
+        return image;
+    }
+    convert(image, |rgb| space.from_linear(rgb))
+}
+
+/// Reads an image and the colour space its embedded profile describes, images without a profile
+/// are taken as sRGB. `None` if the profile isn't recognised.
+pub fn load(path: &Path) -> ImageResult<(RgbaImage, Option<ColorSpace>)> {
+    let mut decoder = ImageReader::new(BufReader::new(File::open(path)?))
+        .with_guessed_format()?
+        .into_decoder()?;
+
+    let space = match decoder.icc_profile()? {
+        Some(profile) => ColorSpace::from_icc(&profile),
+        None => Some(ColorSpace::Srgb),
+    };
+
+    Ok((DynamicImage::from_decoder(decoder)?.to_rgba8(), space))
+}
+
+/// Writes an encoded image, with the profile of `space` embedded in the formats that support it
+pub fn save(path: &Path, format: ImageFormat, image: RgbaImage, space: ColorSpace) -> ImageResult<()> {
+    let image = DynamicImage::ImageRgba8(image);
+    let profile = space.icc_profile();
+    let file = BufWriter::new(File::create(path)?);
+
+    match format {
+        ImageFormat::Png => {
+            let mut encoder = PngEncoder::new(file);
+            encoder.set_icc_profile(profile).map_err(ImageError::Unsupported)?;
+            image.write_with_encoder(encoder)
+        }
+        ImageFormat::Tiff => {
+            let mut encoder = TiffEncoder::new(file);
+            encoder.set_icc_profile(profile).map_err(ImageError::Unsupported)?;
+            image.write_with_encoder(encoder)
+        }
+        // Jpeg has no alpha channel
+        ImageFormat::Jpeg => {
+            let mut encoder = JpegEncoder::new(file);
+            encoder.set_icc_profile(profile).map_err(ImageError::Unsupported)?;
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)
+        }
+        _ => {
+            drop(file);
+            image.save_with_format(path, format)
+        }
+    }
+}
+
+/// Just enough of the icc format to write matrix/trc display profiles and to identify them
+mod icc {
+    use super::ColorSpace;
+
+    /// D50, the profile connection space white
+    const D50: [f32; 3] = [0.9642, 1.0, 0.8249];
+
+    /// Bradford adaptation from D65 to D50
+    const D65_TO_D50: [[f32; 3]; 3] = [
+        [1.0478112, 0.0228866, -0.0501270],
+        [0.0295424, 0.9904844, -0.0170491],
+        [-0.0092345, 0.0150436, 0.7521316],
+    ];
+
+    /// Red, green and blue primaries adapted to D50
+    pub fn primaries(space: ColorSpace) -> [[f32; 3]; 3] {
+        match space {
+            ColorSpace::Srgb | ColorSpace::Linear => [
+                [0.4360747, 0.2225045, 0.0139322],
+                [0.3850649, 0.7168786, 0.0971045],
+                [0.1430804, 0.0606169, 0.7141733],
+            ],
+            ColorSpace::DisplayP3 => [
+                [0.5151021, 0.2411825, -0.0010500],
+                [0.2919649, 0.6922360, 0.0418817],
+                [0.1571536, 0.0665815, 0.7843785],
+            ],
+        }
+    }
+
+    fn s15f16(v: f32) -> [u8; 4] {
+        ((v * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    pub fn xyz(v: [f32; 3]) -> Vec<u8> {
+        let mut data = b"XYZ \0\0\0\0".to_vec();
+        v.iter().for_each(|c| data.extend(s15f16(*c)));
+        data
+    }
+
+    pub fn mluc(text: &str) -> Vec<u8> {
+        let utf16: Vec<u8> = text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect();
+        let mut data = b"mluc\0\0\0\0".to_vec();
+        data.extend(1u32.to_be_bytes());
+        data.extend(12u32.to_be_bytes());
+        data.extend(b"enUS");
+        data.extend((utf16.len() as u32).to_be_bytes());
+        data.extend(28u32.to_be_bytes());
+        data.extend(utf16);
+        data
+    }
+
+    /// Parametric curve, the sRGB one or a straight line
+    fn para(space: ColorSpace) -> Vec<u8> {
+        let mut data = b"para\0\0\0\0".to_vec();
+        match space {
+            ColorSpace::Linear => {
+                data.extend([0, 0, 0, 0]);
+                data.extend(s15f16(1.0));
+            }
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => {
+                data.extend([0, 3, 0, 0]);
+                for v in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+                    data.extend(s15f16(v));
+                }
+            }
+        }
+        data
+    }
+
+    pub fn profile(space: ColorSpace) -> Vec<u8> {
+        let mut chad = b"sf32\0\0\0\0".to_vec();
+        D65_TO_D50.iter().flatten().for_each(|v| chad.extend(s15f16(*v)));
+
+        let [r, g, b] = primaries(space);
+        let trc = para(space);
+        let tags: Vec<(&[u8; 4], Vec<u8>)> = vec![
+            (b"desc", mluc(space.name())),
+            (b"cprt", mluc("No copyright, use freely")),
+            (b"wtpt", xyz(D50)),
+            (b"chad", chad),
+            (b"rXYZ", xyz(r)),
+            (b"gXYZ", xyz(g)),
+            (b"bXYZ", xyz(b)),
+            (b"rTRC", trc.clone()),
+            (b"gTRC", trc.clone()),
+            (b"bTRC", trc),
+        ];
+        write(&tags)
+    }
+
+    /// Display profile with `tags`
+    pub fn write(tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        // Tag data starts after the header and the tag table, every element 4 byte aligned
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = vec![];
+        let data_start = 128 + 4 + 12 * tags.len();
+        for (signature, tag) in tags {
+            table.extend(*signature);
+            table.extend(((data_start + data.len()) as u32).to_be_bytes());
+            table.extend((tag.len() as u32).to_be_bytes());
+            data.extend(tag);
+            data.resize(data.len().next_multiple_of(4), 0);
+        }
+
+        let size = data_start + data.len();
+        let mut header = vec![];
+        header.extend((size as u32).to_be_bytes());
+        header.extend([0; 4]);
+        header.extend(0x04300000u32.to_be_bytes());
+        header.extend(b"mntrRGB XYZ ");
+        header.extend([0; 12]);
+        header.extend(b"acsp");
+        header.extend([0; 24]);
+        header.extend([0; 4]);
+        D50.iter().for_each(|v| header.extend(s15f16(*v)));
+        header.extend([0; 4]);
+        header.extend([0; 16]);
+        header.extend([0; 28]);
+        debug_assert_eq!(header.len(), 128);
+
+        [header, table, data].concat()
+    }
+
+    fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+        Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+    }
+
+    fn tag<'a>(profile: &'a [u8], signature: &[u8; 4]) -> Option<&'a [u8]> {
+        let count = be_u32(profile, 128)? as usize;
+        (0..count.min(256)).find_map(|i| {
+            let entry = 132 + 12 * i;
+            if profile.get(entry..entry + 4)? != signature {
+                return None;
+            }
+            let offset = be_u32(profile, entry + 4)? as usize;
+            let size = be_u32(profile, entry + 8)? as usize;
+            profile.get(offset..offset + size)
+        })
+    }
+
+    /// Text of the `desc` tag, v2 `desc` and v4 `mluc` types
+    pub fn description(profile: &[u8]) -> Option<String> {
+        let desc = tag(profile, b"desc")?;
+        match desc.get(0..4)? {
+            b"desc" => {
+                let length = be_u32(desc, 8)? as usize;
+                let text = desc.get(12..12 + length)?;
+                Some(String::from_utf8_lossy(text).trim_end_matches('\0').to_owned())
+            }
+            b"mluc" => {
+                let length = be_u32(desc, 20)? as usize;
+                let offset = be_u32(desc, 24)? as usize;
+                let utf16: Vec<u16> = desc.get(offset..offset + length)?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                Some(String::from_utf16_lossy(&utf16))
+            }
+            _ => None,
+        }
+    }
+
+    fn be_s15f16(data: &[u8], offset: usize) -> Option<f32> {
+        Some(be_u32(data, offset)? as i32 as f32 / 65536.0)
+    }
+
+    pub fn xyz_tag(profile: &[u8], signature: &[u8; 4]) -> Option<[f32; 3]> {
+        let data = tag(profile, signature)?;
+        Some([be_s15f16(data, 8)?, be_s15f16(data, 12)?, be_s15f16(data, 16)?])
+    }
+
+    /// Decodes `x` with the red tone curve, or the gray one of gray profiles.
+    /// Handles `curv` gammas and tables and every `para` function type.
+    pub fn tone(profile: &[u8], x: f32) -> Option<f32> {
+        let data = tag(profile, b"rTRC").or_else(|| tag(profile, b"kTRC"))?;
+        match data.get(0..4)? {
+            b"curv" => {
+                let count = be_u32(data, 8)? as usize;
+                let entry = |i: usize| Some(u16::from_be_bytes(data.get(12 + 2 * i..14 + 2 * i)?.try_into().ok()?) as f32);
+                match count {
+                    0 => Some(x),
+                    // u8Fixed8 gamma
+                    1 => Some(x.powf(entry(0)? / 256.0)),
+                    _ => {
+                        let at = x.clamp(0.0, 1.0) * (count - 1) as f32;
+                        let i = (at as usize).min(count - 2);
+                        let (a, b) = (entry(i)?, entry(i + 1)?);
+                        Some((a + (b - a) * (at - i as f32)) / 65535.0)
+                    }
+                }
+            }
+            b"para" => {
+                let kind = u16::from_be_bytes(data.get(8..10)?.try_into().ok()?) as usize;
+                let count = *[1, 3, 4, 5, 7].get(kind)?;
+                let p: Vec<f32> = (0..count).map(|i| be_s15f16(data, 12 + 4 * i)).collect::<Option<_>>()?;
+                let power = |v: f32| v.max(0.0).powf(p[0]);
+                Some(match kind {
+                    0 => power(x),
+                    1 => if x >= -p[2] / p[1] { power(p[1] * x + p[2]) } else { 0.0 },
+                    2 => if x >= -p[2] / p[1] { power(p[1] * x + p[2]) + p[3] } else { p[3] },
+                    3 => if x >= p[4] { power(p[1] * x + p[2]) } else { p[3] * x },
+                    _ => if x >= p[4] { power(p[1] * x + p[2]) + p[5] } else { p[3] * x + p[6] },
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: [f32; 3], b: [f32; 3], tolerance: f32) {
+        assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < tolerance), "{:?} != {:?}", a, b);
+    }
+
+    /// Reference values from Ottosson's Oklab post and colour picker
+    #[test]
+    fn oklab_reference_values() {
+        assert_close(linear_to_oklab([1.0, 1.0, 1.0]), [1.0, 0.0, 0.0], 1e-3);
+        assert_close(linear_to_oklab([1.0, 0.0, 0.0]), [0.627955, 0.224863, 0.125846], 1e-3);
+        assert_close(linear_to_oklab([0.0, 1.0, 0.0]), [0.866440, -0.233888, 0.179498], 1e-3);
+        assert_close(linear_to_oklab([0.0, 0.0, 1.0]), [0.452014, -0.032457, -0.311528], 1e-3);
+        assert_close(oklab_to_linear([0.627955, 0.224863, 0.125846]), [1.0, 0.0, 0.0], 1e-3);
+    }
+
+    #[test]
+    fn okhsl_reference_values() {
+        // sRGB red is okhsl(29.23°, 100%, 56.81%)
+        assert_close(linear_to_okhsl([1.0, 0.0, 0.0]), [29.23 / 360.0, 1.0, 0.5681], 2e-3);
+        assert_close(okhsl_to_linear([29.23 / 360.0, 1.0, 0.5681]), [1.0, 0.0, 0.0], 5e-3);
+
+        // Every gamut edge colour is fully saturated, and has full value in okhsv
+        for primary in [[0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 1.0, 0.0]] {
+            let [_, s, _] = linear_to_okhsl(primary);
+            assert!((s - 1.0).abs() < 2e-3, "{:?} has saturation {}", primary, s);
+            let [_, s, v] = linear_to_okhsv(primary);
+            assert!((s - 1.0).abs() < 2e-3 && (v - 1.0).abs() < 2e-3, "{:?} has okhsv s {} v {}", primary, s, v);
+        }
+
+        // Greys have no saturation, white and black sit at the ends of the lightness axis
+        let [_, s, l] = linear_to_okhsl([1.0, 1.0, 1.0]);
+        assert!(s.abs() < 1e-3 && (l - 1.0).abs() < 1e-3);
+        let [_, s, l] = linear_to_okhsl([0.0, 0.0, 0.0]);
+        assert!(s.abs() < 1e-3 && l.abs() < 1e-3);
+    }
+
+    #[test]
+    fn okhsl_and_okhsv_round_trip() {
+        for r in 0..=4 {
+            for g in 0..=4 {
+                for b in 0..=4 {
+                    let rgb = [r, g, b].map(|c| c as f32 / 4.0);
+                    assert_close(okhsl_to_linear(linear_to_okhsl(rgb)), rgb, 2e-3);
+                    assert_close(okhsv_to_linear(linear_to_okhsv(rgb)), rgb, 2e-3);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clipping_keeps_lightness_and_hue() {
+        let outside = oklab_to_linear([0.7, 0.3, 0.0]);
+        assert!(!in_gamut(outside));
+        let clipped = clip_to_gamut(outside);
+        assert!(in_gamut(clipped));
+        let [l, a, b] = linear_to_oklab(clipped);
+        assert!((l - 0.7).abs() < 1e-2 && a > 0.0 && b.abs() < 1e-2);
+    }
+
+    #[test]
+    fn parse_hex_and_oklch() {
+        assert_close(parse("#ff0000").unwrap(), [1.0, 0.0, 0.0], 1e-6);
+        assert_close(parse("oklch(62.7955% 0.2577 29.23)").unwrap(), [1.0, 0.0, 0.0], 2e-3);
+        assert!(parse("#ff00").is_err());
+        assert!(parse("oklch(0.5 0.1)").is_err());
+    }
+
+    #[test]
+    fn icc_profiles_round_trip() {
+        for space in ColorSpace::ALL {
+            let profile = space.icc_profile();
+            assert_eq!(icc::description(&profile).as_deref(), Some(space.name()));
+            assert_eq!(ColorSpace::from_icc(&profile), Some(space));
+            assert_eq!(u32::from_be_bytes(profile[0..4].try_into().unwrap()) as usize, profile.len());
+            assert_eq!(&profile[36..40], b"acsp");
+        }
+    }
+
+    /// `curv` tag with a gamma or a table
+    fn curv(entries: &[u16]) -> Vec<u8> {
+        let mut data = b"curv\0\0\0\0".to_vec();
+        data.extend((entries.len() as u32).to_be_bytes());
+        data.extend(entries.iter().flat_map(|e| e.to_be_bytes()));
+        data
+    }
+
+    fn para(kind: u16, params: &[f32]) -> Vec<u8> {
+        let mut data = b"para\0\0\0\0".to_vec();
+        data.extend(kind.to_be_bytes());
+        data.extend([0, 0]);
+        data.extend(params.iter().flat_map(|p| ((p * 65536.0).round() as i32).to_be_bytes()));
+        data
+    }
+
+    fn rgb_profile(description: &str, space: ColorSpace, trc: Vec<u8>) -> Vec<u8> {
+        let [r, g, b] = icc::primaries(space);
+        icc::write(&[
+            (b"desc", icc::mluc(description)),
+            (b"rXYZ", icc::xyz(r)),
+            (b"gXYZ", icc::xyz(g)),
+            (b"bXYZ", icc::xyz(b)),
+            (b"rTRC", trc.clone()),
+            (b"gTRC", trc.clone()),
+            (b"bTRC", trc),
+        ])
+    }
+
+    #[test]
+    fn icc_tone_curves_decide_over_descriptions() {
+        let gamma_22 = curv(&[(2.2 * 256.0) as u16]);
+        let srgb_table: Vec<u16> = (0..1024).map(|i| (srgb_to_linear(i as f32 / 1023.0) * 65535.0).round() as u16).collect();
+
+        // Named sRGB by whoever wrote it, but linear
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("sRGB IEC61966-2.1", ColorSpace::Srgb, para(0, &[1.0]))), Some(ColorSpace::Linear));
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("sRGB IEC61966-2.1", ColorSpace::Srgb, curv(&[]))), Some(ColorSpace::Linear));
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("Monitor", ColorSpace::Srgb, gamma_22.clone())), Some(ColorSpace::Srgb));
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("Monitor", ColorSpace::DisplayP3, curv(&srgb_table))), Some(ColorSpace::DisplayP3));
+        // The sRGB curve in the general form with offsets of zero
+        let srgb_para = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045, 0.0, 0.0];
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("", ColorSpace::Srgb, para(4, &srgb_para))), Some(ColorSpace::Srgb));
+
+        // Apple RGB like gamma, linear P3 and Adobe RGB primaries aren't supported
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("sRGB", ColorSpace::Srgb, curv(&[(1.8 * 256.0) as u16]))), None);
+        assert_eq!(ColorSpace::from_icc(&rgb_profile("Display P3", ColorSpace::DisplayP3, para(0, &[1.0]))), None);
+        let adobe = icc::write(&[(b"rXYZ", icc::xyz([0.6097, 0.3111, 0.0195])), (b"rTRC", gamma_22.clone())]);
+        assert_eq!(ColorSpace::from_icc(&adobe), None);
+
+        // Gray profiles only have the one curve
+        assert_eq!(ColorSpace::from_icc(&icc::write(&[(b"kTRC", gamma_22)])), Some(ColorSpace::Srgb));
+        assert_eq!(ColorSpace::from_icc(&icc::write(&[(b"kTRC", curv(&[]))])), Some(ColorSpace::Linear));
+
+        // Lut based profiles go by their description
+        assert_eq!(ColorSpace::from_icc(&icc::write(&[(b"desc", icc::mluc("Display P3"))])), Some(ColorSpace::DisplayP3));
+        assert_eq!(ColorSpace::from_icc(&icc::write(&[(b"desc", icc::mluc("Generic CMYK"))])), None);
+        assert_eq!(ColorSpace::from_icc(b"not a profile"), None);
+    }
+
+    #[test]
+    fn icc_parametric_curves() {
+        let tone = |kind, params: &[f32], x| icc::tone(&icc::write(&[(b"rTRC", para(kind, params))]), x).unwrap();
+        assert!((tone(0, &[2.0], 0.5) - 0.25).abs() < 1e-4);
+        // Cut off below -b / a
+        assert_eq!(tone(1, &[1.0, 2.0, -0.5], 0.2), 0.0);
+        assert!((tone(1, &[1.0, 2.0, -0.5], 0.5) - 0.5).abs() < 1e-4);
+        assert!((tone(2, &[1.0, 2.0, -0.5, 0.25], 0.2) - 0.25).abs() < 1e-4);
+        assert!((tone(3, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045], 0.02) - srgb_to_linear(0.02)).abs() < 1e-4);
+        assert!((tone(4, &[1.0, 1.0, 0.0, 2.0, 0.5, 0.1, 0.2], 0.25) - 0.7).abs() < 1e-4);
+        assert!(icc::tone(&icc::write(&[(b"rTRC", para(5, &[1.0]))]), 0.5).is_none());
+    }
+
+    #[test]
+    fn icc_primaries_are_stored() {
+        let profile = ColorSpace::DisplayP3.icc_profile();
+        let [r, g, b] = icc::primaries(ColorSpace::DisplayP3);
+        assert_close(icc::xyz_tag(&profile, b"rXYZ").unwrap(), r, 1e-4);
+        assert_close(icc::xyz_tag(&profile, b"gXYZ").unwrap(), g, 1e-4);
+        assert_close(icc::xyz_tag(&profile, b"bXYZ").unwrap(), b, 1e-4);
+        assert_eq!(icc::xyz_tag(&profile, b"none"), None);
+    }
+}
@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 use std::fmt::format;
 use std::ops::{Range, RangeInclusive};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use ash::vk;
 use ash::vk::{AccessFlags, BufferImageCopy, BufferUsageFlags, DescriptorSet, DescriptorSetLayoutBinding, DescriptorType, DeviceSize, ImageAspectFlags, ImageCopy, ImageLayout, ImageSubresourceLayers, ImageUsageFlags, ImageView, Offset3D, PipelineStageFlags, PushConstantRange, Sampler, ShaderStageFlags, WriteDescriptorSet};
 use bytemuck::{Pod, Zeroable};
@@ -10,30 +11,81 @@ use cen::graphics::pipeline_store::{PipelineConfig, PipelineKey};
 use cen::graphics::Renderer;
 use cen::graphics::renderer::RenderComponent;
 use cen::vulkan::{Buffer, CommandBuffer, ComputePipeline, DescriptorSetLayout, Image};
-use egui::{Button, Color32, ImageSize, ImageSource, Key, Pos2, Rect, Response, Scene, Sense, Slider, Stroke, StrokeKind, TextureId, Vec2, Widget};
+use egui::{Button, Color32, ComboBox, Event, ImageSize, ImageSource, Key, Pos2, Rect, Response, Scene, Sense, Slider, Stroke, StrokeKind, TextureId, Vec2, Widget};
 use egui::debug_text::print;
 use egui::ecolor::Hsva;
 use egui::emath::TSTransform;
 use egui::load::SizedTexture;
 use egui_dock::{DockArea, DockState, NodeIndex, Style};
 use gpu_allocator::MemoryLocation;
-use image::{EncodableLayout, GenericImageView, RgbaImage};
-use okhsl::Okhsl;
-use crate::editor::Tool::{Draw, Weight};
+use image::{EncodableLayout, GenericImageView, ImageResult, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use crate::editor::Tool::{Draw, Pick, Select, Weight};
+use crate::color;
+use crate::color::ColorSpace;
+use crate::export;
+use crate::gradient;
+use crate::gradient::{Gradient, RAMP_SIZE};
+use crate::history::{full_copy, History};
+use crate::layers::{create_layer_image, Compositor, LayerStack};
+use crate::project;
+use crate::palette::Palette;
+use crate::picker::Picker;
+use crate::animation::{Animation, Easing, Keyframe};
+use crate::canvas::Canvas;
+use crate::brushes::{BrushBlend, BrushBuffers, BrushLibrary, PushConstants, DABS_BINDING, GRADIENT_BINDING, PARAMS_BINDING, SELECTION_BINDING, WEIGHTS_BINDING};
+use crate::params;
+use crate::pen;
+use crate::pen::{Pen, PressureMapping};
+use crate::params::{ParamDecl, ParamValue};
+use crate::project::{Manifest, PROJECT_EXTENSION, PROJECT_VERSION};
+use crate::recording;
+use crate::recording::{Frame, Player, Recorder, RECORDING_EXTENSION};
+use crate::selection;
+use crate::selection::Selection;
+use crate::stroke;
+use crate::timelapse::{Capture, Session, Timelapse};
+use crate::stroke::{Dab, StrokeEngine, MAX_DABS};
+use crate::weights::{GpuWeight, WeightHandle, Weights, MAX_WEIGHTS};
+
+/// Size of the blank canvas started on when no image is given or it can't be opened
+const BLANK_CANVAS_SIZE: u32 = 1024;
 
 pub struct Editor {
     pub tree: DockState<String>,
-    image: Option<Image>,
+    image_path: PathBuf,
+    /// Image or project from the command line, opened in `initialize`
+    startup_path: Option<PathBuf>,
+    /// Why the last image or project couldn't be opened, shown under the open button
+    open_status: String,
+    /// Colour space of the file the canvas was loaded from
+    source_space: ColorSpace,
+    texture_dirty: bool,
+    pending_manifest: Option<Manifest>,
+    layer_images: HashMap<u64, Image>,
+    composite: Option<Image>,
+    compositor: Option<Compositor>,
     orig_image: Option<Image>,
     texture_id: Option<TextureId>,
     tab_viewer: Option<TabViewer>,
-    pipeline: Option<PipelineKey>,
+    brushes: BrushLibrary,
+    /// Brush buffers no frame in flight uses anymore
+    brush_buffers: Arc<Mutex<Vec<BrushBuffers>>>,
     draw_buffer: Option<Image>,
-    stencil_buffer: Option<Image>
+    stencil_buffer: Option<Image>,
+    selection_mask: Option<Image>,
+    history: History,
+    recorder: Option<Recorder>,
+    player: Option<Player>,
+    /// Replaced images and textures, kept until the frames that use them have finished
+    retired_images: Vec<Image>,
+    retired_textures: Vec<TextureId>,
+    /// Textures whose images are no longer in flight, freed on the next gui update
+    freed_textures: Arc<Mutex<Vec<TextureId>>>,
 }
 
 impl Editor {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(startup_path: Option<PathBuf>) -> Self {
 
         let mut tree = DockState::new(vec!["view".to_owned(), "extra".to_owned()]);
 
@@ -43,15 +95,620 @@ impl Editor {
 
         Self {
             tree,
+            image_path: PathBuf::from("untitled.png"),
+            startup_path,
+            open_status: String::new(),
+            source_space: ColorSpace::Srgb,
+            texture_dirty: false,
+            pending_manifest: None,
             texture_id: None,
-            image: None,
+            layer_images: HashMap::new(),
+            composite: None,
+            compositor: None,
             orig_image: None,
             draw_buffer: None,
             stencil_buffer: None,
-            pipeline: None,
+            selection_mask: None,
+            brushes: BrushLibrary::new("brushes"),
+            brush_buffers: Arc::new(Mutex::new(vec![])),
             tab_viewer: None,
+            history: History::new(1024),
+            recorder: None,
+            player: None,
+            retired_images: vec![],
+            retired_textures: vec![],
+            freed_textures: Arc::new(Mutex::new(vec![])),
         }
     }
+
+    fn active_layer_id(&self) -> u64 {
+        self.tab_viewer.as_ref().map(|t| t.layers.active_id()).unwrap_or(0)
+    }
+
+    fn active_image(&self) -> &Image {
+        &self.layer_images[&self.active_layer_id()]
+    }
+
+    /// Everything the brush and the history take from the ui this frame
+    fn capture_frame(&self) -> Frame {
+        let tab_viewer = self.tab_viewer.as_ref().unwrap();
+
+        // Fall back to any brush if the selected one is gone
+        let brush = self.brushes.get(&tab_viewer.brush)
+            .or_else(|| self.brushes.get(tab_viewer.brush_names.first()?));
+        let mapping = brush.and_then(|b| tab_viewer.pressure_mappings.get(&b.name)).cloned().unwrap_or_default();
+        let params = brush.map(|b| {
+            let values = tab_viewer.brush_params.get(&b.name).cloned().unwrap_or_default();
+            pen::apply(&b.params, &values, &mapping, tab_viewer.pen.pressure)
+        }).unwrap_or_default();
+
+        let [r, g, b] = color::okhsl_to_linear(tab_viewer.okhsl);
+        let alpha = pen::factor(&mapping, pen::OPACITY, tab_viewer.pen.pressure);
+        let [br, bg, bb] = color::okhsl_to_linear(tab_viewer.background);
+        let foreground = tab_viewer.okhsl;
+        let dirty_margin = tab_viewer.dirty_rect.then(|| stroke::footprint(&params, &tab_viewer.weights.handles));
+
+        Frame {
+            brush: brush.map(|b| b.name.clone()).unwrap_or_default(),
+            params,
+            blend: brush.map(|b| tab_viewer.brush_blends.get(&b.name).copied().unwrap_or(b.blend)).unwrap_or_default(),
+            color: [r, g, b, alpha],
+            background: [br, bg, bb, 1.0],
+            gradient: tab_viewer.gradient.active_stops(foreground, tab_viewer.background),
+            cursor_a: tab_viewer.image_pointer_prev.into(),
+            cursor_b: tab_viewer.image_pointer.into(),
+            tilt: tab_viewer.pen.tilt.into(),
+            pressure: tab_viewer.pen.pressure,
+            dabs: tab_viewer.stroke.dabs.clone(),
+            weights: tab_viewer.weights.handles.clone(),
+            dirty_margin,
+            layers: tab_viewer.layers.layers.clone(),
+            active_layer: tab_viewer.layers.active,
+            // Nothing is merged while compute is paused
+            merge: (tab_viewer.merge || tab_viewer.flush_dabs) && !tab_viewer.compute,
+            reset: tab_viewer.reset_image,
+            undo: tab_viewer.undo,
+            redo: tab_viewer.redo,
+        }
+    }
+
+    /// Starts and stops recordings and replays as requested from the ui
+    fn update_recording(&mut self, width: u32, height: u32) {
+        let tab_viewer = self.tab_viewer.as_mut().unwrap();
+
+        if std::mem::take(&mut tab_viewer.stop_recording) {
+            self.player = None;
+            if let Some(recorder) = self.recorder.take() {
+                let frames = recorder.frames;
+                match recorder.finish() {
+                    Ok(()) => println!("Recorded {} frames", frames),
+                    Err(e) => println!("Failed to finish recording: {}", e),
+                }
+            }
+        }
+
+        if let Some(path) = tab_viewer.record.take() {
+            match Recorder::create(&path, width, height) {
+                Ok(recorder) => self.recorder = Some(recorder),
+                Err(e) => println!("Failed to record to {}: {}", path.display(), e),
+            }
+        }
+
+        if let Some(path) = tab_viewer.replay.take() {
+            match Player::open(&path) {
+                Ok(player) if (player.width, player.height) != (width, height) => {
+                    println!("Can't replay {}, it was recorded on a {}x{} image", path.display(), player.width, player.height);
+                }
+                Ok(player) => {
+                    // The stroke in progress would otherwise be merged into the replay
+                    tab_viewer.stroke.clear();
+                    self.player = Some(player);
+                }
+                Err(e) => println!("Failed to open recording {}: {}", path.display(), e),
+            }
+        }
+
+        tab_viewer.recording_status = match (&self.recorder, &self.player) {
+            (_, Some(player)) => format!("replaying {}/{}", player.played, player.frames),
+            (Some(recorder), None) => format!("recording, {} frames", recorder.frames),
+            (None, None) => String::new(),
+        };
+    }
+
+    /// Flattens the merged layers, reads them back from the gpu and writes them to `path`
+    /// once the command buffer has finished
+    fn export(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, path: PathBuf) {
+        let width = self.orig_image.as_ref().unwrap().width;
+        let height = self.orig_image.as_ref().unwrap().height;
+
+        let flat = create_layer_image(renderer, command_buffer, width, height);
+        self.compositor.as_ref().unwrap().composite(
+            renderer,
+            command_buffer,
+            &flat,
+            &self.tab_viewer.as_ref().unwrap().layers,
+            &self.layer_images,
+            self.active_image()
+        );
+        renderer.transition_image(
+            &command_buffer,
+            flat.handle(),
+            ImageLayout::GENERAL,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::COMPUTE_SHADER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::SHADER_WRITE,
+            AccessFlags::TRANSFER_READ,
+        );
+
+        let mut buf = Self::download(renderer, command_buffer, &flat);
+        let space = self.tab_viewer.as_ref().unwrap().export_space;
+
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            // Keep the flattened image alive until the copy has finished
+            drop(flat);
+            let map = buf.mapped().unwrap();
+            let png = RgbaImage::from_raw(
+                width,
+                height,
+                Vec::from(map.as_slice())
+            ).expect("Failed to map png buffer");
+
+            match export::save_image(&path, png, space) {
+                Ok(()) => println!("Saved image to {}", path.display()),
+                Err(e) => println!("Failed to save image to {}: {}", path.display(), e),
+            }
+        }));
+    }
+
+    /// Records a copy of the `[x, y, width, height]` region of the composite, which has just been drawn
+    fn download_composite(&self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, region: [u32; 4]) -> Buffer {
+        let composite = self.composite.as_ref().unwrap();
+        renderer.transition_image(
+            &command_buffer,
+            composite.handle(),
+            ImageLayout::GENERAL,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::COMPUTE_SHADER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::SHADER_WRITE,
+            AccessFlags::TRANSFER_READ,
+        );
+        let buf = Self::download_region(renderer, command_buffer, composite, region);
+        renderer.transition_image(
+            &command_buffer,
+            composite.handle(),
+            ImageLayout::GENERAL,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::COMPUTE_SHADER,
+            AccessFlags::TRANSFER_READ,
+            AccessFlags::SHADER_WRITE,
+        );
+        buf
+    }
+
+    /// Reads back the composite and hands it to `capture` as a timelapse frame
+    fn capture_composite(&self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, capture: Capture) {
+        let width = self.composite.as_ref().unwrap().width;
+        let height = self.composite.as_ref().unwrap().height;
+
+        let mut buf = self.download_composite(renderer, command_buffer, [0, 0, width, height]);
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            let map = buf.mapped().unwrap();
+            let pixels = RgbaImage::from_raw(width, height, Vec::from(map.as_slice())).expect("Failed to map timelapse buffer");
+            capture.write(pixels);
+        }));
+    }
+
+    /// Reads back the `size` by `size` area of the composite around `pos` for the eyedropper, the
+    /// average linear colour ends up in `result`. Areas at the edge are cut to the canvas.
+    fn pick_composite(&self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, pos: Pos2, size: u32, result: Arc<Mutex<Option<[f32; 3]>>>) {
+        let width = self.composite.as_ref().unwrap().width as i64;
+        let height = self.composite.as_ref().unwrap().height as i64;
+        let (x, y) = (pos.x.floor() as i64, pos.y.floor() as i64);
+        if x < 0 || y < 0 || x >= width || y >= height {
+            return;
+        }
+
+        let half = (size / 2) as i64;
+        let (x0, y0) = ((x - half).max(0), (y - half).max(0));
+        let (x1, y1) = ((x + half + 1).min(width), (y + half + 1).min(height));
+        let region = [x0 as u32, y0 as u32, (x1 - x0) as u32, (y1 - y0) as u32];
+
+        let mut buf = self.download_composite(renderer, command_buffer, region);
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            let map = buf.mapped().unwrap();
+            let pixels = map.as_slice();
+            let count = (pixels.len() / 4) as f32;
+            let mut sum = [0.0; 3];
+            for pixel in pixels.chunks_exact(4) {
+                for i in 0..3 {
+                    sum[i] += pixel[i] as f32 / 255.0;
+                }
+            }
+            *result.lock().unwrap() = Some(sum.map(|c| c / count));
+        }));
+    }
+
+    /// Writes the animation of a stopped timelapse once the frames of earlier command buffers are queued
+    fn finish_timelapse(renderer: &mut Renderer, command_buffer: &mut CommandBuffer, session: Session) {
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            // Encoding takes a while at full resolution, keep it off the render thread
+            std::thread::spawn(move || match session.finish() {
+                Ok(frames) => println!("Saved {} frames", frames),
+                Err(e) => println!("Failed to save frames: {}", e),
+            });
+        }));
+    }
+
+    /// Records a copy of `image`, which has to be in the general layout, into a new host visible buffer.
+    /// The buffer can be mapped once the command buffer has finished.
+    fn download(renderer: &mut Renderer, command_buffer: &mut CommandBuffer, image: &Image) -> Buffer {
+        Self::download_region(renderer, command_buffer, image, [0, 0, image.width, image.height])
+    }
+
+    /// Like `download`, but only the `[x, y, width, height]` region of `image`, rows packed tightly
+    fn download_region(renderer: &mut Renderer, command_buffer: &mut CommandBuffer, image: &Image, region: [u32; 4]) -> Buffer {
+        let [x, y, width, height] = region;
+        let buf = Buffer::new(
+            &renderer.device,
+            &mut renderer.allocator,
+            MemoryLocation::CpuToGpu,
+            (width * height * 4) as DeviceSize,
+            BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST
+        );
+
+        let bufferimagecopy = [
+            BufferImageCopy::default()
+                .buffer_offset(0)
+                .buffer_row_length(width as u32)
+                .buffer_image_height(height)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_offset(vk::Offset3D { x: x as i32, y: y as i32, z: 0 })
+                .image_extent(vk::Extent3D { width, height, depth: 1 })
+        ];
+
+        command_buffer.copy_image_to_buffer(
+            image,
+            ImageLayout::GENERAL,
+            &buf,
+            &bufferimagecopy
+        );
+
+        buf
+    }
+
+    /// Opens an image or a project file, returns false if that failed
+    fn open(&mut self, renderer: &mut Renderer, path: &Path) -> bool {
+        if !project::is_project(path) {
+            match self.load_image(renderer, path) {
+                Ok(warning) => self.open_status = warning.unwrap_or_default(),
+                Err(e) => {
+                    self.open_status = format!("Failed to open image {}: {}", path.display(), e);
+                    println!("{}", self.open_status);
+                    return false;
+                }
+            }
+            self.image_path = path.to_path_buf();
+            if let Some(tab_viewer) = self.tab_viewer.as_mut() {
+                tab_viewer.layers = LayerStack::new();
+            }
+            return true;
+        }
+
+        match project::load(path) {
+            Ok(project) => {
+                let layers: Vec<(u64, &RgbaImage)> = project.layers.iter().map(|(id, image)| (*id, image)).collect();
+                self.load_canvas(renderer, &project.original, &layers);
+                self.image_path = PathBuf::from(&project.manifest.source);
+                // The layer stack has to match the new images before the next frame is drawn
+                if let Some(tab_viewer) = self.tab_viewer.as_mut() {
+                    tab_viewer.layers = LayerStack::from_layers(project.manifest.layers.clone(), project.manifest.active_layer);
+                }
+                self.pending_manifest = Some(project.manifest);
+                self.open_status.clear();
+                true
+            }
+            Err(e) => {
+                self.open_status = format!("Failed to open project {}: {}", path.display(), e);
+                println!("{}", self.open_status);
+                false
+            }
+        }
+    }
+
+    /// Restores the ui state stored in a project
+    fn apply_manifest(&mut self, manifest: Manifest) {
+        let tab_viewer = self.tab_viewer.as_mut().unwrap();
+        tab_viewer.layers = LayerStack::from_layers(manifest.layers, manifest.active_layer);
+        tab_viewer.okhsl = manifest.color;
+        if let Some(background) = manifest.background {
+            tab_viewer.background = background;
+        }
+        tab_viewer.gradient = manifest.gradient;
+        if !manifest.brush.is_empty() {
+            tab_viewer.brush = manifest.brush;
+        }
+        tab_viewer.brush_params = manifest.brush_params;
+        tab_viewer.brush_blends = manifest.brush_blends;
+        tab_viewer.pressure_mappings = manifest.pressure_mappings;
+        tab_viewer.export_space = manifest.export_space;
+        tab_viewer.current_tool = manifest.current_tool;
+        tab_viewer.weights = Weights::from_handles(manifest.weights);
+        tab_viewer.animation.set_keyframes(manifest.keyframes);
+        tab_viewer.palette.set_swatches(manifest.palette);
+        self.tree = manifest.dock;
+    }
+
+    fn manifest(&self) -> Manifest {
+        let tab_viewer = self.tab_viewer.as_ref().unwrap();
+        Manifest {
+            version: PROJECT_VERSION,
+            source: self.image_path.to_string_lossy().into_owned(),
+            layers: tab_viewer.layers.layers.clone(),
+            active_layer: tab_viewer.layers.active,
+            color: tab_viewer.okhsl,
+            background: Some(tab_viewer.background),
+            gradient: tab_viewer.gradient.clone(),
+            brush: tab_viewer.brush.clone(),
+            brush_params: tab_viewer.brush_params.clone(),
+            brush_blends: tab_viewer.brush_blends.clone(),
+            pressure_mappings: tab_viewer.pressure_mappings.clone(),
+            export_space: tab_viewer.export_space,
+            current_tool: tab_viewer.current_tool,
+            weights: tab_viewer.weights.handles.clone(),
+            keyframes: tab_viewer.animation.keyframes.clone(),
+            palette: tab_viewer.palette.swatches.clone(),
+            dock: self.tree.clone(),
+        }
+    }
+
+    /// Reads all layers back from the gpu and writes the project once the command buffer has finished
+    fn save_project(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, path: PathBuf) {
+        let manifest = self.manifest();
+        let width = self.orig_image.as_ref().unwrap().width;
+        let height = self.orig_image.as_ref().unwrap().height;
+
+        let mut original = Self::download(renderer, command_buffer, self.orig_image.as_ref().unwrap());
+        let mut layers = vec![];
+        for layer in &manifest.layers {
+            layers.push((layer.id, Self::download(renderer, command_buffer, &self.layer_images[&layer.id])));
+        }
+
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            let read = |buf: &mut Buffer| {
+                let map = buf.mapped().unwrap();
+                RgbaImage::from_raw(width, height, Vec::from(map.as_slice())).expect("Failed to map layer buffer")
+            };
+
+            let original = read(&mut original);
+            let layers: Vec<(u64, RgbaImage)> = layers.iter_mut().map(|(id, buf)| (*id, read(buf))).collect();
+
+            match project::save(&path, &manifest, &original, &layers) {
+                Ok(()) => println!("Saved project to {}", path.display()),
+                Err(e) => println!("Failed to save project to {}: {}", path.display(), e),
+            }
+        }));
+    }
+
+    /// Loads the image at `path` as the background of a new canvas, the current canvas is kept
+    /// if the image can't be read. Returns a warning for the user if the colour profile isn't known.
+    fn load_image(&mut self, renderer: &mut Renderer, path: &Path) -> ImageResult<Option<String>> {
+
+        // Load image from disk
+        let (im, space) = color::load(path)?;
+        let warning = space.is_none().then(|| format!("Unknown colour profile in {}, assuming sRGB", path.display()));
+        if let Some(warning) = &warning {
+            println!("{}", warning);
+        }
+        let space = space.unwrap_or(ColorSpace::Srgb);
+        let im = color::decode(im, space);
+        self.source_space = space;
+
+        self.load_canvas(renderer, &im, &[(0, &im)]);
+        Ok(warning)
+    }
+
+    /// Starts over on an opaque white canvas
+    fn load_blank(&mut self, renderer: &mut Renderer, width: u32, height: u32) {
+        let blank = RgbaImage::from_pixel(width, height, Rgba([255; 4]));
+        self.source_space = ColorSpace::Srgb;
+        self.image_path = PathBuf::from("untitled.png");
+        self.load_canvas(renderer, &blank, &[(0, &blank)]);
+    }
+
+    /// (Re)creates the canvas images at the size of `original` and uploads the layers.
+    /// All pixel data is linear.
+    fn load_canvas(&mut self, renderer: &mut Renderer, original: &RgbaImage, layers: &[(u64, &RgbaImage)]) {
+        self.history.clear();
+
+        // The previous frame may still be using the old canvas
+        self.retired_images.extend(
+            [self.orig_image.take(), self.draw_buffer.take(), self.stencil_buffer.take(), self.selection_mask.take(), self.composite.take()]
+                .into_iter()
+                .flatten()
+        );
+        self.retired_images.extend(self.layer_images.drain().map(|(_, image)| image));
+
+        let width = original.width();
+        let height = original.height();
+
+        let usage = ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC;
+        self.orig_image = Some(Image::new(&renderer.device, &mut renderer.allocator, width, height, usage));
+        self.draw_buffer = Some(Image::new(&renderer.device, &mut renderer.allocator, width, height, usage));
+        self.stencil_buffer = Some(Image::new(&renderer.device, &mut renderer.allocator, width, height, usage));
+        self.selection_mask = Some(Image::new(&renderer.device, &mut renderer.allocator, width, height, usage));
+        self.composite = Some(Image::new(&renderer.device, &mut renderer.allocator, width, height, usage));
+
+        let mut command_buffer = renderer.create_command_buffer();
+        command_buffer.begin();
+
+        // The staging buffers have to outlive the command buffer
+        let mut staging = vec![];
+        let original_buffer = Self::upload(renderer, &mut command_buffer, original, self.orig_image.as_ref().unwrap());
+        staging.push(original_buffer);
+
+        let mut layer_images = HashMap::new();
+        for (id, pixels) in layers {
+            let image = Image::new(&renderer.device, &mut renderer.allocator, width, height, usage);
+            staging.push(Self::upload(renderer, &mut command_buffer, pixels, &image));
+            layer_images.insert(*id, image);
+        }
+
+        renderer.transition_image(
+            &command_buffer,
+            self.stencil_buffer.as_ref().unwrap().handle(),
+            ImageLayout::UNDEFINED,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::TRANSFER_READ,
+            AccessFlags::TRANSFER_WRITE,
+        );
+
+        command_buffer.clear_color_image(
+            self.stencil_buffer.as_ref().unwrap(),
+            ImageLayout::GENERAL,
+            [0.0, 0.0, 0.0, 1.0]
+        );
+
+        // Everything starts out selected
+        renderer.transition_image(
+            &command_buffer,
+            self.selection_mask.as_ref().unwrap().handle(),
+            ImageLayout::UNDEFINED,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::TRANSFER_READ,
+            AccessFlags::TRANSFER_WRITE,
+        );
+        command_buffer.clear_color_image(
+            self.selection_mask.as_ref().unwrap(),
+            ImageLayout::GENERAL,
+            [1.0, 1.0, 1.0, 1.0]
+        );
+
+        // Draw buffer and composite are overwritten every frame, they start out as the original
+        for image in [self.draw_buffer.as_ref().unwrap(), self.composite.as_ref().unwrap()] {
+            renderer.transition_image(
+                &command_buffer,
+                image.handle(),
+                ImageLayout::UNDEFINED,
+                ImageLayout::GENERAL,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_READ,
+                AccessFlags::TRANSFER_WRITE,
+            );
+            command_buffer.copy_image(
+                self.orig_image.as_ref().unwrap(),
+                ImageLayout::GENERAL,
+                image,
+                ImageLayout::GENERAL,
+                &full_copy(width, height)
+            );
+            renderer.transition_image(
+                &command_buffer,
+                image.handle(),
+                ImageLayout::GENERAL,
+                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::TRANSFER,
+                AccessFlags::TRANSFER_READ,
+                AccessFlags::TRANSFER_WRITE,
+            );
+        }
+
+        command_buffer.end();
+        renderer.submit_single_time_command_buffer(command_buffer);
+        drop(staging);
+
+        self.layer_images = layer_images;
+    }
+
+    /// Drops the images and textures replaced since the last frame once `command_buffer` has
+    /// finished, earlier frames may still use them
+    fn release_retired(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer) {
+        if self.retired_images.is_empty() && self.retired_textures.is_empty() {
+            return;
+        }
+        let images = std::mem::take(&mut self.retired_images);
+        let textures = std::mem::take(&mut self.retired_textures);
+        let freed = self.freed_textures.clone();
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+            drop(images);
+            freed.lock().unwrap().extend(textures);
+        }));
+    }
+
+    /// Records a copy of `pixels` into `image` and leaves the image in the general layout.
+    /// Returns the staging buffer, which must be kept alive until the commands have executed.
+    fn upload(renderer: &mut Renderer, command_buffer: &mut CommandBuffer, pixels: &RgbaImage, image: &Image) -> Buffer {
+        let width = pixels.width();
+        let height = pixels.height();
+
+        let mut buf = Buffer::new(
+            &renderer.device,
+            &mut renderer.allocator,
+            MemoryLocation::CpuToGpu,
+            (width * height * 4) as DeviceSize,
+            BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST
+        );
+
+        let mut map = buf.mapped().unwrap();
+        let pixel_data = pixels.as_bytes();
+        unsafe { std::ptr::copy_nonoverlapping(pixel_data.as_ptr(), map.as_mut_slice().as_mut_ptr(), pixel_data.len()); }
+
+        renderer.transition_image(
+            &command_buffer,
+            image.handle(),
+            ImageLayout::UNDEFINED,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::TRANSFER_READ,
+            AccessFlags::TRANSFER_WRITE,
+        );
+        let regions = [
+            BufferImageCopy::default()
+                .buffer_offset(0)
+                .buffer_row_length(0)
+                .buffer_image_height(height)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+                .image_extent(vk::Extent3D { width, height, depth: 1 })
+        ];
+        command_buffer.copy_buffer_to_image(
+            &buf,
+            image,
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            &regions
+        );
+        renderer.transition_image(
+            &command_buffer,
+            image.handle(),
+            ImageLayout::TRANSFER_DST_OPTIMAL,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::TRANSFER_READ,
+            AccessFlags::TRANSFER_WRITE,
+        );
+
+        buf
+    }
 }
 
 struct TabViewer {
@@ -67,83 +724,81 @@ struct TabViewer {
     reset_image: bool,
     space_down: bool,
     compute: bool,
-    okhsl: Okhsl,
-    okhsl_h_32: f32,
-    shader_tool: u32,
+    /// Only run the brush around the dabs of the stroke
+    dirty_rect: bool,
+    /// Brush colour as okhsl h, s, l
+    okhsl: [f32; 3],
+    /// Background colour as okhsl h, s, l
+    background: [f32; 3],
+    gradient: Gradient,
+    brush: String,
+    brush_names: Vec<String>,
+    /// Parameter values per brush, only the ones that differ from the defaults
+    brush_params: HashMap<String, HashMap<String, ParamValue>>,
+    brush_param_decls: Vec<ParamDecl>,
+    /// Blend mode per brush, only the ones changed from what the brush declares
+    brush_blends: HashMap<String, BrushBlend>,
+    /// Blend mode the selected brush declares
+    brush_default_blend: BrushBlend,
+    pen: Pen,
+    pressure_mappings: HashMap<String, PressureMapping>,
+    stroke: StrokeEngine,
     current_tool: Tool,
-    weight_pos: Vec<Pos2>,
+    weights: Weights,
+    selection: Selection,
     in_scene: bool,
     shift_down: bool,
     export_image: bool,
+    export_path: String,
+    allow_overwrite: bool,
+    export_space: ColorSpace,
+    open_image: Option<PathBuf>,
+    /// Why the last image or project couldn't be opened
+    open_status: String,
+    save_project: Option<PathBuf>,
     merge: bool,
+    undo: bool,
+    redo: bool,
+    history_len: usize,
+    can_undo: bool,
+    can_redo: bool,
+    /// The dabs of the stroke filled up and have all been drawn, merge them
+    flush_dabs: bool,
+    history_budget_mb: usize,
+    layers: LayerStack,
+    /// Where to start a recording
+    record: Option<PathBuf>,
+    /// Recording to replay on the canvas
+    replay: Option<PathBuf>,
+    /// Ends the current recording and replay
+    stop_recording: bool,
+    recording_status: String,
+    timelapse: Timelapse,
+    animation: Animation,
+    canvas: Canvas,
+    palette: Palette,
+    picker: Picker,
+    /// Image position to read the brush colour from
+    pick_request: Option<Pos2>,
+    /// Width of the square the eyedropper averages over, 1, 3 or 5 pixels
+    pick_size: u32,
+    /// Linear colour the eyedropper read back
+    pick_result: Arc<Mutex<Option<[f32; 3]>>>,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Tool {
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Tool {
     Draw,
-    Weight
+    Weight,
+    Select,
+    /// Eyedropper, the colour under the pointer becomes the brush colour
+    Pick
 }
 
 impl TabViewer {
-    fn multi_color_gradient_slider(
-        ui: &mut egui::Ui,
-        value: &mut f32,
-        range: RangeInclusive<f32>,
-        colors: &[Color32]
-    ) {
-        if colors.is_empty() {
-            // Fallback to regular slider if no colors provided
-            ui.add(egui::Slider::new(value, range));
-            return;
-        }
-
-        let desired_size = egui::vec2(ui.available_width().min(200.0), 24.0);
-        let (rect, _) = ui.allocate_exact_size(desired_size, egui::Sense::hover());
-
-        // Draw multi-color gradient background
-        let painter = ui.painter();
-        let gradient_rect = rect.shrink(2.0);
-
-        if colors.len() == 1 {
-            // Single color - just fill with that color
-            painter.rect_filled(gradient_rect, 2, colors[0]);
-        } else {
-            // Multi-color gradient
-            let segments = colors.len();
-            let segment_width = gradient_rect.width() / segments as f32;
-
-            for i in 0..segments {
-                painter.rect_filled(
-                    Rect {
-                        min: gradient_rect.min + Vec2::new(segment_width * i as f32, 0.0),
-                        max: gradient_rect.min + Vec2::new(segment_width * i as f32 + segment_width, gradient_rect.height())},
-                    2,
-                    colors[i]
-                );
-            }
-        }
-
-
-        // Add border
-        painter.rect_stroke(gradient_rect, egui::Rounding::same(4),
-                            egui::Stroke::new(1.0, egui::Color32::GRAY), StrokeKind::Inside);
-
-        ui.scope(|ui| {
-            // Override the style to force minimum size
-            ui.style_mut().spacing.slider_width = rect.width();
-            ui.style_mut().spacing.interact_size.x = rect.width();
-
-            ui.add(Slider::new(value, range).show_value(false));
-        });
-
-        // Place transparent slider on top
-        // let layout = egui::layout::left_to_right(egui::align::left);
-        // ui.allocate_ui_with_layout(rect.size(), layout, |ui| {
-        //     ui.style_mut().visuals.widgets.inactive.bg_fill = egui::color32::transparent;
-        //     ui.style_mut().visuals.widgets.hovered.bg_fill = egui::color32::transparent;
-        //     ui.style_mut().visuals.widgets.active.bg_fill = egui::color32::transparent;
-        //     ui.add_sized(rect.size(), egui::slider::new(value, range).show_value(true));
-        // });
+    /// Swaps the foreground and background colours
+    fn swap_colors(&mut self) {
+        self.okhsl = std::mem::replace(&mut self.background, self.okhsl);
     }
 }
 
@@ -157,43 +812,131 @@ impl egui_dock::TabViewer for TabViewer {
     fn ui(&mut self, ui: &mut egui::Ui, tab: &mut Self::Tab) {
         if tab == "tools" {
 
+            ui.horizontal(|ui| {
+                if ui.button("open").clicked() {
+                    self.open_image = rfd::FileDialog::new()
+                        .add_filter("image or project", &["png", "jpg", "jpeg", "webp", "tif", "tiff", "exr", PROJECT_EXTENSION])
+                        .pick_file();
+                }
+                if ui.button("save project").clicked() {
+                    self.save_project = rfd::FileDialog::new()
+                        .add_filter("project", &[PROJECT_EXTENSION])
+                        .save_file()
+                        .map(|p| p.with_extension(PROJECT_EXTENSION));
+                }
+            });
+            if !self.open_status.is_empty() {
+                ui.colored_label(ui.visuals().error_fg_color, &self.open_status);
+            }
             self.reset_image = ui.button("reset").clicked();
+
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.export_path);
+                if ui.button("…").clicked() {
+                    let current = PathBuf::from(&self.export_path);
+                    let mut dialog = rfd::FileDialog::new()
+                        .add_filter("image", &export::EXPORT_EXTENSIONS);
+                    if let Some(name) = current.file_name().and_then(|n| n.to_str()) {
+                        dialog = dialog.set_file_name(name);
+                    }
+                    if let Some(dir) = current.parent().filter(|d| d.is_dir()) {
+                        dialog = dialog.set_directory(dir);
+                    }
+                    if let Some(path) = dialog.save_file() {
+                        self.export_path = path.to_string_lossy().into_owned();
+                    }
+                }
+            });
+            ComboBox::from_label("export colour space")
+                .selected_text(self.export_space.name())
+                .show_ui(ui, |ui| {
+                    for space in ColorSpace::ALL {
+                        ui.selectable_value(&mut self.export_space, space, space.name());
+                    }
+                });
+            ui.checkbox(&mut self.allow_overwrite, "allow overwriting source");
             self.export_image = ui.button("export").clicked();
+
             self.merge = ui.button("merge").clicked();
 
+            ui.horizontal(|ui| {
+                self.undo = ui.add_enabled(self.can_undo, Button::new("undo")).clicked();
+                self.redo = ui.add_enabled(self.can_redo, Button::new("redo")).clicked();
+                ui.label(format!("{} steps", self.history_len));
+            });
+            ui.add(Slider::new(&mut self.history_budget_mb, 64..=8192).text("history MB"));
+
+            ui.horizontal(|ui| {
+                if ui.button("record").clicked() {
+                    self.record = rfd::FileDialog::new()
+                        .add_filter("recording", &[RECORDING_EXTENSION])
+                        .save_file()
+                        .map(|p| p.with_extension(RECORDING_EXTENSION));
+                }
+                if ui.button("replay").clicked() {
+                    self.replay = rfd::FileDialog::new()
+                        .add_filter("recording", &[RECORDING_EXTENSION])
+                        .pick_file();
+                }
+                self.stop_recording = ui.add_enabled(!self.recording_status.is_empty(), Button::new("stop")).clicked();
+            });
+            if !self.recording_status.is_empty() {
+                ui.label(&self.recording_status);
+            }
+
             ui.separator();
 
-            ui.label("H");
-            let mut colors = vec![];
-            for i in 0..20 {
-                let hsl = Okhsl { h: i as f64 / 19., s: self.okhsl.s, l: self.okhsl.l };
-                let rgb = hsl.to_srgb();
-                colors.push(Color32::from_rgb(rgb.r, rgb.g, rgb.b));
-            }
-            Self::multi_color_gradient_slider(ui, &mut self.okhsl_h_32, RangeInclusive::new(0., 1.0), colors.as_slice() );
-            self.okhsl.h = self.okhsl_h_32 as f64;
-            ui.label("S");
-            let mut colors = vec![];
-            for i in 0..20 {
-                let hsl = Okhsl { h: self.okhsl.h, s: i as f32 / 19., l: self.okhsl.l };
-                let rgb = hsl.to_srgb();
-                colors.push(Color32::from_rgb(rgb.r, rgb.g, rgb.b));
-            }
-            Self::multi_color_gradient_slider(ui, &mut self.okhsl.s, RangeInclusive::new(0., 1.0), colors.as_slice() );
-            ui.label("L");
-            let mut colors = vec![];
-            for i in 0..20 {
-                let hsl = Okhsl { h: self.okhsl.h, s: self.okhsl.s, l: i as f32 / 19. };
-                let rgb = hsl.to_srgb();
-                colors.push(Color32::from_rgb(rgb.r, rgb.g, rgb.b));
-            }
-            Self::multi_color_gradient_slider(ui, &mut self.okhsl.l, RangeInclusive::new(0., 1.0), colors.as_slice() );
-
-            let rgb = self.okhsl.to_srgb();
+            self.timelapse.ui(ui);
+
+            ui.separator();
+
+            // New keyframes take the brush as it is set up right now, with every parameter filled in
+            let [r, g, b] = color::okhsl_to_linear(self.okhsl);
+            let values = self.brush_params.get(&self.brush).cloned().unwrap_or_default();
+            let current = Keyframe {
+                frame: self.animation.current,
+                weights: self.weights.handles.clone(),
+                color: [r, g, b, 1.0],
+                params: self.brush_param_decls.iter()
+                    .map(|d| (d.name.clone(), values.get(&d.name).copied().unwrap_or(d.default)))
+                    .collect(),
+                easing: Easing::Linear,
+            };
+            self.animation.ui(ui, current);
+
+            ui.separator();
+
+            self.layers.ui(ui);
+
+            ui.separator();
+
+            let selection = &self.selection;
+            self.canvas.ui(ui, [self.texture_size.x as u32, self.texture_size.y as u32], || selection.bounds());
+
+            ui.separator();
+
+            self.picker.ui(ui, &mut self.okhsl);
+
+            // Foreground colour with the background colour next to it
+            let to_srgb = |hsl| ColorSpace::Srgb.from_linear(color::okhsl_to_linear(hsl)).map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            let [fr, fg, fb] = to_srgb(self.okhsl);
+            let [r, g, b] = to_srgb(self.background);
             let width = ui.available_width();
             let (rect, _) = ui.allocate_exact_size(Vec2 { x: width, y: 60. }, egui::Sense::hover());
+            let split = rect.min.x + rect.width() * 0.75;
             let painter = ui.painter();
-            painter.rect_filled(rect, 2, Color32::from_rgb(rgb.r, rgb.g, rgb.b));
+            painter.rect_filled(Rect::from_min_max(rect.min, Pos2::new(split, rect.max.y)), 2, Color32::from_rgb(fr, fg, fb));
+            painter.rect_filled(Rect::from_min_max(Pos2::new(split, rect.min.y), rect.max), 2, Color32::from_rgb(r, g, b));
+            if ui.button("swap colours").on_hover_text("X").clicked() {
+                self.swap_colors();
+            }
+
+            if let Some([h, s, l]) = self.palette.ui(ui, self.okhsl) {
+                // Grey swatches have no hue, keep the one the slider is at
+                self.okhsl = [if s > 0.0 { h } else { self.okhsl[0] }, s, l];
+            }
+
+            self.gradient.ui(ui, self.okhsl);
 
             ui.separator();
 
@@ -207,16 +950,75 @@ impl egui_dock::TabViewer for TabViewer {
             if ui.add(weight_button).clicked() {
                 self.current_tool = Weight;
             }
+            let mut select_button = Button::new("Select");
+            if self.current_tool == Select { select_button = select_button.selected(true); }
+            if ui.add(select_button).clicked() {
+                self.current_tool = Select;
+            }
+            let mut pick_button = Button::new("Pick");
+            if self.current_tool == Pick { pick_button = pick_button.selected(true); }
+            if ui.add(pick_button).on_hover_text("alt click while drawing").clicked() {
+                self.current_tool = Pick;
+            }
+            if self.current_tool == Weight {
+                self.weights.ui(ui);
+            }
+            if self.current_tool == Pick {
+                ui.horizontal(|ui| {
+                    ui.label("sample");
+                    for size in [1, 3, 5] {
+                        ui.selectable_value(&mut self.pick_size, size, format!("{}x{}", size, size));
+                    }
+                });
+            }
+            if self.current_tool == Select {
+                self.selection.ui(ui);
+            }
 
             ui.separator();
 
             ui.checkbox(&mut self.compute, "Compute");
+            ui.checkbox(&mut self.dirty_rect, "dirty rectangle")
+                .on_hover_text("Only redraw around the dabs of the stroke, as far as the largest radius parameter or weight radius of the brush reaches. Brushes without dabs redraw the whole image.");
+
+            for name in &self.brush_names {
+                let button = Button::new(name)
+                    .selected(&self.brush == name);
+                if ui.add(button).clicked() { self.brush = name.clone(); }
+            }
 
-            for i in 0..10 {
-                let button = Button::new(format!("Tool {}", i))
-                    .selected(self.shader_tool == i);
-                if ui.add(button).clicked() { self.shader_tool = i; }
+            ui.separator();
+
+            let default_blend = self.brush_default_blend;
+            let blend = self.brush_blends.entry(self.brush.clone()).or_insert(default_blend);
+            ComboBox::from_label("blend")
+                .selected_text(blend.name())
+                .show_ui(ui, |ui| {
+                    for mode in BrushBlend::ALL {
+                        ui.selectable_value(blend, mode, mode.name());
+                    }
+                });
+            if *blend == default_blend {
+                self.brush_blends.remove(&self.brush);
+            }
+
+            let values = self.brush_params.entry(self.brush.clone()).or_default();
+            params::ui(ui, &self.brush_param_decls, values);
+
+            ui.separator();
+
+            if self.pen.has_pen {
+                ui.label(format!("pen pressure {:.2}", self.pen.pressure));
+            } else {
+                ui.label(format!("pointer pressure {:.2}", self.pen.pressure));
+                ui.add(Slider::new(&mut self.pen.max_speed, 100.0..=10000.0).text("max speed"));
             }
+            let mapping = self.pressure_mappings.entry(self.brush.clone()).or_default();
+            pen::mapping_ui(ui, &self.brush_param_decls, mapping);
+
+            ui.separator();
+
+            self.stroke.ui(ui);
 
         }
 
@@ -234,8 +1036,40 @@ impl egui_dock::TabViewer for TabViewer {
                 self.pointer_held = input.pointer.primary_down() && self.pointer_down;
                 self.pointer_down = input.pointer.primary_down();
                 self.pointer_released = input.pointer.primary_released();
+                self.pen.update(input);
                 self.space_down = input.key_down(Key::Space);
+
+                // Feed every pointer sample since the last frame to the stroke, not only the latest
+                if self.current_tool == Draw && !self.space_down {
+                    let frame_rect = ui.min_rect();
+                    let scene_rect = self.scene_rect;
+                    let to_image = |p: Pos2| (p - frame_rect.min) / frame_rect.size() * scene_rect.size() + scene_rect.min.to_vec2();
+
+                    // Alt click picks the colour instead of starting a stroke
+                    if input.pointer.primary_pressed() && self.in_scene && input.modifiers.alt {
+                        self.pick_request = Some(self.image_pointer.to_pos2());
+                    } else if input.pointer.primary_pressed() && self.in_scene {
+                        self.stroke.begin(self.image_pointer.to_pos2(), self.pen.pressure);
+                    }
+                    if self.stroke.is_active() {
+                        for event in &input.events {
+                            if let Event::PointerMoved(p) = event {
+                                self.stroke.add_sample(to_image(*p).to_pos2(), self.pen.pressure);
+                            }
+                        }
+                    }
+                    if input.pointer.primary_released() {
+                        self.stroke.end();
+                    }
+                }
                 self.merge = self.merge || input.key_pressed(Key::Enter);
+                if input.modifiers.command && input.key_pressed(Key::Z) {
+                    if input.modifiers.shift {
+                        self.redo = true;
+                    } else {
+                        self.undo = true;
+                    }
+                }
                 self.shift_down = input.modifiers.shift;
             });
 
@@ -260,12 +1094,8 @@ impl egui_dock::TabViewer for TabViewer {
                             inner_rect = ui.min_rect();
 
                             // Draw weights
-                            let painter = ui.painter();
-                            let weight_size = 10.;
-                            for p in &self.weight_pos {
-                                let rect = Rect { min: Pos2 { x: -weight_size, y: -weight_size } / 2. + p.to_vec2(), max: Pos2 { x: weight_size, y: weight_size } / 2. + p.to_vec2() };
-                                painter.rect_stroke(rect, 0, Stroke::new(1., Color32::from_rgb(255, 255, 255)), StrokeKind::Inside);
-                            }
+                            self.weights.paint(ui.painter());
+                            self.selection.paint(ui.painter(), ui.input(|i| i.time));
                         })
                         .response;
 
@@ -277,21 +1107,67 @@ impl egui_dock::TabViewer for TabViewer {
 
             self.view_rect = group.response.rect;
             self.in_scene = false;
+            // Keys typed into a text field shouldn't move or delete handles
+            let typing = ui.ctx().wants_keyboard_input();
             ui.input(|input| {
                 if let Some(pos) = input.pointer.latest_pos() {
                     self.in_scene = self.view_rect.contains(pos);
                 }
 
-                if self.current_tool == Weight {
+                if self.current_tool == Weight && !self.space_down {
+                    let pointer = self.image_pointer.to_pos2();
+                    self.weights.hovered = if self.in_scene { self.weights.hit(pointer) } else { None };
 
-                    if self.in_scene {
-                        if input.pointer.primary_pressed() {
-                            self.weight_pos[ 0 ] = self.image_pointer.to_pos2();
+                    // Handles are grabbed where they are drawn, shift click on empty canvas adds one
+                    if self.in_scene && input.pointer.primary_pressed() && !self.weights.begin_drag(pointer) {
+                        if input.modifiers.shift {
+                            self.weights.add(pointer);
+                        } else {
+                            self.weights.selected = None;
                         }
+                    }
+                    if self.weights.is_dragging() {
+                        self.weights.drag_to(pointer);
+                    }
+                    if !input.pointer.primary_down() {
+                        self.weights.end_drag();
+                    }
 
-                        if input.pointer.primary_down() {
-                            self.weight_pos[ 1 ] = self.image_pointer.to_pos2();
-                        }
+                    let step = if input.modifiers.shift { 10.0 } else { 1.0 };
+                    let nudge = [(Key::ArrowLeft, Vec2::new(-1.0, 0.0)), (Key::ArrowRight, Vec2::new(1.0, 0.0)), (Key::ArrowUp, Vec2::new(0.0, -1.0)), (Key::ArrowDown, Vec2::new(0.0, 1.0))]
+                        .iter()
+                        .filter(|(key, _)| input.key_pressed(*key))
+                        .fold(Vec2::ZERO, |sum, (_, d)| sum + *d);
+                    if nudge != Vec2::ZERO && !typing {
+                        self.weights.nudge(nudge * step);
+                    }
+
+                    if input.key_pressed(Key::Delete) && !typing {
+                        self.weights.delete_selected();
+                    }
+                } else {
+                    self.weights.hovered = None;
+                    self.weights.end_drag();
+                }
+
+                if input.key_pressed(Key::X) && !typing {
+                    self.swap_colors();
+                }
+
+                // Keep picking while the button is held so the colour follows the pointer
+                if self.current_tool == Pick && !self.space_down && self.in_scene && input.pointer.primary_down() {
+                    self.pick_request = Some(self.image_pointer.to_pos2());
+                }
+
+                if self.current_tool == Select && !self.space_down {
+                    let pointer = self.image_pointer.to_pos2();
+                    if self.in_scene && input.pointer.primary_pressed() {
+                        self.selection.begin(pointer);
+                    } else if input.pointer.primary_down() {
+                        self.selection.extend(pointer);
+                    }
+                    if input.pointer.primary_released() {
+                        self.selection.finish();
                     }
                 }
             });
@@ -304,8 +1180,8 @@ impl egui_dock::TabViewer for TabViewer {
 impl GuiComponent for Editor {
     fn initialize_gui(&mut self, gui: &mut GuiSystem) {
         if self.texture_id.is_none() {
-            assert!(self.draw_buffer.is_some());
-            self.texture_id = Some(gui.create_texture(self.draw_buffer.as_ref().unwrap()));
+            assert!(self.composite.is_some());
+            self.texture_id = Some(gui.create_texture(self.composite.as_ref().unwrap()));
         }
 
         self.tab_viewer = Some(TabViewer {
@@ -313,8 +1189,16 @@ impl GuiComponent for Editor {
             texture_id: self.texture_id.unwrap(),
             scene_rect: Rect::ZERO,
             view_rect: Rect::ZERO,
-            texture_size: Vec2::new(self.image.as_ref().unwrap().width as f32, self.image.as_ref().unwrap().height as f32),
-            shader_tool: 0,
+            texture_size: Vec2::new(self.orig_image.as_ref().unwrap().width as f32, self.orig_image.as_ref().unwrap().height as f32),
+            brush: "cone".to_owned(),
+            brush_names: self.brushes.names(),
+            brush_params: HashMap::new(),
+            brush_param_decls: vec![],
+            brush_blends: HashMap::new(),
+            brush_default_blend: BrushBlend::Normal,
+            pen: Pen::new(),
+            pressure_mappings: HashMap::new(),
+            stroke: StrokeEngine::new(),
             image_pointer: Default::default(),
             image_pointer_prev: Default::default(),
             pointer_down: false,
@@ -323,377 +1207,330 @@ impl GuiComponent for Editor {
             space_down: false,
             shift_down: false,
             compute: false,
+            dirty_rect: false,
             merge: false,
+            undo: false,
+            redo: false,
+            history_len: 0,
+            can_undo: false,
+            can_redo: false,
+            flush_dabs: false,
+            history_budget_mb: self.history.budget_mb,
             reset_image: false,
             export_image: false,
-            okhsl: Okhsl {
-                h: 1.0,
-                s: 1.0,
-                l: 1.0,
-            },
-            okhsl_h_32: 1.0,
+            export_path: export::default_export_path(&self.image_path).to_string_lossy().into_owned(),
+            allow_overwrite: false,
+            export_space: self.source_space,
+            open_image: None,
+            open_status: self.open_status.clone(),
+            save_project: None,
+            okhsl: [1.0, 1.0, 1.0],
+            background: [0.0, 0.0, 1.0],
+            gradient: Gradient::default(),
             current_tool: Draw,
-            weight_pos: vec![ Pos2 { x: 100., y: 100. }, Pos2 { x: 200., y: 100.} ],
+            weights: Weights::new(),
+            selection: Selection::new(),
+            layers: LayerStack::new(),
+            record: None,
+            replay: None,
+            stop_recording: false,
+            recording_status: String::new(),
+            timelapse: Timelapse::new(),
+            animation: Animation::new(),
+            canvas: Canvas::new(),
+            palette: Palette::new(),
+            picker: Picker::new(),
+            pick_request: None,
+            pick_size: 1,
+            pick_result: Arc::new(Mutex::new(None)),
         });
-    }
 
-    fn gui(&mut self, gui: &GuiSystem, context: &egui::Context) {
-        DockArea::new(&mut self.tree)
-            .style(Style::from_egui(context.style().as_ref()))
-            .show(context, self.tab_viewer.as_mut().unwrap());
+        if let Some(manifest) = self.pending_manifest.take() {
+            self.apply_manifest(manifest);
+        }
     }
-}
-
-#[repr(C)]
-#[derive(Copy, Clone, Pod, Zeroable)]
-struct PushConstants {
-    color: [f32; 4],
-    cursor_a: Vec2,
-    cursor_b: Vec2,
-    weight_a: Vec2,
-    weight_b: Vec2,
-    shader_tool: u32,
-}
-
-impl RenderComponent for Editor {
-    fn initialize(&mut self, renderer: &mut Renderer) {
 
-        // Initialize shader
-        let bindings = [
-            DescriptorSetLayoutBinding::default()
-                .binding(0)
-                .descriptor_count(1)
-                .descriptor_type(DescriptorType::STORAGE_IMAGE)
-                .stage_flags(ShaderStageFlags::COMPUTE),
-            DescriptorSetLayoutBinding::default()
-                .binding(1)
-                .descriptor_count(1)
-                .descriptor_type(DescriptorType::STORAGE_IMAGE)
-                .stage_flags(ShaderStageFlags::COMPUTE),
-            DescriptorSetLayoutBinding::default()
-                .binding(2)
-                .descriptor_count(1)
-                .descriptor_type(DescriptorType::STORAGE_IMAGE)
-                .stage_flags(ShaderStageFlags::COMPUTE)
-        ];
-
-        let layout = vec![DescriptorSetLayout::new_push_descriptor(
-            &renderer.device,
-            &bindings
-        )];
-
-        let push_constants = vec![PushConstantRange::default()
-            .size(size_of::<PushConstants>() as u32)
-            .stage_flags(ShaderStageFlags::COMPUTE)
-            .offset(0)
-        ];
-
-        let macros: HashMap<String, String> = HashMap::new();
-        self.pipeline = Some(renderer.pipeline_store().insert(PipelineConfig {
-            shader_path: "shaders/brush.comp".parse().unwrap(),
-            descriptor_set_layouts: layout,
-            push_constant_ranges: push_constants,
-            macros,
-        }).unwrap());
-
-        // Load image from disk
-        let mut im = image::open("./output.png").expect("Couldn't load image").to_rgba8();
-        for pixel in im.pixels_mut() {
-            for v in pixel.0.as_mut_slice() {
-                let mut fv = *v as f32 / 255.0;
-                fv = fv.powf(2.2);
-                *v = (fv * 255.0) as u8;
+    fn gui(&mut self, gui: &GuiSystem, context: &egui::Context) {
+        for texture in self.freed_textures.lock().unwrap().drain(..) {
+            gui.free_texture(texture);
+        }
+        self.tab_viewer.as_mut().unwrap().open_status.clone_from(&self.open_status);
+
+        // The canvas was re-created, point the view at the new composite
+        if self.texture_dirty {
+            self.retired_textures.extend(self.texture_id.take());
+            self.texture_id = Some(gui.create_texture(self.composite.as_ref().unwrap()));
+            let tab_viewer = self.tab_viewer.as_mut().unwrap();
+            tab_viewer.texture_id = self.texture_id.unwrap();
+            tab_viewer.texture_size = Vec2::new(self.orig_image.as_ref().unwrap().width as f32, self.orig_image.as_ref().unwrap().height as f32);
+            tab_viewer.export_path = export::default_export_path(&self.image_path).to_string_lossy().into_owned();
+            tab_viewer.allow_overwrite = false;
+            tab_viewer.export_space = self.source_space;
+            tab_viewer.stroke.clear();
+            tab_viewer.selection.reset(self.orig_image.as_ref().unwrap().width, self.orig_image.as_ref().unwrap().height);
+            self.texture_dirty = false;
+
+            if let Some(manifest) = self.pending_manifest.take() {
+                self.apply_manifest(manifest);
             }
         }
-        let width = im.width();
-        let height = im.height();
-
-        // Load image into buffer
-        let mut buf = Buffer::new(
-            &renderer.device,
-            &mut renderer.allocator,
-            MemoryLocation::CpuToGpu,
-            (width * height * 4) as DeviceSize,
-            BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST
-        );
-
-        let mut map = buf.mapped().unwrap();
-        let pixel_data = im.as_bytes();
-        unsafe { std::ptr::copy_nonoverlapping(pixel_data.as_ptr(), map.as_mut_slice().as_mut_ptr(), pixel_data.len()); }
-
-        self.image = Some(Image::new(
-            &renderer.device,
-            &mut renderer.allocator,
-            im.width(),
-            im.height(),
-            ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC,
-        ));
-
-        self.orig_image = Some(Image::new(
-            &renderer.device,
-            &mut renderer.allocator,
-            im.width(),
-            im.height(),
-            ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC,
-        ));
-
-        self.draw_buffer = Some(Image::new(
-            &renderer.device,
-            &mut renderer.allocator,
-            im.width(),
-            im.height(),
-            ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC
-        ));
-
-        self.stencil_buffer = Some(Image::new(
-            &renderer.device,
-            &mut renderer.allocator,
-            im.width(),
-            im.height(),
-            ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC
-        ));
-
-        let mut command_buffer = renderer.create_command_buffer();
-        command_buffer.begin();
-
-        renderer.transition_image(
-            &command_buffer,
-            self.orig_image.as_ref().unwrap().handle(),
-            ImageLayout::UNDEFINED,
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
 
-        renderer.transition_image(
-            &command_buffer,
-            self.draw_buffer.as_ref().unwrap().handle(),
-            ImageLayout::UNDEFINED,
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
+        DockArea::new(&mut self.tree)
+            .style(Style::from_egui(context.style().as_ref()))
+            .show(context, self.tab_viewer.as_mut().unwrap());
+    }
+}
 
-        renderer.transition_image(
-            &command_buffer,
-            self.stencil_buffer.as_ref().unwrap().handle(),
-            ImageLayout::UNDEFINED,
-            ImageLayout::GENERAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
+impl RenderComponent for Editor {
+    fn initialize(&mut self, renderer: &mut Renderer) {
 
-        command_buffer.clear_color_image(
-            self.stencil_buffer.as_ref().unwrap(),
-            ImageLayout::GENERAL,
-            [0.0, 0.0, 0.0, 1.0]
-        );
+        self.brushes.update(renderer);
+        self.compositor = Some(Compositor::new(renderer));
 
-        renderer.transition_image(
-            &command_buffer,
-            self.image.as_ref().unwrap().handle(),
-            ImageLayout::UNDEFINED,
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
-        let regions = [
-            BufferImageCopy::default()
-                .buffer_offset(0)
-                .buffer_row_length(0)
-                .buffer_image_height(height)
-                .image_subresource(vk::ImageSubresourceLayers {
-                    aspect_mask: vk::ImageAspectFlags::COLOR,
-                    mip_level: 0,
-                    base_array_layer: 0,
-                    layer_count: 1,
-                })
-                .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
-                .image_extent(vk::Extent3D { width, height, depth: 1 })
-        ];
-        command_buffer.copy_buffer_to_image(
-            &buf,
-            self.image.as_ref().unwrap(),
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            &regions
-        );
-        command_buffer.copy_buffer_to_image(
-            &buf,
-            self.draw_buffer.as_ref().unwrap(),
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            &regions
-        );
-        command_buffer.copy_buffer_to_image(
-            &buf,
-            self.orig_image.as_ref().unwrap(),
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            &regions
-        );
-        renderer.transition_image(
-            &command_buffer,
-            self.image.as_ref().unwrap().handle(),
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            ImageLayout::GENERAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
-        renderer.transition_image(
-            &command_buffer,
-            self.orig_image.as_ref().unwrap().handle(),
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            ImageLayout::GENERAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
-        renderer.transition_image(
-            &command_buffer,
-            self.draw_buffer.as_ref().unwrap().handle(),
-            ImageLayout::TRANSFER_DST_OPTIMAL,
-            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-            PipelineStageFlags::TRANSFER,
-            PipelineStageFlags::TRANSFER,
-            AccessFlags::TRANSFER_READ,
-            AccessFlags::TRANSFER_WRITE,
-        );
-        command_buffer.end();
-        renderer.submit_single_time_command_buffer(command_buffer);
+        let opened = match self.startup_path.take() {
+            Some(path) => self.open(renderer, &path),
+            None => false,
+        };
+        if !opened {
+            self.load_blank(renderer, BLANK_CANVAS_SIZE, BLANK_CANVAS_SIZE);
+        }
     }
 
     fn render(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, swapchain_image: &ash::vk::Image, swapchain_image_view: &ImageView) {
+        self.release_retired(renderer, command_buffer);
+
+        if let Some(path) = self.tab_viewer.as_mut().unwrap().open_image.take() {
+            if self.open(renderer, &path) {
+                // Recordings, timelapses and animation renders only make sense on the canvas they were started on
+                self.tab_viewer.as_mut().unwrap().stop_recording = true;
+                self.tab_viewer.as_mut().unwrap().timelapse.stop();
+                self.tab_viewer.as_mut().unwrap().animation.stop_render();
+                self.texture_dirty = true;
+                return;
+            }
+        }
 
-        if self.tab_viewer.as_ref().unwrap().reset_image {
-            let width = self.image.as_ref().unwrap().width;
-            let height = self.image.as_ref().unwrap().height;
-            let regions = [
-                ImageCopy::default()
-                    .src_offset(Offset3D { x: 0, y: 0, z: 0 })
-                    .dst_offset(Offset3D { x: 0, y: 0, z: 0 })
-                    .extent(vk::Extent3D { width, height, depth: 1 })
-                    .src_subresource(ImageSubresourceLayers {
-                        aspect_mask: ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    })
-                    .dst_subresource(ImageSubresourceLayers {
-                        aspect_mask: ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    })
-            ];
-            command_buffer.copy_image(
-                self.orig_image.as_ref().unwrap(),
-                ImageLayout::GENERAL,
-                self.image.as_ref().unwrap(),
-                ImageLayout::GENERAL,
-                &regions
-            );
+        // A canvas operation has been applied to the layers read back earlier, re-create the canvas around them
+        let result = self.tab_viewer.as_ref().unwrap().canvas.result.lock().unwrap().take();
+        if let Some((original, layers)) = result {
+            let layers: Vec<(u64, &RgbaImage)> = layers.iter().map(|(id, image)| (*id, image)).collect();
+            self.load_canvas(renderer, &original, &layers);
+            let tab_viewer = self.tab_viewer.as_mut().unwrap();
+            tab_viewer.stop_recording = true;
+            tab_viewer.timelapse.stop();
+            tab_viewer.animation.stop_render();
+            tab_viewer.canvas.reset_size();
+            self.texture_dirty = true;
+            return;
+        }
 
-            renderer.transition_image(
-                &command_buffer,
-                self.draw_buffer.as_ref().unwrap().handle(),
-                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                ImageLayout::TRANSFER_DST_OPTIMAL,
-                PipelineStageFlags::TOP_OF_PIPE,
-                PipelineStageFlags::COMPUTE_SHADER,
-                AccessFlags::NONE,
-                AccessFlags::SHADER_WRITE,
-            );
+        // The eyedropper colour has been read back, the sliders follow it
+        let picked = self.tab_viewer.as_ref().unwrap().pick_result.lock().unwrap().take();
+        if let Some(linear) = picked {
+            let [h, s, l] = color::linear_to_okhsl(linear);
+            let tab_viewer = self.tab_viewer.as_mut().unwrap();
+            // Greys have no hue, keep the one the slider is at
+            tab_viewer.okhsl = [if s > 0.0 { h } else { tab_viewer.okhsl[0] }, s, l];
+        }
 
-            command_buffer.copy_image(
-                self.orig_image.as_ref().unwrap(),
-                ImageLayout::GENERAL,
-                self.draw_buffer.as_ref().unwrap(),
-                ImageLayout::TRANSFER_DST_OPTIMAL,
-                &regions
-            );
+        if let Some(path) = self.tab_viewer.as_mut().unwrap().save_project.take() {
+            self.save_project(renderer, command_buffer, path);
+        }
 
-            renderer.transition_image(
-                &command_buffer,
-                self.draw_buffer.as_ref().unwrap().handle(),
-                ImageLayout::TRANSFER_DST_OPTIMAL,
-                ImageLayout::SHADER_READ_ONLY_OPTIMAL,
-                PipelineStageFlags::COMPUTE_SHADER,
-                PipelineStageFlags::BOTTOM_OF_PIPE,
-                AccessFlags::SHADER_WRITE,
-                AccessFlags::NONE,
-            );
+        self.history.budget_mb = self.tab_viewer.as_ref().unwrap().history_budget_mb;
+
+        // Allocate images for new layers and drop the ones of deleted layers
+        let width = self.orig_image.as_ref().unwrap().width;
+        let height = self.orig_image.as_ref().unwrap().height;
+
+        self.update_recording(width, height);
+
+        if let Some(session) = self.tab_viewer.as_mut().unwrap().timelapse.take_finished() {
+            Self::finish_timelapse(renderer, command_buffer, session);
+        }
+        if let Some(session) = self.tab_viewer.as_mut().unwrap().animation.take_finished() {
+            Self::finish_timelapse(renderer, command_buffer, session);
         }
 
-        if self.tab_viewer.as_ref().unwrap().export_image {
-            let width = self.image.as_ref().unwrap().width;
-            let height = self.image.as_ref().unwrap().height;
-            let mut buf = Buffer::new(
-                &renderer.device,
-                &mut renderer.allocator,
-                MemoryLocation::CpuToGpu,
-                (width * height * 4) as DeviceSize,
-                BufferUsageFlags::TRANSFER_SRC | BufferUsageFlags::TRANSFER_DST
-            );
+        self.brushes.update(renderer);
+        self.brushes.release(renderer, command_buffer);
+        self.tab_viewer.as_mut().unwrap().brush_names = self.brushes.names();
+
+        // The ui describes the frame unless a recording is being replayed
+        let mut frame = self.capture_frame();
+        self.tab_viewer.as_mut().unwrap().brush_param_decls = self.brushes.get(&frame.brush).map(|b| b.params.clone()).unwrap_or_default();
+        self.tab_viewer.as_mut().unwrap().brush_default_blend = self.brushes.get(&frame.brush).map(|b| b.blend).unwrap_or_default();
+        let mut replayed_selection = None;
+        let replaying = match self.player.as_mut().map(|p| p.next()) {
+            Some(Some((replayed, selection))) => match recording::check_layers(&replayed.layers, self.layer_images.keys().copied()) {
+                Ok(()) => {
+                    frame = replayed;
+                    replayed_selection = selection;
+                    true
+                }
+                Err(e) => {
+                    println!("Stopped replaying: {}", e);
+                    self.player = None;
+                    false
+                }
+            },
+            Some(None) => {
+                println!("Replayed {} frames", self.player.take().unwrap().played);
+                false
+            }
+            None => false,
+        };
+        // Keyframed values replace the ui ones while previewing or rendering the animation
+        let animation = &self.tab_viewer.as_ref().unwrap().animation;
+        if let Some(keyframe) = animation.active_frame().and_then(|f| animation.sample(f)) {
+            frame.weights = keyframe.weights;
+            frame.color = keyframe.color;
+            // Without a gradient the ramp starts at the brush colour, which is keyframed
+            let tab_viewer = self.tab_viewer.as_ref().unwrap();
+            let [r, g, b, _] = keyframe.color;
+            frame.gradient = tab_viewer.gradient.active_stops(color::linear_to_okhsl([r, g, b]), tab_viewer.background);
+            frame.params.extend(keyframe.params);
+            if frame.dirty_margin.is_some() {
+                frame.dirty_margin = Some(stroke::footprint(&frame.params, &frame.weights));
+            }
+            // Every animation frame is drawn from the same canvas
+            if animation.is_rendering() {
+                frame.merge = false;
+                frame.reset = false;
+            }
+        }
+        if replaying && !frame.layers.is_empty() {
+            let layers = &mut self.tab_viewer.as_mut().unwrap().layers;
+            if layers.layers != frame.layers || layers.active != frame.active_layer {
+                *layers = LayerStack::from_layers(frame.layers.clone(), frame.active_layer);
+            }
+        }
 
-            let bufferimagecopy = [
-                BufferImageCopy::default()
-                    .buffer_offset(0)
-                    .buffer_row_length(width as u32)
-                    .buffer_image_height(height)
-                    .image_subresource(vk::ImageSubresourceLayers {
-                        aspect_mask: vk::ImageAspectFlags::COLOR,
-                        mip_level: 0,
-                        base_array_layer: 0,
-                        layer_count: 1,
-                    })
-                    .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
-                    .image_extent(vk::Extent3D { width, height, depth: 1 })
-            ];
+        let layer_ids: Vec<u64> = self.tab_viewer.as_ref().unwrap().layers.layers.iter().map(|l| l.id).collect();
+        // Deleted layers may still be in use by the previous frame
+        let deleted: Vec<u64> = self.layer_images.keys().filter(|id| !layer_ids.contains(id)).copied().collect();
+        for id in deleted {
+            self.retired_images.extend(self.layer_images.remove(&id));
+        }
+        for id in layer_ids {
+            if !self.layer_images.contains_key(&id) {
+                let image = create_layer_image(renderer, command_buffer, width, height);
+                self.layer_images.insert(id, image);
+            }
+        }
 
-            command_buffer.copy_image_to_buffer(
-                self.image.as_ref().unwrap(),
-                ImageLayout::GENERAL,
-                &buf,
-                &bufferimagecopy
-            );
+        // Read every layer back for a canvas operation, the result is picked up in a later frame
+        if let Some(op) = self.tab_viewer.as_mut().unwrap().canvas.request.take() {
+            let canvas = &self.tab_viewer.as_ref().unwrap().canvas;
+            let result = canvas.result.clone();
+            let (fill, clear) = (canvas.fill_for(true), canvas.fill_for(false));
+            let bottom = self.tab_viewer.as_ref().unwrap().layers.layers[0].id;
+
+            let mut original = Self::download(renderer, command_buffer, self.orig_image.as_ref().unwrap());
+            let mut layers = vec![];
+            for (id, image) in &self.layer_images {
+                layers.push((*id, Self::download(renderer, command_buffer, image)));
+            }
 
+            renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
+                let read = |buf: &mut Buffer| {
+                    let map = buf.mapped().unwrap();
+                    RgbaImage::from_raw(width, height, Vec::from(map.as_slice())).expect("Failed to map layer buffer")
+                };
+
+                // Only the bottom layer gets filled where the canvas grows
+                let original = op.apply(&read(&mut original), fill);
+                let layers = layers.iter_mut()
+                    .map(|(id, buf)| (*id, op.apply(&read(buf), if *id == bottom { fill } else { clear })))
+                    .collect();
+                *result.lock().unwrap() = Some((original, layers));
+            }));
+        }
+
+        // Run a requested magic wand on the active layer once it has been read back
+        let selection = &mut self.tab_viewer.as_mut().unwrap().selection;
+        selection.resize(width, height);
+        selection.poll();
+        if let Some(seed) = selection.wand_request.take() {
+            let tolerance = selection.tolerance;
+            let result = selection.wand_result.clone();
+            let mut buf = Self::download(renderer, command_buffer, self.active_image());
             renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || {
                 let map = buf.mapped().unwrap();
-                let mut png = RgbaImage::from_raw(
-                    width,
-                    height,
-                    Vec::from(map.as_slice())
-                ).expect("Failed to map png buffer");
-
-                for pixel in png.pixels_mut() {
-                    for v in pixel.0.as_mut_slice() {
-                        let mut fv = *v as f32 / 255.0;
-                        fv = fv.powf(1.0 / 2.2);
-                        *v = (fv * 255.0) as u8;
-                    }
-                }
+                let pixels = RgbaImage::from_raw(width, height, Vec::from(map.as_slice())).expect("Failed to map layer buffer");
+                *result.lock().unwrap() = Some(selection::magic_wand(&pixels, seed, tolerance));
+            }));
+        }
 
-                png.save(Path::new("output.png")).expect("Failed to save image");
+        // Upload a changed selection, the staging buffer lives until the upload has finished
+        let mut upload = self.tab_viewer.as_mut().unwrap().selection.take_upload();
+        if replaying {
+            upload = replayed_selection;
+        }
+        if let Some(mask) = &upload {
+            let staging = Self::upload(renderer, command_buffer, mask, self.selection_mask.as_ref().unwrap());
+            renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || drop(staging)));
+        }
 
-                println!("Saved png image");
-            }));
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(&frame, upload.as_ref()) {
+                println!("Stopped recording: {}", e);
+                self.recorder = None;
+            }
+        }
+
+        if frame.reset {
+            self.tab_viewer.as_mut().unwrap().stroke.clear();
+            let id = self.active_layer_id();
+            self.history.push(renderer, command_buffer, id, &self.layer_images[&id]);
+
+            // Only the bottom layer has an original to go back to, the same one canvas operations
+            // fill, other layers are cleared
+            if id == self.tab_viewer.as_ref().unwrap().layers.layers[0].id {
+                command_buffer.copy_image(
+                    self.orig_image.as_ref().unwrap(),
+                    ImageLayout::GENERAL,
+                    &self.layer_images[&id],
+                    ImageLayout::GENERAL,
+                    &full_copy(width, height)
+                );
+            } else {
+                command_buffer.clear_color_image(
+                    &self.layer_images[&id],
+                    ImageLayout::GENERAL,
+                    [0.0, 0.0, 0.0, 0.0]
+                );
+            }
+        }
+
+        if self.tab_viewer.as_ref().unwrap().export_image {
+            let export_path = PathBuf::from(&self.tab_viewer.as_ref().unwrap().export_path);
+            let allow_overwrite = self.tab_viewer.as_ref().unwrap().allow_overwrite;
+            if !allow_overwrite && export::is_source(&self.image_path, &export_path) {
+                println!("Refusing to overwrite source image {}", export_path.display());
+            } else {
+                self.export(renderer, command_buffer, export_path);
+            }
+        }
+
+        if frame.undo {
+            self.history.undo(&mut self.layer_images);
+        }
+        if frame.redo {
+            self.history.redo(&mut self.layer_images);
         }
+        self.history.release(renderer, command_buffer);
+        let tab_viewer = self.tab_viewer.as_mut().unwrap();
+        tab_viewer.history_len = self.history.len();
+        tab_viewer.can_undo = self.history.can_undo();
+        tab_viewer.can_redo = self.history.can_redo();
 
         // Early exit for any non-drawing operations
         if self.tab_viewer.as_ref().unwrap().compute {
             return;
         }
 
-        if self.tab_viewer.as_ref().unwrap().merge {
+        if frame.merge {
 
             // Clear brush stencil
 
@@ -703,6 +1540,19 @@ impl RenderComponent for Editor {
                 [0.0, 0.0, 0.0, 1.0]
             );
 
+            // The dabs are part of the layer from here on, a stroke that filled them carries on
+            let stroke = &mut self.tab_viewer.as_mut().unwrap().stroke;
+            if stroke.is_full() {
+                stroke.flush();
+            } else {
+                stroke.clear();
+            }
+            frame.dabs.clear();
+
+            // Remember the layer so the merge can be undone
+            let id = self.active_layer_id();
+            self.history.push(renderer, command_buffer, id, &self.layer_images[&id]);
+
             // Copy the draw buffer into the image buffer
 
             renderer.transition_image(
@@ -716,8 +1566,6 @@ impl RenderComponent for Editor {
                 AccessFlags::SHADER_WRITE,
             );
 
-            let width = self.image.as_ref().unwrap().width;
-            let height = self.image.as_ref().unwrap().height;
             let regions = [
                 ImageCopy::default()
                     .src_offset(Offset3D { x: 0, y: 0, z: 0 })
@@ -739,7 +1587,7 @@ impl RenderComponent for Editor {
             command_buffer.copy_image(
                 self.draw_buffer.as_ref().unwrap(),
                 ImageLayout::TRANSFER_SRC_OPTIMAL,
-                self.image.as_ref().unwrap(),
+                &self.layer_images[&id],
                 ImageLayout::GENERAL,
                 &regions
             );
@@ -775,8 +1623,6 @@ impl RenderComponent for Editor {
         );
 
         // Clear the draw image
-        let width = self.image.as_ref().unwrap().width;
-        let height = self.image.as_ref().unwrap().height;
         let regions = [
             ImageCopy::default()
                 .src_offset(Offset3D { x: 0, y: 0, z: 0 })
@@ -796,55 +1642,211 @@ impl RenderComponent for Editor {
                 })
         ];
         command_buffer.copy_image(
-            self.image.as_ref().unwrap(),
+            self.active_image(),
             ImageLayout::GENERAL,
             self.draw_buffer.as_ref().unwrap(),
             ImageLayout::GENERAL,
             &regions
         );
 
-        let binding = renderer.pipeline_store().get(self.pipeline.unwrap());
-        let pipeline = binding.as_ref().unwrap();
-        command_buffer.bind_pipeline(pipeline);
-
-        let rgb = self.tab_viewer.as_ref().unwrap().okhsl.to_srgb();
-        let mut rgba = [rgb.r as f32 / 255.0, rgb.g as f32 / 255.0, rgb.b as f32 / 255.0, 1.0];
-        for i in 0..3 {
-            if (rgba[i] <= 0.04045f32) {
-                rgba[i] =  rgba[i] / 12.92f32;
+        if let Some(brush) = self.brushes.get(&frame.brush) {
+            // Earlier frames may still read their buffers, take a set none of them uses
+            let recycled = self.brush_buffers.lock().unwrap().pop();
+            let mut buffers = recycled.unwrap_or_else(|| BrushBuffers::new(renderer));
+
+            let data = params::std140(&brush.params, &frame.params);
+            let mut map = buffers.params.mapped().unwrap();
+            map.as_mut_slice()[..data.len()].copy_from_slice(&data);
+            drop(map);
+
+            let dabs = &frame.dabs[..frame.dabs.len().min(MAX_DABS)];
+            let mut map = buffers.dabs.mapped().unwrap();
+            map.as_mut_slice()[..dabs.len() * size_of::<Dab>()].copy_from_slice(bytemuck::cast_slice(dabs));
+            drop(map);
+
+            let weights: Vec<GpuWeight> = frame.weights.iter().take(MAX_WEIGHTS).map(WeightHandle::gpu).collect();
+            let mut map = buffers.weights.mapped().unwrap();
+            map.as_mut_slice()[..weights.len() * size_of::<GpuWeight>()].copy_from_slice(bytemuck::cast_slice(&weights));
+            drop(map);
+
+            // Recordings from before gradients existed sample the brush colour everywhere
+            let ramp = if frame.gradient.is_empty() {
+                vec![[frame.color[0], frame.color[1], frame.color[2], 1.0]; RAMP_SIZE]
+            } else {
+                gradient::ramp(&frame.gradient)
+            };
+            let mut map = buffers.gradient.mapped().unwrap();
+            map.as_mut_slice()[..RAMP_SIZE * size_of::<[f32; 4]>()].copy_from_slice(bytemuck::cast_slice(&ramp));
+            drop(map);
+
+            let binding = renderer.pipeline_store().get(brush.pipeline);
+            let pipeline = binding.as_ref().unwrap();
+            command_buffer.bind_pipeline(pipeline);
+
+            // In dirty rectangle mode only the pixels around the dabs can change. Brushes that lay
+            // no dabs draw from the weights and cursor, anywhere on the image, so they get all of it.
+            let (min, max) = if let Some(margin) = frame.dirty_margin {
+                match stroke::bounds(dabs) {
+                    Some(bounds) => {
+                        let bounds = bounds.expand(margin);
+                        let clip = |v: f32, size: u32| (v.max(0.0) as u32).min(size);
+                        (
+                            [clip(bounds.min.x.floor(), width), clip(bounds.min.y.floor(), height)],
+                            [clip(bounds.max.x.ceil() + 1.0, width), clip(bounds.max.y.ceil() + 1.0, height)],
+                        )
+                    }
+                    None => ([0, 0], [width, height]),
+                }
             } else {
-                rgba[i] = ((rgba[i] + 0.055f32) / 1.055f32).powf( 2.4f32);
+                ([0, 0], [width, height])
+            };
+
+            let push_constants = PushConstants {
+                cursor_a: frame.cursor_a.into(),
+                cursor_b: frame.cursor_b.into(),
+                color: frame.color,
+                background: frame.background,
+                weight_a: frame.weights.first().map(|w| w.pos.to_vec2()).unwrap_or(Vec2::ZERO),
+                weight_b: frame.weights.get(1).map(|w| w.pos.to_vec2()).unwrap_or(Vec2::ZERO),
+                tilt: frame.tilt.into(),
+                pressure: frame.pressure,
+                dab_count: dabs.len() as u32,
+                offset: [min[0] as i32, min[1] as i32],
+                weight_count: weights.len() as u32,
+                blend: frame.blend as u32,
+            };
+            command_buffer.push_constants(pipeline, ShaderStageFlags::COMPUTE, 0, &bytemuck::cast_slice(std::slice::from_ref(&push_constants)));
+
+            let bindings = [
+                self.active_image().binding(vk::ImageLayout::GENERAL),
+                self.draw_buffer.as_ref().unwrap().binding(vk::ImageLayout::GENERAL),
+                self.stencil_buffer.as_ref().unwrap().binding(vk::ImageLayout::GENERAL)
+            ];
+            let selection_binding = [self.selection_mask.as_ref().unwrap().binding(vk::ImageLayout::GENERAL)];
+
+            let write_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(0)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&bindings);
+
+            let params_info = [vk::DescriptorBufferInfo::default()
+                .buffer(*buffers.params.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let params_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(PARAMS_BINDING)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&params_info);
+
+            let dabs_info = [vk::DescriptorBufferInfo::default()
+                .buffer(*buffers.dabs.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let dabs_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(DABS_BINDING)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&dabs_info);
+
+            let selection_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(SELECTION_BINDING)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&selection_binding);
+
+            let weights_info = [vk::DescriptorBufferInfo::default()
+                .buffer(*buffers.weights.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let weights_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(WEIGHTS_BINDING)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&weights_info);
+
+            let gradient_info = [vk::DescriptorBufferInfo::default()
+                .buffer(*buffers.gradient.handle())
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let gradient_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(GRADIENT_BINDING)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&gradient_info);
+
+            command_buffer.bind_push_descriptor(
+                pipeline,
+                0,
+                &[write_descriptor_set, params_descriptor_set, dabs_descriptor_set, weights_descriptor_set, selection_descriptor_set, gradient_descriptor_set]
+            );
+            let size = [max[0] - min[0], max[1] - min[1]];
+            if size[0] > 0 && size[1] > 0 {
+                command_buffer.dispatch(size[0].div_ceil(16), size[1].div_ceil(16), 1);
             }
-        }
 
-        let push_constants = PushConstants {
-            cursor_a: self.tab_viewer.as_ref().unwrap().image_pointer_prev,
-            cursor_b: self.tab_viewer.as_ref().unwrap().image_pointer,
-            color: rgba,
-            weight_a: self.tab_viewer.as_ref().unwrap().weight_pos[0].to_vec2(),
-            weight_b: self.tab_viewer.as_ref().unwrap().weight_pos[1].to_vec2(),
-            shader_tool: self.tab_viewer.as_ref().unwrap().shader_tool
-        };
-        command_buffer.push_constants(pipeline, ShaderStageFlags::COMPUTE, 0, &bytemuck::cast_slice(std::slice::from_ref(&push_constants)));
+            drop(binding);
+            let free = self.brush_buffers.clone();
+            renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || free.lock().unwrap().push(buffers)));
 
-        let bindings = [
-            self.image.as_ref().unwrap().binding(vk::ImageLayout::GENERAL),
-            self.draw_buffer.as_ref().unwrap().binding(vk::ImageLayout::GENERAL),
-            self.stencil_buffer.as_ref().unwrap().binding(vk::ImageLayout::GENERAL)
-        ];
+            // A full stroke is merged next frame, once the draw image holds all of its dabs
+            self.tab_viewer.as_mut().unwrap().flush_dabs = !replaying && dabs.len() >= MAX_DABS;
+        }
 
-        let write_descriptor_set = WriteDescriptorSet::default()
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
-            .image_info(&bindings);
+        // Blend the layers, with the brush output standing in for the active layer
+        renderer.transition_image(
+            &command_buffer,
+            self.draw_buffer.as_ref().unwrap().handle(),
+            ImageLayout::GENERAL,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::COMPUTE_SHADER,
+            PipelineStageFlags::COMPUTE_SHADER,
+            AccessFlags::SHADER_WRITE,
+            AccessFlags::SHADER_READ,
+        );
+        renderer.transition_image(
+            &command_buffer,
+            self.composite.as_ref().unwrap().handle(),
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::TOP_OF_PIPE,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::NONE,
+            AccessFlags::TRANSFER_WRITE,
+        );
+        self.compositor.as_ref().unwrap().composite(
+            renderer,
+            command_buffer,
+            self.composite.as_ref().unwrap(),
+            &self.tab_viewer.as_ref().unwrap().layers,
+            &self.layer_images,
+            self.draw_buffer.as_ref().unwrap()
+        );
+
+        // Capture the canvas as shown in the view for the timelapse and the animation
+        if let Some(capture) = self.tab_viewer.as_mut().unwrap().timelapse.capture(frame.merge) {
+            self.capture_composite(renderer, command_buffer, capture);
+        }
+        if let Some(capture) = self.tab_viewer.as_mut().unwrap().animation.capture() {
+            self.capture_composite(renderer, command_buffer, capture);
+        }
+        if let Some(pos) = self.tab_viewer.as_mut().unwrap().pick_request.take() {
+            let tab_viewer = self.tab_viewer.as_ref().unwrap();
+            let (size, result) = (tab_viewer.pick_size, tab_viewer.pick_result.clone());
+            self.pick_composite(renderer, command_buffer, pos, size, result);
+        }
 
-        command_buffer.bind_push_descriptor(
-            pipeline,
-            0,
-            &[write_descriptor_set]
+        renderer.transition_image(
+            &command_buffer,
+            self.composite.as_ref().unwrap().handle(),
+            ImageLayout::GENERAL,
+            ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            PipelineStageFlags::COMPUTE_SHADER,
+            PipelineStageFlags::BOTTOM_OF_PIPE,
+            AccessFlags::SHADER_WRITE,
+            AccessFlags::NONE,
         );
-        command_buffer.dispatch(500, 500, 1 );
 
         renderer.transition_image(
             &command_buffer,
@@ -857,4 +1859,4 @@ impl RenderComponent for Editor {
             AccessFlags::NONE,
         );
     }
-}
\ No newline at end of file
+}
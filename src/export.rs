@@ -0,0 +1,38 @@
+use std::path::{Path, PathBuf};
+use image::{DynamicImage, ImageFormat, ImageResult, Rgba32FImage, RgbaImage};
+use crate::color;
+use crate::color::ColorSpace;
+
+/// File extensions offered in the save dialog
+pub const EXPORT_EXTENSIONS: [&str; 7] = ["png", "jpg", "jpeg", "webp", "tif", "tiff", "exr"];
+
+/// Suggests an export path next to the source image that doesn't overwrite it
+pub fn default_export_path(source: &Path) -> PathBuf {
+    let stem = source.file_stem().and_then(|s| s.to_str()).unwrap_or("output");
+    source.with_file_name(format!("{}_edit.png", stem))
+}
+
+/// Whether writing to `target` would overwrite the image the session was loaded from
+pub fn is_source(source: &Path, target: &Path) -> bool {
+    match (source.canonicalize(), target.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => source == target,
+    }
+}
+
+/// Writes linear rgba8 pixel data read back from the gpu to `path`, encoded for `space`.
+/// The format is picked from the file extension.
+pub fn save_image(path: &Path, linear: RgbaImage, space: ColorSpace) -> ImageResult<()> {
+    let format = ImageFormat::from_path(path)?;
+
+    // OpenEXR stores linear floats, everything else gets encoded
+    if format == ImageFormat::OpenExr {
+        let hdr = Rgba32FImage::from_fn(linear.width(), linear.height(), |x, y| {
+            let p = linear.get_pixel(x, y).0;
+            image::Rgba([p[0] as f32 / 255.0, p[1] as f32 / 255.0, p[2] as f32 / 255.0, p[3] as f32 / 255.0])
+        });
+        return DynamicImage::ImageRgba32F(hdr).save_with_format(path, format);
+    }
+
+    color::save(path, format, color::encode(linear, space), space)
+}
@@ -0,0 +1,122 @@
+use egui::{Button, Color32, Sense, Slider, Stroke, StrokeKind, Vec2};
+use serde::{Deserialize, Serialize};
+use crate::color;
+use crate::color::ColorSpace;
+
+/// Number of colours the ramp brushes sample from is made of
+pub const RAMP_SIZE: usize = 256;
+
+/// A colour on the gradient
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stop {
+    /// Position along the ramp, 0 to 1
+    pub pos: f32,
+    /// Okhsl h, s, l, the same way the brush colour is stored
+    pub color: [f32; 3],
+}
+
+/// Samples the stops into `RAMP_SIZE` linear colours, interpolated in Oklab. Positions before the
+/// first and after the last stop hold their colour.
+pub fn ramp(stops: &[Stop]) -> Vec<[f32; 4]> {
+    let mut stops: Vec<(f32, [f32; 3])> = stops.iter()
+        .map(|s| (s.pos, color::linear_to_oklab(color::okhsl_to_linear(s.color))))
+        .collect();
+    stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    (0..RAMP_SIZE).map(|i| {
+        let t = i as f32 / (RAMP_SIZE - 1) as f32;
+        let next = stops.partition_point(|s| s.0 <= t);
+        let lab = match (next.checked_sub(1).and_then(|i| stops.get(i)), stops.get(next)) {
+            (Some(a), Some(b)) => {
+                let f = (t - a.0) / (b.0 - a.0).max(1e-6);
+                std::array::from_fn(|c| a.1[c] + (b.1[c] - a.1[c]) * f)
+            }
+            (Some(a), None) => a.1,
+            (None, Some(b)) => b.1,
+            (None, None) => [0.0; 3],
+        };
+        let [r, g, b] = color::clip_to_gamut(color::oklab_to_linear(lab));
+        [r, g, b, 1.0]
+    }).collect()
+}
+
+/// Multi stop gradient brushes can sample instead of the foreground colour
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Gradient {
+    /// Without it the ramp runs from the foreground to the background colour
+    pub enabled: bool,
+    pub stops: Vec<Stop>,
+    #[serde(skip)]
+    selected: usize,
+}
+
+impl Default for Gradient {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            stops: vec![Stop { pos: 0.0, color: [0.0, 0.0, 0.0] }, Stop { pos: 1.0, color: [0.0, 0.0, 1.0] }],
+            selected: 0,
+        }
+    }
+}
+
+impl Gradient {
+    /// Stops the brushes get, `foreground` and `background` as okhsl
+    pub fn active_stops(&self, foreground: [f32; 3], background: [f32; 3]) -> Vec<Stop> {
+        if self.enabled && !self.stops.is_empty() {
+            self.stops.clone()
+        } else {
+            vec![Stop { pos: 0.0, color: foreground }, Stop { pos: 1.0, color: background }]
+        }
+    }
+
+    /// `current` is the brush colour as okhsl, new stops and "set" take it
+    pub fn ui(&mut self, ui: &mut egui::Ui, current: [f32; 3]) {
+        ui.checkbox(&mut self.enabled, "gradient");
+        if !self.enabled {
+            return;
+        }
+
+        // Preview of the ramp the brushes sample
+        let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width().min(200.0), 16.0), Sense::hover());
+        let ramp = ramp(&self.stops);
+        let width = rect.width() / RAMP_SIZE as f32;
+        for (i, [r, g, b, _]) in ramp.into_iter().enumerate() {
+            let [r, g, b] = ColorSpace::Srgb.from_linear([r, g, b]).map(|c| (c * 255.0).round() as u8);
+            let min = rect.min + Vec2::new(width * i as f32, 0.0);
+            ui.painter().rect_filled(egui::Rect::from_min_size(min, Vec2::new(width + 0.5, rect.height())), 0, Color32::from_rgb(r, g, b));
+        }
+        ui.painter().rect_stroke(rect, 0, Stroke::new(1.0, Color32::GRAY), StrokeKind::Inside);
+
+        ui.horizontal_wrapped(|ui| {
+            for (i, stop) in self.stops.iter().enumerate() {
+                let (rect, response) = ui.allocate_exact_size(Vec2::splat(18.0), Sense::click());
+                let [r, g, b] = ColorSpace::Srgb.from_linear(color::okhsl_to_linear(stop.color)).map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+                ui.painter().rect_filled(rect, 2, Color32::from_rgb(r, g, b));
+                if i == self.selected {
+                    ui.painter().rect_stroke(rect, 2, Stroke::new(2.0, ui.visuals().strong_text_color()), StrokeKind::Outside);
+                }
+                if response.clicked() {
+                    self.selected = i;
+                }
+            }
+        });
+
+        if let Some(stop) = self.stops.get_mut(self.selected) {
+            ui.add(Slider::new(&mut stop.pos, 0.0..=1.0).text("stop position"));
+        }
+        ui.horizontal(|ui| {
+            if ui.button("add stop").clicked() {
+                self.stops.push(Stop { pos: 0.5, color: current });
+                self.selected = self.stops.len() - 1;
+            }
+            if ui.add_enabled(self.selected < self.stops.len(), Button::new("set")).on_hover_text("give the stop the brush colour").clicked() {
+                self.stops[self.selected].color = current;
+            }
+            if ui.add_enabled(self.stops.len() > 2, Button::new("remove stop")).clicked() {
+                self.stops.remove(self.selected);
+                self.selected = self.selected.min(self.stops.len() - 1);
+            }
+        });
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{HashMap, VecDeque};
+use ash::vk;
+use ash::vk::{AccessFlags, ImageAspectFlags, ImageCopy, ImageLayout, ImageSubresourceLayers, ImageUsageFlags, Offset3D, PipelineStageFlags};
+use cen::graphics::Renderer;
+use cen::vulkan::{CommandBuffer, Image};
+
+/// Undo/redo stack of full gpu snapshots of layer images, keyed by layer id.
+///
+/// Undoing swaps the snapshot with the live image instead of copying it back, so the
+/// previous state stays alive in the redo stack while commands that use it are in flight.
+/// Snapshots that leave the stacks are kept until `release` hands them to a command buffer.
+pub struct History {
+    undo: VecDeque<(u64, Image)>,
+    redo: Vec<(u64, Image)>,
+    retired: Vec<Image>,
+    pub budget_mb: usize,
+}
+
+impl History {
+    pub fn new(budget_mb: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            retired: Vec::new(),
+            budget_mb,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
+    /// Records the current state of layer `id` before it gets modified
+    pub fn push(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer, id: u64, image: &Image) {
+        let width = image.width;
+        let height = image.height;
+
+        let snapshot = Image::new(
+            &renderer.device,
+            &mut renderer.allocator,
+            width,
+            height,
+            ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC,
+        );
+
+        renderer.transition_image(
+            command_buffer,
+            snapshot.handle(),
+            ImageLayout::UNDEFINED,
+            ImageLayout::GENERAL,
+            PipelineStageFlags::TRANSFER,
+            PipelineStageFlags::TRANSFER,
+            AccessFlags::TRANSFER_READ,
+            AccessFlags::TRANSFER_WRITE,
+        );
+
+        command_buffer.copy_image(
+            image,
+            ImageLayout::GENERAL,
+            &snapshot,
+            ImageLayout::GENERAL,
+            &full_copy(width, height)
+        );
+
+        self.undo.push_back((id, snapshot));
+        self.retired.extend(self.redo.drain(..).map(|(_, image)| image));
+
+        // Drop the oldest states once we exceed the memory budget
+        let snapshot_size = width as usize * height as usize * 4;
+        let max_snapshots = (self.budget_mb * 1024 * 1024 / snapshot_size).max(1);
+        while self.undo.len() > max_snapshots {
+            self.retired.extend(self.undo.pop_front().map(|(_, image)| image));
+        }
+
+        self.release(renderer, command_buffer);
+    }
+
+    /// Swaps the last recorded state back into its layer, returns false if there is nothing to undo
+    pub fn undo(&mut self, images: &mut HashMap<u64, Image>) -> bool {
+        match self.undo.pop_back() {
+            Some((id, mut snapshot)) => match images.get_mut(&id) {
+                Some(image) => {
+                    std::mem::swap(image, &mut snapshot);
+                    self.redo.push((id, snapshot));
+                    true
+                }
+                // The layer has been deleted since
+                None => {
+                    self.retired.push(snapshot);
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    /// Swaps the last undone state back into its layer, returns false if there is nothing to redo
+    pub fn redo(&mut self, images: &mut HashMap<u64, Image>) -> bool {
+        match self.redo.pop() {
+            Some((id, mut snapshot)) => match images.get_mut(&id) {
+                Some(image) => {
+                    std::mem::swap(image, &mut snapshot);
+                    self.undo.push_back((id, snapshot));
+                    true
+                }
+                None => {
+                    self.retired.push(snapshot);
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.retired.extend(self.undo.drain(..).chain(self.redo.drain(..)).map(|(_, image)| image));
+    }
+
+    /// Drops the snapshots that left the stacks once `command_buffer` has finished, commands
+    /// recorded before it may still read them
+    pub fn release(&mut self, renderer: &mut Renderer, command_buffer: &mut CommandBuffer) {
+        if self.retired.is_empty() {
+            return;
+        }
+        let retired = std::mem::take(&mut self.retired);
+        renderer.add_command_buffer_callback(command_buffer.clone(), Box::new(move || drop(retired)));
+    }
+}
+
+/// Copy region covering a whole `width` x `height` image
+pub fn full_copy(width: u32, height: u32) -> [ImageCopy; 1] {
+    [
+        ImageCopy::default()
+            .src_offset(Offset3D { x: 0, y: 0, z: 0 })
+            .dst_offset(Offset3D { x: 0, y: 0, z: 0 })
+            .extent(vk::Extent3D { width, height, depth: 1 })
+            .src_subresource(ImageSubresourceLayers {
+                aspect_mask: ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .dst_subresource(ImageSubresourceLayers {
+                aspect_mask: ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+    ]
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use ash::vk;
+use ash::vk::{AccessFlags, DescriptorSetLayoutBinding, DescriptorType, ImageLayout, ImageUsageFlags, PipelineStageFlags, PushConstantRange, ShaderStageFlags, WriteDescriptorSet};
+use bytemuck::{Pod, Zeroable};
+use cen::graphics::pipeline_store::{PipelineConfig, PipelineKey};
+use cen::graphics::Renderer;
+use cen::vulkan::{CommandBuffer, DescriptorSetLayout, Image};
+use egui::{Button, ComboBox, Slider};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Difference,
+    Add,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 5] = [BlendMode::Normal, BlendMode::Multiply, BlendMode::Screen, BlendMode::Difference, BlendMode::Add];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BlendMode::Normal => "normal",
+            BlendMode::Multiply => "multiply",
+            BlendMode::Screen => "screen",
+            BlendMode::Difference => "difference",
+            BlendMode::Add => "add",
+        }
+    }
+}
+
+/// Properties of a single layer, the gpu image lives in the editor under the same id
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Layer {
+    pub id: u64,
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    pub blend: BlendMode,
+}
+
+impl Layer {
+    pub fn new(id: u64, name: String) -> Self {
+        Self {
+            id,
+            name,
+            visible: true,
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+        }
+    }
+}
+
+/// Ordered layer stack, index 0 is the bottom layer
+pub struct LayerStack {
+    pub layers: Vec<Layer>,
+    pub active: usize,
+    next_id: u64,
+}
+
+impl LayerStack {
+    pub fn new() -> Self {
+        Self {
+            layers: vec![Layer::new(0, "background".to_owned())],
+            active: 0,
+            next_id: 1,
+        }
+    }
+
+    /// Restores a saved stack, new layers get ids above the saved ones
+    pub fn from_layers(layers: Vec<Layer>, active: usize) -> Self {
+        let next_id = layers.iter().map(|l| l.id + 1).max().unwrap_or(0);
+        Self {
+            active: active.min(layers.len().saturating_sub(1)),
+            layers,
+            next_id,
+        }
+    }
+
+    pub fn active_id(&self) -> u64 {
+        self.layers[self.active].id
+    }
+
+    pub fn add(&mut self) {
+        let layer = Layer::new(self.next_id, format!("layer {}", self.next_id));
+        self.next_id += 1;
+        self.active += 1;
+        self.layers.insert(self.active, layer);
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("add").clicked() {
+                self.add();
+            }
+            if ui.add_enabled(self.layers.len() > 1, Button::new("delete")).clicked() {
+                self.layers.remove(self.active);
+                self.active = self.active.saturating_sub(1);
+            }
+            if ui.add_enabled(self.active + 1 < self.layers.len(), Button::new("up")).clicked() {
+                self.layers.swap(self.active, self.active + 1);
+                self.active += 1;
+            }
+            if ui.add_enabled(self.active > 0, Button::new("down")).clicked() {
+                self.layers.swap(self.active, self.active - 1);
+                self.active -= 1;
+            }
+        });
+
+        // Top layer first, like every other paint program
+        for i in (0..self.layers.len()).rev() {
+            let layer = &mut self.layers[i];
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut layer.visible, "");
+                if ui.add(Button::new(format!("{}", i)).selected(self.active == i)).clicked() {
+                    self.active = i;
+                }
+                ui.text_edit_singleline(&mut layer.name);
+            });
+            ui.horizontal(|ui| {
+                ui.add(Slider::new(&mut layer.opacity, 0.0..=1.0).show_value(false));
+                ComboBox::from_id_salt(("blend", layer.id))
+                    .selected_text(layer.blend.name())
+                    .show_ui(ui, |ui| {
+                        for mode in BlendMode::ALL {
+                            ui.selectable_value(&mut layer.blend, mode, mode.name());
+                        }
+                    });
+            });
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Pod, Zeroable)]
+struct CompositePushConstants {
+    opacity: f32,
+    blend: u32,
+}
+
+/// Blends the visible layers into a single image for display and export
+pub struct Compositor {
+    pipeline: PipelineKey,
+}
+
+impl Compositor {
+    pub fn new(renderer: &mut Renderer) -> Self {
+        let bindings = [
+            DescriptorSetLayoutBinding::default()
+                .binding(0)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_IMAGE)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+            DescriptorSetLayoutBinding::default()
+                .binding(1)
+                .descriptor_count(1)
+                .descriptor_type(DescriptorType::STORAGE_IMAGE)
+                .stage_flags(ShaderStageFlags::COMPUTE),
+        ];
+
+        let layout = vec![DescriptorSetLayout::new_push_descriptor(
+            &renderer.device,
+            &bindings
+        )];
+
+        let push_constants = vec![PushConstantRange::default()
+            .size(size_of::<CompositePushConstants>() as u32)
+            .stage_flags(ShaderStageFlags::COMPUTE)
+            .offset(0)
+        ];
+
+        let pipeline = renderer.pipeline_store().insert(PipelineConfig {
+            shader_path: "shaders/composite.comp".parse().unwrap(),
+            descriptor_set_layouts: layout,
+            push_constant_ranges: push_constants,
+            macros: HashMap::new(),
+        }).unwrap();
+
+        Self { pipeline }
+    }
+
+    /// Composites `layers` into `target`. The active layer is read from `active_image`
+    /// instead of its own image so unmerged brush output shows up.
+    /// All images are expected in the general layout.
+    pub fn composite(
+        &self,
+        renderer: &mut Renderer,
+        command_buffer: &mut CommandBuffer,
+        target: &Image,
+        stack: &LayerStack,
+        images: &HashMap<u64, Image>,
+        active_image: &Image,
+    ) {
+        command_buffer.clear_color_image(
+            target,
+            ImageLayout::GENERAL,
+            [0.0, 0.0, 0.0, 0.0]
+        );
+
+        let binding = renderer.pipeline_store().get(self.pipeline);
+        let pipeline = binding.as_ref().unwrap();
+
+        for layer in stack.layers.iter().filter(|l| l.visible) {
+            let image = if layer.id == stack.active_id() {
+                active_image
+            } else {
+                match images.get(&layer.id) {
+                    Some(image) => image,
+                    None => continue,
+                }
+            };
+
+            // Wait for the previous layer to be written
+            renderer.transition_image(
+                command_buffer,
+                target.handle(),
+                ImageLayout::GENERAL,
+                ImageLayout::GENERAL,
+                PipelineStageFlags::COMPUTE_SHADER | PipelineStageFlags::TRANSFER,
+                PipelineStageFlags::COMPUTE_SHADER,
+                AccessFlags::SHADER_WRITE | AccessFlags::TRANSFER_WRITE,
+                AccessFlags::SHADER_READ | AccessFlags::SHADER_WRITE,
+            );
+
+            command_buffer.bind_pipeline(pipeline);
+
+            let push_constants = CompositePushConstants {
+                opacity: layer.opacity,
+                blend: layer.blend as u32,
+            };
+            command_buffer.push_constants(pipeline, ShaderStageFlags::COMPUTE, 0, bytemuck::cast_slice(std::slice::from_ref(&push_constants)));
+
+            let bindings = [
+                target.binding(vk::ImageLayout::GENERAL),
+                image.binding(vk::ImageLayout::GENERAL),
+            ];
+
+            let write_descriptor_set = WriteDescriptorSet::default()
+                .dst_binding(0)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&bindings);
+
+            command_buffer.bind_push_descriptor(
+                pipeline,
+                0,
+                &[write_descriptor_set]
+            );
+            command_buffer.dispatch(target.width.div_ceil(16), target.height.div_ceil(16), 1);
+        }
+    }
+}
+
+/// Allocates an image the size of the canvas, cleared to transparent and in the general layout
+pub fn create_layer_image(renderer: &mut Renderer, command_buffer: &mut CommandBuffer, width: u32, height: u32) -> Image {
+    let image = Image::new(
+        &renderer.device,
+        &mut renderer.allocator,
+        width,
+        height,
+        ImageUsageFlags::STORAGE | ImageUsageFlags::SAMPLED | ImageUsageFlags::TRANSFER_DST | ImageUsageFlags::TRANSFER_SRC,
+    );
+
+    renderer.transition_image(
+        command_buffer,
+        image.handle(),
+        ImageLayout::UNDEFINED,
+        ImageLayout::GENERAL,
+        PipelineStageFlags::TRANSFER,
+        PipelineStageFlags::TRANSFER,
+        AccessFlags::TRANSFER_READ,
+        AccessFlags::TRANSFER_WRITE,
+    );
+
+    command_buffer.clear_color_image(
+        &image,
+        ImageLayout::GENERAL,
+        [0.0, 0.0, 0.0, 0.0]
+    );
+
+    image
+}
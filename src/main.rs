@@ -1,5 +1,24 @@
+mod animation;
+mod brushes;
+mod canvas;
+mod color;
 mod editor;
+mod export;
+mod gradient;
+mod history;
+mod layers;
+mod params;
+mod palette;
+mod pen;
+mod picker;
+mod project;
+mod recording;
+mod selection;
+mod stroke;
+mod timelapse;
+mod weights;
 
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use ash::vk::{Image, ImageView};
 use cen::app::App;
@@ -17,9 +36,9 @@ struct Application {
 
 impl Application {
 
-    fn new() -> Application {
+    fn new(image_path: Option<PathBuf>) -> Application {
         Self {
-            editor: Editor::new()
+            editor: Editor::new(image_path)
         }
     }
 }
@@ -47,7 +66,9 @@ fn main() {
     // Initialize .env environment variables
     dotenv().ok();
 
-    let application = Arc::new(Mutex::new(Application::new()));
+    let image_path = std::env::args().nth(1).map(PathBuf::from);
+
+    let application = Arc::new(Mutex::new(Application::new(image_path)));
     App::run(
         AppConfig::default()
             .width(1180)
@@ -0,0 +1,413 @@
+use std::error::Error;
+use std::path::Path;
+use egui::{Button, Color32, Sense, Stroke, StrokeKind, Vec2};
+use serde::{Deserialize, Serialize};
+use crate::color;
+use crate::color::ColorSpace;
+
+/// File extensions palettes can be loaded from and saved to
+pub const PALETTE_EXTENSIONS: [&str; 3] = ["gpl", "ase", "hex"];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Swatch {
+    pub name: String,
+    /// Okhsl h, s, l, the same way the brush colour is stored
+    pub color: [f32; 3],
+}
+
+impl Swatch {
+    pub fn from_srgb(name: String, rgb: [u8; 3]) -> Self {
+        let linear = ColorSpace::Srgb.to_linear(rgb.map(|c| c as f32 / 255.0));
+        Self { name, color: color::linear_to_okhsl(linear) }
+    }
+
+    pub fn srgb(&self) -> [u8; 3] {
+        let srgb = ColorSpace::Srgb.from_linear(color::okhsl_to_linear(self.color));
+        srgb.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+    }
+
+    fn hex(&self) -> String {
+        let [r, g, b] = self.srgb();
+        format!("{:02x}{:02x}{:02x}", r, g, b)
+    }
+}
+
+/// Reads a GIMP `.gpl`, Adobe `.ase` or Lospec `.hex` palette, picked by the extension
+pub fn load(path: &Path) -> Result<Vec<Swatch>, Box<dyn Error>> {
+    match extension(path).as_deref() {
+        Some("gpl") => parse_gpl(&std::fs::read_to_string(path)?),
+        Some("ase") => parse_ase(&std::fs::read(path)?),
+        Some("hex") => parse_hex(&std::fs::read_to_string(path)?),
+        _ => Err(format!("unknown palette format, expected one of {}", PALETTE_EXTENSIONS.join(", ")).into()),
+    }
+}
+
+/// Writes the swatches in the format of the extension, as 8 bit sRGB
+pub fn save(path: &Path, swatches: &[Swatch]) -> Result<(), Box<dyn Error>> {
+    let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("palette");
+    match extension(path).as_deref() {
+        Some("gpl") => std::fs::write(path, write_gpl(name, swatches))?,
+        Some("ase") => std::fs::write(path, write_ase(swatches))?,
+        Some("hex") => std::fs::write(path, write_hex(swatches))?,
+        _ => return Err(format!("unknown palette format, expected one of {}", PALETTE_EXTENSIONS.join(", ")).into()),
+    }
+    Ok(())
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase())
+}
+
+fn parse_gpl(text: &str) -> Result<Vec<Swatch>, Box<dyn Error>> {
+    let mut lines = text.trim_start_matches('\u{feff}').lines();
+    if lines.next().map(|l| l.trim()) != Some("GIMP Palette") {
+        return Err("not a GIMP palette".into());
+    }
+
+    let mut swatches = vec![];
+    for (i, line) in lines.enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let mut channel = || -> Result<u8, Box<dyn Error>> {
+            let part = parts.next().ok_or_else(|| format!("line {}: expected `r g b name`", i + 2))?;
+            Ok(part.parse().map_err(|_| format!("line {}: `{}` is not a channel value from 0 to 255", i + 2, part))?)
+        };
+        let rgb = [channel()?, channel()?, channel()?];
+        let name = parts.collect::<Vec<_>>().join(" ");
+        swatches.push(Swatch::from_srgb(name, rgb));
+    }
+    Ok(swatches)
+}
+
+fn write_gpl(name: &str, swatches: &[Swatch]) -> String {
+    let mut text = format!("GIMP Palette\nName: {}\nColumns: 8\n#\n", name);
+    for swatch in swatches {
+        let [r, g, b] = swatch.srgb();
+        let name = if swatch.name.is_empty() { swatch.hex() } else { swatch.name.clone() };
+        text.push_str(&format!("{:3} {:3} {:3}\t{}\n", r, g, b, name));
+    }
+    text
+}
+
+/// One `rrggbb` per line, Lospec doesn't store names
+fn parse_hex(text: &str) -> Result<Vec<Swatch>, Box<dyn Error>> {
+    let mut swatches = vec![];
+    for (i, line) in text.trim_start_matches('\u{feff}').lines().enumerate() {
+        let hex = line.trim().trim_start_matches('#');
+        if hex.is_empty() {
+            continue;
+        }
+        let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6)
+            .ok_or_else(|| format!("line {}: expected `rrggbb`, got `{}`", i + 1, line.trim()))?;
+        swatches.push(Swatch::from_srgb(String::new(), [16, 8, 0].map(|shift| ((value >> shift) & 0xff) as u8)));
+    }
+    Ok(swatches)
+}
+
+fn write_hex(swatches: &[Swatch]) -> String {
+    swatches.iter().map(|s| s.hex() + "\n").collect()
+}
+
+const ASE_COLOR: u16 = 0x0001;
+
+/// Adobe swatch exchange, big endian blocks of colour entries and groups. Groups are flattened.
+fn parse_ase(data: &[u8]) -> Result<Vec<Swatch>, Box<dyn Error>> {
+    let mut reader = AseReader { data, pos: 0 };
+    if reader.bytes(4)? != b"ASEF" {
+        return Err("not an Adobe swatch exchange file".into());
+    }
+    reader.u16()?;
+    reader.u16()?;
+    let blocks = reader.u32()?;
+
+    let mut swatches = vec![];
+    for _ in 0..blocks {
+        let kind = reader.u16()?;
+        let length = reader.u32()? as usize;
+        let block = reader.bytes(length)?;
+        if kind != ASE_COLOR {
+            continue;
+        }
+
+        let mut block = AseReader { data: block, pos: 0 };
+        let name_length = block.u16()? as usize;
+        let name: Vec<u16> = (0..name_length).map(|_| block.u16()).collect::<Result<_, _>>()?;
+        let name = String::from_utf16_lossy(&name).trim_end_matches('\0').to_owned();
+        let model = block.bytes(4)?;
+        let linear = match model {
+            b"RGB " => ColorSpace::Srgb.to_linear([block.f32()?, block.f32()?, block.f32()?]),
+            b"Gray" => {
+                let v = block.f32()?;
+                ColorSpace::Srgb.to_linear([v, v, v])
+            }
+            b"CMYK" => {
+                let [c, m, y, k] = [block.f32()?, block.f32()?, block.f32()?, block.f32()?];
+                ColorSpace::Srgb.to_linear([c, m, y].map(|v| (1.0 - v) * (1.0 - k)))
+            }
+            b"LAB " => lab_to_linear([block.f32()? * 100.0, block.f32()?, block.f32()?]),
+            _ => return Err(format!("swatch `{}` uses the unknown colour model `{}`", name, String::from_utf8_lossy(model)).into()),
+        };
+        swatches.push(Swatch { name, color: color::linear_to_okhsl(linear.map(|c| c.clamp(0.0, 1.0))) });
+    }
+    Ok(swatches)
+}
+
+fn write_ase(swatches: &[Swatch]) -> Vec<u8> {
+    let mut out = b"ASEF".to_vec();
+    out.extend(1u16.to_be_bytes());
+    out.extend(0u16.to_be_bytes());
+    out.extend((swatches.len() as u32).to_be_bytes());
+
+    for swatch in swatches {
+        let name: Vec<u16> = swatch.name.encode_utf16().chain([0]).collect();
+        let mut block = vec![];
+        block.extend((name.len() as u16).to_be_bytes());
+        block.extend(name.iter().flat_map(|c| c.to_be_bytes()));
+        block.extend(b"RGB ");
+        block.extend(swatch.srgb().iter().flat_map(|c| (*c as f32 / 255.0).to_be_bytes()));
+        // Normal, not a global or spot colour
+        block.extend(2u16.to_be_bytes());
+
+        out.extend(ASE_COLOR.to_be_bytes());
+        out.extend((block.len() as u32).to_be_bytes());
+        out.extend(block);
+    }
+    out
+}
+
+struct AseReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> AseReader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let bytes = self.data.get(self.pos..self.pos + n).ok_or("unexpected end of swatch file")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, Box<dyn Error>> {
+        Ok(f32::from_be_bytes(self.bytes(4)?.try_into()?))
+    }
+}
+
+/// CIE Lab with a D50 white point to linear sRGB, Bradford adapted to D65
+fn lab_to_linear([l, a, b]: [f32; 3]) -> [f32; 3] {
+    let f_y = (l + 16.0) / 116.0;
+    let f_x = f_y + a / 500.0;
+    let f_z = f_y - b / 200.0;
+    let inverse = |f: f32| if f > 6.0 / 29.0 { f * f * f } else { 3.0 * (6.0f32 / 29.0).powi(2) * (f - 4.0 / 29.0) };
+    let xyz = [0.96422 * inverse(f_x), inverse(f_y), 0.82521 * inverse(f_z)];
+
+    let m = [
+        [3.1338561, -1.6168667, -0.4906146],
+        [-0.9787684, 1.9161415, 0.0334540],
+        [0.0719453, -0.2289914, 1.4052427],
+    ];
+    [0, 1, 2].map(|i| m[i][0] * xyz[0] + m[i][1] * xyz[1] + m[i][2] * xyz[2])
+}
+
+/// Saved swatches, in the order they are shown
+pub struct Palette {
+    pub swatches: Vec<Swatch>,
+    selected: Option<usize>,
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        Self { swatches: vec![], selected: None }
+    }
+
+    pub fn set_swatches(&mut self, swatches: Vec<Swatch>) {
+        self.swatches = swatches;
+        self.selected = None;
+    }
+
+    /// `current` is the brush colour as okhsl, returns the colour of a clicked swatch
+    pub fn ui(&mut self, ui: &mut egui::Ui, current: [f32; 3]) -> Option<[f32; 3]> {
+        let mut picked = None;
+
+        ui.horizontal(|ui| {
+            if ui.button("add swatch").clicked() {
+                self.swatches.push(Swatch { name: format!("swatch {}", self.swatches.len() + 1), color: current });
+                self.selected = Some(self.swatches.len() - 1);
+            }
+            if ui.button("load palette").clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("palette", &PALETTE_EXTENSIONS).pick_file() {
+                    match load(&path) {
+                        Ok(swatches) => self.set_swatches(swatches),
+                        Err(e) => println!("Failed to load palette {}: {}", path.display(), e),
+                    }
+                }
+            }
+            if ui.add_enabled(!self.swatches.is_empty(), Button::new("save palette")).clicked() {
+                if let Some(path) = rfd::FileDialog::new().add_filter("palette", &PALETTE_EXTENSIONS).save_file() {
+                    let path = if extension(&path).is_some() { path } else { path.with_extension("gpl") };
+                    if let Err(e) = save(&path, &self.swatches) {
+                        println!("Failed to save palette {}: {}", path.display(), e);
+                    }
+                }
+            }
+        });
+
+        ui.horizontal_wrapped(|ui| {
+            ui.spacing_mut().item_spacing = Vec2::splat(2.0);
+            for (i, swatch) in self.swatches.iter().enumerate() {
+                let (rect, response) = ui.allocate_exact_size(Vec2::splat(20.0), Sense::click());
+                let [r, g, b] = swatch.srgb();
+                ui.painter().rect_filled(rect, 2, Color32::from_rgb(r, g, b));
+                if self.selected == Some(i) {
+                    ui.painter().rect_stroke(rect, 2, Stroke::new(2.0, ui.visuals().strong_text_color()), StrokeKind::Outside);
+                }
+                let response = response.on_hover_text(if swatch.name.is_empty() { swatch.hex() } else { swatch.name.clone() });
+                if response.clicked() {
+                    self.selected = Some(i);
+                    picked = Some(swatch.color);
+                }
+            }
+        });
+
+        // Name, move and delete the selected swatch
+        if let Some(i) = self.selected.filter(|i| *i < self.swatches.len()) {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.swatches[i].name);
+                if ui.add_enabled(i > 0, Button::new("◀")).clicked() {
+                    self.swatches.swap(i, i - 1);
+                    self.selected = Some(i - 1);
+                }
+                if ui.add_enabled(i + 1 < self.swatches.len(), Button::new("▶")).clicked() {
+                    self.swatches.swap(i, i + 1);
+                    self.selected = Some(i + 1);
+                }
+                if ui.button("set").on_hover_text("replace with the brush colour").clicked() {
+                    self.swatches[i].color = current;
+                }
+                if ui.button("delete").clicked() {
+                    self.swatches.remove(i);
+                    self.selected = None;
+                }
+            });
+        }
+
+        picked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Going through okhsl can be off by one step
+    fn assert_colours(swatches: &[Swatch], expected: &[(&str, [u8; 3])]) {
+        assert_eq!(swatches.len(), expected.len());
+        for (swatch, (name, rgb)) in swatches.iter().zip(expected) {
+            assert_eq!(swatch.name, *name);
+            assert!(swatch.srgb().iter().zip(rgb).all(|(a, b)| a.abs_diff(*b) <= 1), "{}: {:?}", name, swatch.srgb());
+        }
+    }
+
+    const GPL: &str = "GIMP Palette\r
+Name: Endesga 8 (edited)\r
+Columns: 4\r
+#\r
+# Exported from Aseprite, then touched up by hand\r
+  0   0   0\tBlack\r
+255 255 255\tSnow White\r
+\r
+ 20  12  28\r
+ 68  36  52    deep   plum  \r
+";
+
+    #[test]
+    fn gimp_palettes() {
+        assert_colours(&parse_gpl(GPL).unwrap(), &[
+            ("Black", [0, 0, 0]),
+            ("Snow White", [255, 255, 255]),
+            ("", [20, 12, 28]),
+            ("deep plum", [68, 36, 52]),
+        ]);
+        // Some editors start the file with a byte order mark
+        assert_eq!(parse_gpl(&format!("\u{feff}{}", GPL)).unwrap().len(), 4);
+
+        assert_eq!(parse_gpl("GIMP Palette\n#\n255 0\n").unwrap_err().to_string(), "line 3: expected `r g b name`");
+        assert_eq!(parse_gpl("GIMP Palette\n255 0 300 Hot\n").unwrap_err().to_string(), "line 2: `300` is not a channel value from 0 to 255");
+        assert!(parse_gpl("JASC-PAL\n0100\n").is_err());
+    }
+
+    #[test]
+    fn lospec_hex_files() {
+        let swatches = parse_hex("\u{feff}FF0000\r\n#00ff88\r\n\r\n  1d2b53  \r\n").unwrap();
+        assert_colours(&swatches, &[("", [255, 0, 0]), ("", [0, 255, 136]), ("", [29, 43, 83])]);
+
+        assert_eq!(parse_hex("ff0000\n\nff00zz\n").unwrap_err().to_string(), "line 3: expected `rrggbb`, got `ff00zz`");
+        assert!(parse_hex("fff\n").is_err());
+    }
+
+    /// A grouped palette with every colour model and swatch type, as Illustrator lays it out
+    const ASE: &[u8] = &[
+        b'A', b'S', b'E', b'F', 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06,
+        // Group start "Warm tones"
+        0xc0, 0x01, 0x00, 0x00, 0x00, 0x18, 0x00, 0x0b, 0x00, 0x57, 0x00, 0x61, 0x00, 0x72, 0x00, 0x6d, 0x00, 0x20, 0x00, 0x74,
+        0x00, 0x6f, 0x00, 0x6e, 0x00, 0x65, 0x00, 0x73, 0x00, 0x00,
+        // "Red", RGB 1 0 0, normal
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x04, 0x00, 0x52, 0x00, 0x65, 0x00, 0x64, 0x00, 0x00, 0x52, 0x47, 0x42, 0x20,
+        0x3f, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,
+        // "Grün", RGB 0 0.8 0.4, spot
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x1e, 0x00, 0x05, 0x00, 0x47, 0x00, 0x72, 0x00, 0xfc, 0x00, 0x6e, 0x00, 0x00, 0x52, 0x47,
+        0x42, 0x20, 0x00, 0x00, 0x00, 0x00, 0x3f, 0x4c, 0xcc, 0xcd, 0x3e, 0xcc, 0xcc, 0xcd, 0x00, 0x01,
+        // Group end
+        0xc0, 0x02, 0x00, 0x00, 0x00, 0x00,
+        // "Ink", Gray 0.2, global
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x14, 0x00, 0x04, 0x00, 0x49, 0x00, 0x6e, 0x00, 0x6b, 0x00, 0x00, 0x47, 0x72, 0x61, 0x79,
+        0x3e, 0x4c, 0xcc, 0xcd, 0x00, 0x00,
+        // "Press cyan", CMYK 1 0 0 0, spot
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x2e, 0x00, 0x0b, 0x00, 0x50, 0x00, 0x72, 0x00, 0x65, 0x00, 0x73, 0x00, 0x73, 0x00, 0x20,
+        0x00, 0x63, 0x00, 0x79, 0x00, 0x61, 0x00, 0x6e, 0x00, 0x00, 0x43, 0x4d, 0x59, 0x4b, 0x3f, 0x80, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+    ];
+
+    #[test]
+    fn adobe_swatch_exchange() {
+        assert_colours(&parse_ase(ASE).unwrap(), &[
+            ("Red", [255, 0, 0]),
+            ("Grün", [0, 204, 102]),
+            ("Ink", [51, 51, 51]),
+            ("Press cyan", [0, 255, 255]),
+        ]);
+
+        // Cut off in the middle of the last swatch
+        assert_eq!(parse_ase(&ASE[..ASE.len() - 5]).unwrap_err().to_string(), "unexpected end of swatch file");
+        assert!(parse_ase(GPL.as_bytes()).is_err());
+        // "Red" in an HSV model nothing writes
+        let mut hsv = ASE.to_vec();
+        hsv[58..62].copy_from_slice(b"HSV ");
+        assert_eq!(parse_ase(&hsv).unwrap_err().to_string(), "swatch `Red` uses the unknown colour model `HSV `");
+    }
+
+    #[test]
+    fn written_palettes_read_back() {
+        let swatches = vec![
+            Swatch::from_srgb("red".to_owned(), [255, 0, 0]),
+            Swatch::from_srgb("sky blue".to_owned(), [135, 206, 235]),
+            Swatch::from_srgb(String::new(), [40, 40, 40]),
+        ];
+        let expected = [("red", [255, 0, 0]), ("sky blue", [135, 206, 235]), ("", [40, 40, 40])];
+        assert_colours(&parse_ase(&write_ase(&swatches)).unwrap(), &expected);
+        // Unnamed swatches are written to GIMP palettes with their hex code as the name
+        assert_colours(&parse_gpl(&write_gpl("test", &swatches)).unwrap(), &[expected[0], expected[1], ("282828", [40, 40, 40])]);
+        let unnamed = expected.map(|(_, rgb)| ("", rgb));
+        assert_colours(&parse_hex(&write_hex(&swatches)).unwrap(), &unnamed);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use egui::Slider;
+use serde::{Deserialize, Serialize};
+use crate::brushes::MAX_PARAMS_SIZE;
+
+/// Marker for parameter declarations in brush sources, e.g.
+///
+/// ```glsl
+/// // @param float range 50.0 0.0 200.0
+/// // @param int count 10 1 32
+/// // @param color tint 1.0 0.5 0.0 1.0
+/// // @param bool invert false
+/// ```
+///
+/// Floats and ints take a default followed by an optional min and max, colours take linear rgba.
+/// The values are available in the shader as `params.<name>`, bools as an int.
+const PARAM_MARKER: &str = "@param";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ParamValue {
+    Float(f32),
+    Int(i32),
+    Color([f32; 4]),
+    Bool(bool),
+}
+
+impl ParamValue {
+    fn same_type(&self, other: &ParamValue) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
+    /// Blends towards `other` by `t`, ints round and bools switch halfway.
+    /// Values of different types don't blend, `self` is kept until `t` reaches 1.
+    pub fn lerp(&self, other: &ParamValue, t: f32) -> ParamValue {
+        match (self, other) {
+            (ParamValue::Float(a), ParamValue::Float(b)) => ParamValue::Float(a + (b - a) * t),
+            (ParamValue::Int(a), ParamValue::Int(b)) => ParamValue::Int((*a as f32 + (b - a) as f32 * t).round() as i32),
+            (ParamValue::Color(a), ParamValue::Color(b)) => ParamValue::Color(std::array::from_fn(|i| a[i] + (b[i] - a[i]) * t)),
+            (ParamValue::Bool(a), ParamValue::Bool(b)) => ParamValue::Bool(if t < 0.5 { *a } else { *b }),
+            _ => if t < 1.0 { *self } else { *other },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParamDecl {
+    pub name: String,
+    pub default: ParamValue,
+    pub min: f32,
+    pub max: f32,
+}
+
+/// Reads the parameter declarations from a brush source
+pub fn parse(source: &str) -> Result<Vec<ParamDecl>, String> {
+    let mut decls = vec![];
+
+    for (i, line) in source.lines().enumerate() {
+        let Some(decl) = line.trim_start().strip_prefix("//").and_then(|l| l.trim_start().strip_prefix(PARAM_MARKER)) else {
+            continue;
+        };
+        let error = |message: &str| format!("line {}: {}", i + 1, message);
+
+        let words: Vec<&str> = decl.split_whitespace().collect();
+        let [kind, name, values @ ..] = words.as_slice() else {
+            return Err(error("expected `@param <type> <name> <default>`"));
+        };
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(error(&format!("invalid parameter name `{}`", name)));
+        }
+        if decls.iter().any(|d: &ParamDecl| d.name == *name) {
+            return Err(error(&format!("parameter `{}` is declared twice", name)));
+        }
+
+        let (default, min, max) = if *kind == "bool" {
+            match values {
+                ["true"] => (ParamValue::Bool(true), 0.0, 1.0),
+                ["false"] => (ParamValue::Bool(false), 0.0, 1.0),
+                _ => return Err(error("expected `true` or `false`")),
+            }
+        } else {
+            let numbers: Vec<f32> = values.iter()
+                .map(|v| v.parse::<f32>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| error("expected numbers"))?;
+
+            // Without a range the slider goes up to twice the default, both ways for negative ones
+            let range = |d: f32| {
+                let extent = d.abs().max(1.0) * 2.0;
+                if d < 0.0 { (-extent, extent) } else { (0.0, extent) }
+            };
+            let (default, min, max) = match (*kind, numbers.as_slice()) {
+                ("float", [d]) => (ParamValue::Float(*d), range(*d).0, range(*d).1),
+                ("float", [d, min, max]) => (ParamValue::Float(*d), *min, *max),
+                ("int", [d]) => (ParamValue::Int(*d as i32), range(*d).0, range(*d).1),
+                ("int", [d, min, max]) => (ParamValue::Int(*d as i32), *min, *max),
+                ("color", [r, g, b, a]) => (ParamValue::Color([*r, *g, *b, *a]), 0.0, 1.0),
+                ("float" | "int" | "color", _) => return Err(error(&format!("wrong number of values for {}", kind))),
+                _ => return Err(error(&format!("unknown parameter type `{}`", kind))),
+            };
+
+            if min > max {
+                return Err(error(&format!("min {} of `{}` is above its max {}", min, name, max)));
+            }
+            if let [d, ..] = numbers.as_slice() {
+                if *kind != "color" && !(min..=max).contains(d) {
+                    return Err(error(&format!("default {} of `{}` is outside {}..={}", d, name, min, max)));
+                }
+            }
+            (default, min, max)
+        };
+
+        decls.push(ParamDecl { name: name.to_string(), default, min, max });
+    }
+
+    let size = std140(&decls, &HashMap::new()).len();
+    if size > MAX_PARAMS_SIZE {
+        return Err(format!("parameters take {} bytes, at most {} fit in the uniform buffer", size, MAX_PARAMS_SIZE));
+    }
+
+    Ok(decls)
+}
+
+/// Std140 alignment of a parameter
+fn alignment(value: &ParamValue) -> usize {
+    match value {
+        ParamValue::Color(_) => 16,
+        _ => 4,
+    }
+}
+
+/// Uniform block declaring the parameters, bound at `binding`
+pub fn glsl_block(decls: &[ParamDecl], binding: u32) -> String {
+    let mut members: Vec<String> = decls.iter().map(|d| {
+        let kind = match d.default {
+            ParamValue::Float(_) => "float",
+            ParamValue::Int(_) | ParamValue::Bool(_) => "int",
+            ParamValue::Color(_) => "vec4",
+        };
+        format!("    {} {};", kind, d.name)
+    }).collect();
+
+    // Blocks can't be empty
+    if members.is_empty() {
+        members.push("    float _unused;".to_owned());
+    }
+
+    format!("layout( binding = {} ) uniform BrushParams\n{{\n{}\n}} params;\n", binding, members.join("\n"))
+}
+
+/// Packs the parameter values in std140 layout, missing values use the defaults
+pub fn std140(decls: &[ParamDecl], values: &HashMap<String, ParamValue>) -> Vec<u8> {
+    let mut data: Vec<u8> = vec![];
+    for decl in decls {
+        let value = values.get(&decl.name)
+            .filter(|v| v.same_type(&decl.default))
+            .unwrap_or(&decl.default);
+
+        data.resize(data.len().next_multiple_of(alignment(value)), 0);
+        match value {
+            ParamValue::Float(v) => data.extend_from_slice(bytemuck::bytes_of(v)),
+            ParamValue::Int(v) => data.extend_from_slice(bytemuck::bytes_of(v)),
+            ParamValue::Bool(v) => data.extend_from_slice(bytemuck::bytes_of(&(*v as i32))),
+            ParamValue::Color(v) => data.extend_from_slice(bytemuck::cast_slice(v)),
+        }
+    }
+    data.resize(data.len().next_multiple_of(16).max(16), 0);
+    data
+}
+
+/// Controls for every declared parameter, values that are left at their default aren't stored
+pub fn ui(ui: &mut egui::Ui, decls: &[ParamDecl], values: &mut HashMap<String, ParamValue>) {
+    for decl in decls {
+        let mut value = values.get(&decl.name)
+            .filter(|v| v.same_type(&decl.default))
+            .copied()
+            .unwrap_or(decl.default);
+
+        ui.horizontal(|ui| {
+            match &mut value {
+                ParamValue::Float(v) => { ui.add(Slider::new(v, decl.min..=decl.max).text(&decl.name)); }
+                ParamValue::Int(v) => { ui.add(Slider::new(v, decl.min as i32..=decl.max as i32).text(&decl.name)); }
+                ParamValue::Color(v) => {
+                    ui.color_edit_button_rgba_unmultiplied(v);
+                    ui.label(&decl.name);
+                }
+                ParamValue::Bool(v) => { ui.checkbox(v, &decl.name); }
+            }
+            if value != decl.default && ui.small_button("↺").clicked() {
+                value = decl.default;
+            }
+        });
+
+        if value == decl.default {
+            values.remove(&decl.name);
+        } else {
+            values.insert(decl.name.clone(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_type() {
+        let source = "
+            #version 450
+            // @param float range 50.0 0.0 200.0
+            //@param int count 10 1 32
+            // @param color tint 1.0 0.5 0.0 1.0
+            // @param bool invert true
+            // not a @param line
+            float range = 50.;
+        ";
+        let decls = parse(source).unwrap();
+        let names: Vec<&str> = decls.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["range", "count", "tint", "invert"]);
+        assert_eq!(decls[0].default, ParamValue::Float(50.0));
+        assert_eq!((decls[0].min, decls[0].max), (0.0, 200.0));
+        assert_eq!(decls[1].default, ParamValue::Int(10));
+        assert_eq!(decls[2].default, ParamValue::Color([1.0, 0.5, 0.0, 1.0]));
+        assert_eq!(decls[3].default, ParamValue::Bool(true));
+    }
+
+    #[test]
+    fn ranges_without_bounds() {
+        let decls = parse("// @param float size 25.0\n// @param float offset -3.0\n// @param int zero 0").unwrap();
+        assert_eq!((decls[0].min, decls[0].max), (0.0, 50.0));
+        assert_eq!((decls[1].min, decls[1].max), (-6.0, 6.0));
+        assert_eq!((decls[2].min, decls[2].max), (0.0, 2.0));
+    }
+
+    #[test]
+    fn rejects_invalid_declarations() {
+        let error = |source: &str| parse(source).unwrap_err();
+        assert_eq!(error("\n// @param float size 1.0 5.0 2.0"), "line 2: min 5 of `size` is above its max 2");
+        assert_eq!(error("// @param int count 40 1 32"), "line 1: default 40 of `count` is outside 1..=32");
+        assert_eq!(error("// @param float a 1\n// @param int a 2"), "line 2: parameter `a` is declared twice");
+        assert!(error("// @param float 2fast 1.0").contains("invalid parameter name"));
+        assert!(error("// @param vec2 offset 1.0 2.0").contains("unknown parameter type"));
+        assert!(error("// @param color tint 1.0 0.5").contains("wrong number of values"));
+        assert!(error("// @param bool invert yes").contains("expected `true` or `false`"));
+        assert!(error("// @param float size big").contains("expected numbers"));
+    }
+
+    #[test]
+    fn parameter_blocks_fit_the_uniform_buffer() {
+        let floats = |n: usize| (0..n).map(|i| format!("// @param float p{} 1.0\n", i)).collect::<String>();
+        assert!(parse(&floats(MAX_PARAMS_SIZE / 4)).is_ok());
+        assert!(parse(&floats(MAX_PARAMS_SIZE / 4 + 1)).unwrap_err().contains("at most 1024"));
+    }
+
+    #[test]
+    fn std140_layout() {
+        let decls = parse("// @param float a 1.0\n// @param color c 0.0 0.0 0.0 1.0\n// @param bool b true").unwrap();
+        let values = HashMap::from([
+            ("a".to_owned(), ParamValue::Float(2.0)),
+            // Values of the wrong type fall back to the default
+            ("b".to_owned(), ParamValue::Float(0.0)),
+        ]);
+        let data = std140(&decls, &values);
+        assert_eq!(data.len(), 48);
+        assert_eq!(bytemuck::cast_slice::<u8, f32>(&data[0..4]), [2.0]);
+        // Colours are aligned to 16 bytes
+        assert_eq!(bytemuck::cast_slice::<u8, f32>(&data[16..32]), [0.0, 0.0, 0.0, 1.0]);
+        assert_eq!(bytemuck::cast_slice::<u8, i32>(&data[32..36]), [1]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use egui::{Event, InputState, Slider, TouchPhase, Vec2};
+use crate::params::{ParamDecl, ParamValue};
+
+/// Pressure mapping target that scales the alpha of the brush colour instead of a parameter
+pub const OPACITY: &str = "opacity";
+
+/// Pen state for the current frame. Without a tablet the pressure is derived from how fast
+/// the pointer moves, slow strokes press harder.
+pub struct Pen {
+    pub pressure: f32,
+    /// Not implemented, egui doesn't report pen tilt so this is always zero
+    pub tilt: Vec2,
+    /// Whether the last pressure came from a pen rather than the pointer velocity
+    pub has_pen: bool,
+    /// Pointer speed in points per second at which the simulated pressure reaches zero
+    pub max_speed: f32,
+}
+
+impl Pen {
+    pub fn new() -> Self {
+        Self {
+            pressure: 1.0,
+            tilt: Vec2::ZERO,
+            has_pen: false,
+            max_speed: 4000.0,
+        }
+    }
+
+    pub fn update(&mut self, input: &InputState) {
+        for event in &input.events {
+            if let Event::Touch { phase, force: Some(force), .. } = event {
+                match phase {
+                    TouchPhase::Start | TouchPhase::Move => {
+                        self.pressure = force.clamp(0.0, 1.0);
+                        self.has_pen = true;
+                    }
+                    TouchPhase::End | TouchPhase::Cancel => self.has_pen = false,
+                }
+            }
+        }
+
+        // Pen pressure holds between events, the simulated one follows the pointer
+        if !self.has_pen {
+            let speed = input.pointer.velocity().length();
+            self.pressure = 1.0 - (speed / self.max_speed).clamp(0.0, 1.0);
+        }
+    }
+}
+
+/// How strongly the pressure scales each target, per target name.
+/// At 0 the pressure is ignored, at 1 no pressure scales the target down to zero.
+pub type PressureMapping = HashMap<String, f32>;
+
+/// Scale factor for a target with mapping `amount` at `pressure`
+pub fn factor(mapping: &PressureMapping, target: &str, pressure: f32) -> f32 {
+    let amount = mapping.get(target).copied().unwrap_or(0.0);
+    1.0 - amount * (1.0 - pressure)
+}
+
+/// Parameter values with the pressure mapping applied to the numeric parameters, kept within
+/// their declared range
+pub fn apply(decls: &[ParamDecl], values: &HashMap<String, ParamValue>, mapping: &PressureMapping, pressure: f32) -> HashMap<String, ParamValue> {
+    let mut values = values.clone();
+    for decl in decls {
+        let f = factor(mapping, &decl.name, pressure);
+        let value = values.get(&decl.name).copied().unwrap_or(decl.default);
+        let scaled = match value {
+            ParamValue::Float(v) => ParamValue::Float((v * f).max(decl.min).min(decl.max)),
+            ParamValue::Int(v) => ParamValue::Int((v as f32 * f).round().max(decl.min.ceil()).min(decl.max.floor()) as i32),
+            other => other,
+        };
+        values.insert(decl.name.clone(), scaled);
+    }
+    values
+}
+
+/// Pressure amount sliders for the opacity and every numeric parameter
+pub fn mapping_ui(ui: &mut egui::Ui, decls: &[ParamDecl], mapping: &mut PressureMapping) {
+    let targets = std::iter::once(OPACITY)
+        .chain(decls.iter()
+            .filter(|d| matches!(d.default, ParamValue::Float(_) | ParamValue::Int(_)))
+            .map(|d| d.name.as_str()));
+
+    for target in targets {
+        let mut amount = mapping.get(target).copied().unwrap_or(0.0);
+        ui.add(Slider::new(&mut amount, 0.0..=1.0).text(format!("pressure → {}", target)));
+        if amount == 0.0 {
+            mapping.remove(target);
+        } else {
+            mapping.insert(target.to_owned(), amount);
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::f32::consts::TAU;
+use std::ops::RangeInclusive;
+use egui::{Color32, ComboBox, DragValue, Mesh, Rect, Sense, Slider, Stroke, StrokeKind, Vec2};
+use crate::color;
+use crate::color::ColorSpace;
+
+/// Colour model the channel sliders edit, all of them edit the same okhsl brush colour
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Okhsl,
+    Oklch,
+    Okhsv,
+    /// sRGB channels from 0 to 255
+    Srgb,
+    /// Linear sRGB channels from 0 to 1, the way brushes get the colour
+    Linear,
+}
+
+impl Model {
+    pub const ALL: [Model; 5] = [Model::Okhsl, Model::Oklch, Model::Okhsv, Model::Srgb, Model::Linear];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Model::Okhsl => "OKHSL",
+            Model::Oklch => "OKLCH",
+            Model::Okhsv => "OKHSV",
+            Model::Srgb => "sRGB",
+            Model::Linear => "linear",
+        }
+    }
+
+    /// Name and largest value of each channel
+    fn channels(&self) -> [(&'static str, f32); 3] {
+        match self {
+            Model::Okhsl => [("H", 1.0), ("S", 1.0), ("L", 1.0)],
+            Model::Oklch => [("L", 1.0), ("C", MAX_CHROMA), ("H", 360.0)],
+            Model::Okhsv => [("H", 1.0), ("S", 1.0), ("V", 1.0)],
+            Model::Srgb => [("R", 255.0), ("G", 255.0), ("B", 255.0)],
+            Model::Linear => [("R", 1.0), ("G", 1.0), ("B", 1.0)],
+        }
+    }
+
+    /// Channels of an okhsl colour, hues are taken from `hsl` so greys keep theirs
+    fn from_okhsl(&self, hsl: [f32; 3]) -> [f32; 3] {
+        let linear = color::okhsl_to_linear(hsl);
+        match self {
+            Model::Okhsl => hsl,
+            Model::Oklch => {
+                let [l, a, b] = color::linear_to_oklab(linear);
+                [l, (a * a + b * b).sqrt(), hsl[0] * 360.0]
+            }
+            Model::Okhsv => {
+                let [_, s, v] = color::linear_to_okhsv(linear);
+                [hsl[0], s, v]
+            }
+            Model::Srgb => ColorSpace::Srgb.from_linear(linear).map(|c| (c.clamp(0.0, 1.0) * 255.0).round()),
+            Model::Linear => linear.map(|c| c.clamp(0.0, 1.0)),
+        }
+    }
+
+    /// Linear sRGB of the channels, OKLCH can end up outside of the gamut
+    fn to_linear(&self, channels: [f32; 3]) -> [f32; 3] {
+        match self {
+            Model::Okhsl => color::okhsl_to_linear(channels),
+            Model::Oklch => {
+                let [l, c, h] = channels;
+                let (sin, cos) = h.to_radians().sin_cos();
+                color::oklab_to_linear([l, c * cos, c * sin])
+            }
+            Model::Okhsv => color::okhsv_to_linear(channels),
+            Model::Srgb => ColorSpace::Srgb.to_linear(channels.map(|c| c / 255.0)),
+            Model::Linear => channels,
+        }
+    }
+
+    /// Hue of the channels as okhsl hue, if the model has one
+    fn hue(&self, channels: [f32; 3]) -> Option<f32> {
+        match self {
+            Model::Okhsl | Model::Okhsv => Some(channels[0]),
+            Model::Oklch => Some((channels[2] / 360.0).rem_euclid(1.0)),
+            Model::Srgb | Model::Linear => None,
+        }
+    }
+}
+
+/// Chroma of the most saturated sRGB colours in OKLCH
+const MAX_CHROMA: f32 = 0.37;
+
+pub fn multi_color_gradient_slider(
+    ui: &mut egui::Ui,
+    value: &mut f32,
+    range: RangeInclusive<f32>,
+    colors: &[Color32]
+) {
+    if colors.is_empty() {
+        // Fallback to regular slider if no colors provided
+        ui.add(egui::Slider::new(value, range));
+        return;
+    }
+
+    let desired_size = egui::vec2(ui.available_width().min(200.0), 24.0);
+    let (rect, _) = ui.allocate_exact_size(desired_size, egui::Sense::hover());
+
+    // Draw multi-color gradient background
+    let painter = ui.painter();
+    let gradient_rect = rect.shrink(2.0);
+
+    if colors.len() == 1 {
+        // Single color - just fill with that color
+        painter.rect_filled(gradient_rect, 2, colors[0]);
+    } else {
+        // Multi-color gradient
+        let segments = colors.len();
+        let segment_width = gradient_rect.width() / segments as f32;
+
+        for i in 0..segments {
+            painter.rect_filled(
+                Rect {
+                    min: gradient_rect.min + Vec2::new(segment_width * i as f32, 0.0),
+                    max: gradient_rect.min + Vec2::new(segment_width * i as f32 + segment_width, gradient_rect.height())},
+                2,
+                colors[i]
+            );
+        }
+    }
+
+
+    // Add border
+    painter.rect_stroke(gradient_rect, egui::CornerRadius::same(4),
+                        egui::Stroke::new(1.0, egui::Color32::GRAY), StrokeKind::Inside);
+
+    ui.scope(|ui| {
+        // Override the style to force minimum size
+        ui.style_mut().spacing.slider_width = rect.width();
+        ui.style_mut().spacing.interact_size.x = rect.width();
+
+        ui.add(Slider::new(value, range).show_value(false));
+    });
+
+    // Place transparent slider on top
+    // let layout = egui::layout::left_to_right(egui::align::left);
+    // ui.allocate_ui_with_layout(rect.size(), layout, |ui| {
+    //     ui.style_mut().visuals.widgets.inactive.bg_fill = egui::color32::transparent;
+    //     ui.style_mut().visuals.widgets.hovered.bg_fill = egui::color32::transparent;
+    //     ui.style_mut().visuals.widgets.active.bg_fill = egui::color32::transparent;
+    //     ui.add_sized(rect.size(), egui::slider::new(value, range).show_value(true));
+    // });
+}
+
+fn to_color32(linear: [f32; 3]) -> Color32 {
+    let [r, g, b] = ColorSpace::Srgb.from_linear(linear).map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    Color32::from_rgb(r, g, b)
+}
+
+/// Channel sliders of the selected model, a hex field, a saturation and lightness square and a hue wheel
+pub struct Picker {
+    pub model: Model,
+    /// Brush colour the channels were last synced with
+    synced: [f32; 3],
+    /// Channels of the current model. Kept between frames so out of gamut OKLCH values and the
+    /// hue of greys survive editing.
+    channels: [f32; 3],
+    hex: String,
+    hex_error: Option<String>,
+}
+
+impl Picker {
+    pub fn new() -> Self {
+        Self {
+            model: Model::Okhsl,
+            synced: [f32::NAN; 3],
+            channels: [0.0; 3],
+            hex: String::new(),
+            hex_error: None,
+        }
+    }
+
+    /// Edits `hsl`, the brush colour as okhsl h, s, l
+    pub fn ui(&mut self, ui: &mut egui::Ui, hsl: &mut [f32; 3]) {
+        let previous = self.model;
+        ComboBox::from_label("colour model")
+            .selected_text(self.model.name())
+            .show_ui(ui, |ui| {
+                for model in Model::ALL {
+                    ui.selectable_value(&mut self.model, model, model.name());
+                }
+            });
+        if self.model != previous || *hsl != self.synced {
+            self.channels = self.model.from_okhsl(*hsl);
+            self.synced = *hsl;
+        }
+
+        // A gradient strip per channel, showing the colour each value of it would give
+        let mut changed = false;
+        for (i, (name, max)) in self.model.channels().into_iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(name);
+                let speed = max / 200.0;
+                changed |= ui.add(DragValue::new(&mut self.channels[i]).range(0.0..=max).speed(speed)).changed();
+            });
+            let colors: Vec<Color32> = (0..20).map(|step| {
+                let mut channels = self.channels;
+                channels[i] = step as f32 / 19.0 * max;
+                let linear = self.model.to_linear(channels);
+                if color::in_gamut(linear) { to_color32(linear) } else { Color32::from_gray(40) }
+            }).collect();
+            let before = self.channels[i];
+            multi_color_gradient_slider(ui, &mut self.channels[i], RangeInclusive::new(0.0, max), &colors);
+            changed |= self.channels[i] != before;
+        }
+
+        let linear = self.model.to_linear(self.channels);
+        if self.model == Model::Oklch && !color::in_gamut(linear) {
+            let [_, a, b] = color::linear_to_oklab(color::clip_to_gamut(linear));
+            ui.colored_label(ui.visuals().warn_fg_color, format!("outside sRGB, chroma clipped to {:.3}", (a * a + b * b).sqrt()));
+        }
+        if changed {
+            *hsl = match self.model {
+                Model::Okhsl => self.channels,
+                model => Self::okhsl(color::clip_to_gamut(linear), model.hue(self.channels).unwrap_or(hsl[0])),
+            };
+            self.synced = *hsl;
+        }
+
+        self.hex_ui(ui, hsl);
+
+        ui.horizontal(|ui| {
+            Self::square(ui, hsl);
+            Self::wheel(ui, hsl);
+        });
+    }
+
+    /// Okhsl of a linear colour, greys get `hue`
+    fn okhsl(linear: [f32; 3], hue: f32) -> [f32; 3] {
+        let [h, s, l] = color::linear_to_okhsl(linear);
+        [if s > 0.0 { h } else { hue }, s.clamp(0.0, 1.0), l.clamp(0.0, 1.0)]
+    }
+
+    /// `#rrggbb` or `oklch(l c h)`, applied when the field loses focus
+    fn hex_ui(&mut self, ui: &mut egui::Ui, hsl: &mut [f32; 3]) {
+        let response = ui.horizontal(|ui| {
+            ui.label("hex");
+            ui.text_edit_singleline(&mut self.hex)
+        }).inner;
+
+        if response.lost_focus() {
+            let text = self.hex.trim();
+            let text = if text.len() == 6 && !text.starts_with('#') { format!("#{}", text) } else { text.to_owned() };
+            match color::parse(&text) {
+                Ok(linear) => {
+                    *hsl = Self::okhsl(color::clip_to_gamut(linear), hsl[0]);
+                    self.hex_error = (!color::in_gamut(linear)).then(|| "outside sRGB, chroma clipped".to_owned());
+                }
+                Err(e) => self.hex_error = Some(e),
+            }
+        }
+        if !response.has_focus() {
+            let [r, g, b] = ColorSpace::Srgb.from_linear(color::okhsl_to_linear(*hsl)).map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            self.hex = format!("#{:02x}{:02x}{:02x}", r, g, b);
+        }
+        if let Some(error) = &self.hex_error {
+            ui.colored_label(ui.visuals().warn_fg_color, error);
+        }
+    }
+
+    /// Okhsl saturation to the right and lightness upwards at the current hue
+    fn square(ui: &mut egui::Ui, hsl: &mut [f32; 3]) {
+        let (rect, response) = ui.allocate_exact_size(Vec2::splat(120.0), Sense::click_and_drag());
+
+        const CELLS: u32 = 16;
+        let mut mesh = Mesh::default();
+        for y in 0..=CELLS {
+            for x in 0..=CELLS {
+                let (s, l) = (x as f32 / CELLS as f32, 1.0 - y as f32 / CELLS as f32);
+                let pos = rect.min + Vec2::new(s, 1.0 - l) * rect.size();
+                mesh.colored_vertex(pos, to_color32(color::okhsl_to_linear([hsl[0], s, l])));
+            }
+        }
+        for y in 0..CELLS {
+            for x in 0..CELLS {
+                let i = y * (CELLS + 1) + x;
+                mesh.add_triangle(i, i + 1, i + CELLS + 1);
+                mesh.add_triangle(i + 1, i + CELLS + 2, i + CELLS + 1);
+            }
+        }
+        ui.painter().add(mesh);
+
+        if let Some(pos) = response.interact_pointer_pos() {
+            let t = ((pos - rect.min) / rect.size()).clamp(Vec2::ZERO, Vec2::splat(1.0));
+            hsl[1] = t.x;
+            hsl[2] = 1.0 - t.y;
+        }
+        let marker = rect.min + Vec2::new(hsl[1], 1.0 - hsl[2]) * rect.size();
+        ui.painter().circle_stroke(marker, 4.0, Stroke::new(1.5, if hsl[2] > 0.6 { Color32::BLACK } else { Color32::WHITE }));
+    }
+
+    /// Ring of okhsl hues, counter clockwise from red on the right
+    fn wheel(ui: &mut egui::Ui, hsl: &mut [f32; 3]) {
+        let (rect, response) = ui.allocate_exact_size(Vec2::splat(120.0), Sense::click_and_drag());
+        let center = rect.center();
+        let (outer, inner) = (rect.width() / 2.0, rect.width() / 2.0 * 0.7);
+        let at = |hue: f32, radius: f32| {
+            let (sin, cos) = (hue * TAU).sin_cos();
+            center + Vec2::new(cos, -sin) * radius
+        };
+
+        const SEGMENTS: u32 = 64;
+        let mut mesh = Mesh::default();
+        for i in 0..=SEGMENTS {
+            let hue = i as f32 / SEGMENTS as f32;
+            let color = to_color32(color::okhsl_to_linear([hue, 1.0, 0.65]));
+            mesh.colored_vertex(at(hue, inner), color);
+            mesh.colored_vertex(at(hue, outer), color);
+        }
+        for i in 0..SEGMENTS {
+            let v = i * 2;
+            mesh.add_triangle(v, v + 1, v + 2);
+            mesh.add_triangle(v + 1, v + 3, v + 2);
+        }
+        ui.painter().add(mesh);
+
+        if let Some(pos) = response.interact_pointer_pos() {
+            let d = pos - center;
+            hsl[0] = ((-d.y).atan2(d.x) / TAU).rem_euclid(1.0);
+        }
+        let marker = at(hsl[0], (inner + outer) / 2.0);
+        ui.painter().circle_stroke(marker, (outer - inner) / 2.0, Stroke::new(2.0, Color32::WHITE));
+        ui.painter().circle_filled(center, inner * 0.6, to_color32(color::okhsl_to_linear(*hsl)));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use egui_dock::DockState;
+use image::{ImageFormat, RgbaImage};
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use crate::animation::Keyframe;
+use crate::brushes::BrushBlend;
+use crate::color::ColorSpace;
+use crate::editor::Tool;
+use crate::gradient::Gradient;
+use crate::layers::Layer;
+use crate::palette::Swatch;
+use crate::params::ParamValue;
+use crate::pen::PressureMapping;
+use crate::weights::WeightHandle;
+
+/// Current version of the project format, bumped whenever old files can't be read as is
+pub const PROJECT_VERSION: u32 = 1;
+pub const PROJECT_EXTENSION: &str = "imlove";
+
+const MANIFEST: &str = "manifest.json";
+const ORIGINAL: &str = "original.png";
+
+/// Everything about a session except the pixels
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Image the session was started from
+    pub source: String,
+    pub layers: Vec<Layer>,
+    pub active_layer: usize,
+    /// Brush colour as okhsl h, s, l
+    pub color: [f32; 3],
+    /// Background colour as okhsl h, s, l
+    #[serde(default)]
+    pub background: Option<[f32; 3]>,
+    /// Colour ramp brushes can sample
+    #[serde(default)]
+    pub gradient: Gradient,
+    /// Name of the selected brush
+    #[serde(default)]
+    pub brush: String,
+    /// Brush parameters that differ from their defaults, per brush
+    #[serde(default)]
+    pub brush_params: HashMap<String, HashMap<String, ParamValue>>,
+    /// Blend modes that differ from what the brushes declare, per brush
+    #[serde(default)]
+    pub brush_blends: HashMap<String, BrushBlend>,
+    /// Pressure mapping amounts per brush and target
+    #[serde(default)]
+    pub pressure_mappings: HashMap<String, PressureMapping>,
+    /// Colour space exports are encoded for
+    #[serde(default)]
+    pub export_space: ColorSpace,
+    pub current_tool: Tool,
+    pub weights: Vec<WeightHandle>,
+    /// Keyframes of the weight, colour and parameter animation
+    #[serde(default)]
+    pub keyframes: Vec<Keyframe>,
+    /// Saved swatches of the palette panel
+    #[serde(default)]
+    pub palette: Vec<Swatch>,
+    pub dock: DockState<String>,
+}
+
+/// A loaded project. Pixel data is linear, the same way it lives on the gpu.
+pub struct Project {
+    pub manifest: Manifest,
+    pub original: RgbaImage,
+    pub layers: Vec<(u64, RgbaImage)>,
+}
+
+pub fn is_project(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == PROJECT_EXTENSION)
+}
+
+fn layer_file(id: u64) -> String {
+    format!("layers/{}.png", id)
+}
+
+/// Writes a zip holding the json manifest, the original image and one png per layer.
+/// Pngs are written as is so the linear data round trips without loss.
+pub fn save(path: &Path, manifest: &Manifest, original: &RgbaImage, layers: &[(u64, RgbaImage)]) -> Result<(), Box<dyn Error>> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+
+    zip.start_file(MANIFEST, SimpleFileOptions::default())?;
+    zip.write_all(serde_json::to_string_pretty(manifest)?.as_bytes())?;
+
+    // Pngs are already compressed
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut write_png = |name: &str, image: &RgbaImage| -> Result<(), Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        image.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+        zip.start_file(name, stored)?;
+        zip.write_all(&bytes)?;
+        Ok(())
+    };
+
+    write_png(ORIGINAL, original)?;
+    for (id, image) in layers {
+        write_png(&layer_file(*id), image)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+pub fn load(path: &Path) -> Result<Project, Box<dyn Error>> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+
+    let mut json = String::new();
+    zip.by_name(MANIFEST)?.read_to_string(&mut json)?;
+    let manifest: Manifest = serde_json::from_str(&json)?;
+    if manifest.version > PROJECT_VERSION {
+        return Err(format!("project version {} is newer than supported version {}", manifest.version, PROJECT_VERSION).into());
+    }
+
+    if manifest.layers.is_empty() {
+        return Err("project has no layers".into());
+    }
+    for (i, layer) in manifest.layers.iter().enumerate() {
+        if manifest.layers[..i].iter().any(|l| l.id == layer.id) {
+            return Err(format!("layer id {} is used twice", layer.id).into());
+        }
+    }
+
+    let mut read_png = |name: &str| -> Result<RgbaImage, Box<dyn Error>> {
+        let mut bytes = Vec::new();
+        zip.by_name(name)?.read_to_end(&mut bytes)?;
+        Ok(image::load_from_memory_with_format(&bytes, ImageFormat::Png)?.to_rgba8())
+    };
+
+    let original = read_png(ORIGINAL)?;
+    let mut layers = Vec::new();
+    for layer in &manifest.layers {
+        let image = read_png(&layer_file(layer.id))
+            .map_err(|e| format!("missing image for layer {} ({}): {}", layer.name, layer_file(layer.id), e))?;
+        if image.dimensions() != original.dimensions() {
+            return Err(format!("layer {} is {:?}, expected {:?}", layer.name, image.dimensions(), original.dimensions()).into());
+        }
+        layers.push((layer.id, image));
+    }
+
+    Ok(Project { manifest, original, layers })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn manifest(layers: Vec<Layer>) -> Manifest {
+        Manifest {
+            version: PROJECT_VERSION,
+            source: "input.png".to_owned(),
+            layers,
+            active_layer: 0,
+            color: [0.1, 0.8, 0.6],
+            background: None,
+            gradient: Gradient::default(),
+            brush: "cone".to_owned(),
+            brush_params: HashMap::new(),
+            brush_blends: HashMap::new(),
+            pressure_mappings: HashMap::new(),
+            export_space: ColorSpace::default(),
+            current_tool: Tool::Draw,
+            weights: vec![],
+            keyframes: vec![],
+            palette: vec![],
+            dock: DockState::new(vec!["view".to_owned()]),
+        }
+    }
+
+    /// Saves a project with the given layers and images and loads it back
+    fn save_and_load(name: &str, manifest: Manifest, layers: &[(u64, RgbaImage)]) -> Result<Project, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("imlove-{}-{}.{}", name, std::process::id(), PROJECT_EXTENSION));
+        let original = RgbaImage::from_pixel(4, 3, Rgba([10, 20, 30, 255]));
+        save(&path, &manifest, &original, layers).unwrap();
+        let project = load(&path);
+        std::fs::remove_file(&path).unwrap();
+        project
+    }
+
+    #[test]
+    fn layers_round_trip() {
+        let background = RgbaImage::from_pixel(4, 3, Rgba([1, 2, 3, 255]));
+        let mut paint = RgbaImage::new(4, 3);
+        paint.put_pixel(2, 1, Rgba([200, 100, 0, 128]));
+        let layers = [(0, background), (5, paint)];
+
+        let project = save_and_load("round-trip", manifest(vec![Layer::new(0, "background".to_owned()), Layer::new(5, "paint".to_owned())]), &layers).unwrap();
+        assert_eq!(project.original.get_pixel(0, 0), &Rgba([10, 20, 30, 255]));
+        assert_eq!(project.layers, layers);
+        assert_eq!(project.manifest.brush, "cone");
+    }
+
+    #[test]
+    fn rejects_projects_without_layers() {
+        let error = save_and_load("no-layers", manifest(vec![]), &[]).err().unwrap();
+        assert_eq!(error.to_string(), "project has no layers");
+    }
+
+    #[test]
+    fn rejects_duplicate_layer_ids() {
+        let layers = vec![Layer::new(3, "a".to_owned()), Layer::new(3, "b".to_owned())];
+        let error = save_and_load("duplicate-ids", manifest(layers), &[(3, RgbaImage::new(4, 3))]).err().unwrap();
+        assert_eq!(error.to_string(), "layer id 3 is used twice");
+    }
+
+    #[test]
+    fn rejects_missing_and_mismatched_layer_images() {
+        let layers = vec![Layer::new(0, "background".to_owned()), Layer::new(1, "paint".to_owned())];
+        let error = save_and_load("missing-image", manifest(layers.clone()), &[(0, RgbaImage::new(4, 3))]).err().unwrap();
+        assert!(error.to_string().starts_with("missing image for layer paint (layers/1.png)"), "{}", error);
+
+        let error = save_and_load("size-mismatch", manifest(layers), &[(0, RgbaImage::new(4, 3)), (1, RgbaImage::new(3, 4))]).err().unwrap();
+        assert_eq!(error.to_string(), "layer paint is (3, 4), expected (4, 3)");
+    }
+
+    #[test]
+    fn rejects_newer_projects() {
+        let mut newer = manifest(vec![Layer::new(0, "background".to_owned())]);
+        newer.version = PROJECT_VERSION + 1;
+        let error = save_and_load("newer", newer, &[(0, RgbaImage::new(4, 3))]).err().unwrap();
+        assert!(error.to_string().contains("newer than supported"));
+    }
+}
@@ -0,0 +1,442 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use crate::brushes::BrushBlend;
+use crate::gradient::Stop;
+use crate::layers::Layer;
+use crate::params::ParamValue;
+use crate::stroke::Dab;
+use crate::weights::WeightHandle;
+
+/// Current version of the recording format, bumped whenever old files can't be replayed as is
+pub const RECORDING_VERSION: u32 = 2;
+pub const RECORDING_EXTENSION: &str = "imrec";
+
+/// Everything `Editor::render` takes from the ui in one frame. Replaying these against the
+/// same image reproduces the session.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frame {
+    pub brush: String,
+    /// Parameter values with the pressure mapping already applied
+    pub params: HashMap<String, ParamValue>,
+    pub blend: BrushBlend,
+    /// Linear brush colour, alpha scaled by the pressure mapping
+    pub color: [f32; 4],
+    /// Linear background colour
+    pub background: [f32; 4],
+    /// Stops of the colour ramp, from the foreground to the background colour without a gradient
+    pub gradient: Vec<Stop>,
+    pub cursor_a: [f32; 2],
+    pub cursor_b: [f32; 2],
+    pub tilt: [f32; 2],
+    pub pressure: f32,
+    pub dabs: Vec<Dab>,
+    pub weights: Vec<WeightHandle>,
+    /// Margin around the dabs in dirty rectangle mode, `None` redraws the whole image
+    pub dirty_margin: Option<f32>,
+    pub layers: Vec<Layer>,
+    pub active_layer: usize,
+    pub merge: bool,
+    pub reset: bool,
+    pub undo: bool,
+    pub redo: bool,
+}
+
+/// A line of a recording. Only what changed since the previous frame is written, `Frame`
+/// ends each frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    Header { version: u32, width: u32, height: u32 },
+    Brush { name: String, params: HashMap<String, ParamValue>, blend: BrushBlend },
+    Color([f32; 4]),
+    Background([f32; 4]),
+    Gradient(Vec<Stop>),
+    Pointer { cursor_a: [f32; 2], cursor_b: [f32; 2], tilt: [f32; 2], pressure: f32 },
+    /// Keeps the first `keep` dabs and appends `dabs`, strokes only ever grow until a merge
+    Dabs { keep: usize, dabs: Vec<Dab> },
+    Weights(Vec<WeightHandle>),
+    DirtyMargin(Option<f32>),
+    Layers { layers: Vec<Layer>, active: usize },
+    /// Selection mask as runs of (coverage, length) in row order
+    Selection { runs: Vec<(u8, u32)> },
+    Merge,
+    Reset,
+    Undo,
+    Redo,
+    Frame,
+}
+
+/// Writes frames to a recording as they are rendered
+pub struct Recorder {
+    out: BufWriter<File>,
+    /// Last written frame, `None` until the first one so it is written in full
+    last: Option<Frame>,
+    pub frames: usize,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write_entry(&mut out, &Entry::Header { version: RECORDING_VERSION, width, height })?;
+        Ok(Self { out, last: None, frames: 0 })
+    }
+
+    /// Writes what changed since the last frame. `selection` is the mask uploaded this frame, if any.
+    pub fn record(&mut self, frame: &Frame, selection: Option<&RgbaImage>) -> Result<(), Box<dyn Error>> {
+        let mut entries = vec![];
+        let last = self.last.as_ref();
+
+        if last.is_none_or(|l| l.brush != frame.brush || l.params != frame.params || l.blend != frame.blend) {
+            entries.push(Entry::Brush { name: frame.brush.clone(), params: frame.params.clone(), blend: frame.blend });
+        }
+        if last.is_none_or(|l| l.color != frame.color) {
+            entries.push(Entry::Color(frame.color));
+        }
+        if last.is_none_or(|l| l.background != frame.background) {
+            entries.push(Entry::Background(frame.background));
+        }
+        if last.is_none_or(|l| l.gradient != frame.gradient) {
+            entries.push(Entry::Gradient(frame.gradient.clone()));
+        }
+        if last.is_none_or(|l| l.cursor_a != frame.cursor_a || l.cursor_b != frame.cursor_b || l.tilt != frame.tilt || l.pressure != frame.pressure) {
+            entries.push(Entry::Pointer { cursor_a: frame.cursor_a, cursor_b: frame.cursor_b, tilt: frame.tilt, pressure: frame.pressure });
+        }
+        let previous = last.map(|l| l.dabs.as_slice()).unwrap_or_default();
+        if last.is_none() || previous != frame.dabs {
+            // Merging or flushing a full stroke starts over, then nothing is shared with the previous frame
+            let keep = if frame.dabs.starts_with(previous) { previous.len() } else { 0 };
+            entries.push(Entry::Dabs { keep, dabs: frame.dabs[keep..].to_vec() });
+        }
+        if last.is_none_or(|l| l.weights != frame.weights) {
+            entries.push(Entry::Weights(frame.weights.clone()));
+        }
+        if last.is_none_or(|l| l.dirty_margin != frame.dirty_margin) {
+            entries.push(Entry::DirtyMargin(frame.dirty_margin));
+        }
+        if last.is_none_or(|l| l.layers != frame.layers || l.active_layer != frame.active_layer) {
+            entries.push(Entry::Layers { layers: frame.layers.clone(), active: frame.active_layer });
+        }
+        if let Some(mask) = selection {
+            entries.push(Entry::Selection { runs: encode_mask(mask) });
+        }
+        if frame.undo { entries.push(Entry::Undo); }
+        if frame.redo { entries.push(Entry::Redo); }
+        if frame.reset { entries.push(Entry::Reset); }
+        if frame.merge { entries.push(Entry::Merge); }
+        entries.push(Entry::Frame);
+
+        for entry in &entries {
+            write_entry(&mut self.out, entry)?;
+        }
+        self.last = Some(frame.clone());
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// A loaded recording, handed out a frame at a time
+pub struct Player {
+    pub width: u32,
+    pub height: u32,
+    entries: Vec<Entry>,
+    next: usize,
+    frame: Frame,
+    pub frames: usize,
+    pub played: usize,
+}
+
+impl Player {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header = lines.next().ok_or("empty recording")??;
+        let (width, height) = match serde_json::from_str(&header).map_err(|e| format!("line 1: {}", e))? {
+            Entry::Header { version, width, height } => {
+                if version > RECORDING_VERSION {
+                    return Err(format!("recording version {} is newer than supported version {}", version, RECORDING_VERSION).into());
+                }
+                // Brushes output colour and coverage since version 2, older recordings draw differently
+                if version < RECORDING_VERSION {
+                    return Err(format!("recording version {} is older than supported version {}", version, RECORDING_VERSION).into());
+                }
+                (width, height)
+            }
+            _ => return Err("recording doesn't start with a header".into()),
+        };
+
+        let mut entries = vec![];
+        // Dabs in the stroke so far, a file that keeps more than were laid would replay another stroke
+        let mut dabs = 0;
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line).map_err(|e| format!("line {}: {}", i + 2, e))?;
+            match &entry {
+                Entry::Dabs { keep, dabs: added } => {
+                    if *keep > dabs {
+                        return Err(format!("line {}: keeps {} dabs of a stroke with {}", i + 2, keep, dabs).into());
+                    }
+                    dabs = keep + added.len();
+                }
+                Entry::Selection { runs } if runs.iter().map(|(_, length)| *length as u64).sum::<u64>() != width as u64 * height as u64 => {
+                    return Err(format!("line {}: selection doesn't cover the {}x{} canvas", i + 2, width, height).into());
+                }
+                _ => {}
+            }
+            entries.push(entry);
+        }
+        let frames = entries.iter().filter(|e| matches!(e, Entry::Frame)).count();
+
+        Ok(Self { width, height, entries, next: 0, frame: Frame::default(), frames, played: 0 })
+    }
+
+    /// The next frame and the selection mask uploaded in it, `None` once the recording is over
+    pub fn next(&mut self) -> Option<(Frame, Option<RgbaImage>)> {
+        let frame = &mut self.frame;
+        frame.merge = false;
+        frame.reset = false;
+        frame.undo = false;
+        frame.redo = false;
+        let mut selection = None;
+
+        while let Some(entry) = self.entries.get(self.next) {
+            self.next += 1;
+            match entry.clone() {
+                Entry::Header { .. } => {}
+                Entry::Brush { name, params, blend } => {
+                    frame.brush = name;
+                    frame.params = params;
+                    frame.blend = blend;
+                }
+                Entry::Color(color) => frame.color = color,
+                Entry::Background(color) => frame.background = color,
+                Entry::Gradient(stops) => frame.gradient = stops,
+                Entry::Pointer { cursor_a, cursor_b, tilt, pressure } => {
+                    frame.cursor_a = cursor_a;
+                    frame.cursor_b = cursor_b;
+                    frame.tilt = tilt;
+                    frame.pressure = pressure;
+                }
+                Entry::Dabs { keep, dabs } => {
+                    frame.dabs.truncate(keep);
+                    frame.dabs.extend(dabs);
+                }
+                Entry::Weights(weights) => frame.weights = weights,
+                Entry::DirtyMargin(margin) => frame.dirty_margin = margin,
+                Entry::Layers { layers, active } => {
+                    frame.layers = layers;
+                    frame.active_layer = active;
+                }
+                Entry::Selection { runs } => selection = Some(decode_mask(&runs, self.width, self.height)),
+                Entry::Merge => frame.merge = true,
+                Entry::Reset => frame.reset = true,
+                Entry::Undo => frame.undo = true,
+                Entry::Redo => frame.redo = true,
+                Entry::Frame => {
+                    self.played += 1;
+                    return Some((frame.clone(), selection));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Checks replayed layers against the ids of the canvas layers. Layers the canvas doesn't have
+/// must have been added while recording, with ids above every layer it started with.
+pub fn check_layers(layers: &[Layer], existing: impl IntoIterator<Item = u64>) -> Result<(), String> {
+    let existing: HashSet<u64> = existing.into_iter().collect();
+    let highest = existing.iter().max().copied();
+    let mut seen = HashSet::new();
+    for layer in layers {
+        if !seen.insert(layer.id) {
+            return Err(format!("layer id {} is used twice", layer.id));
+        }
+        if !existing.contains(&layer.id) && highest.is_some_and(|h| layer.id < h) {
+            return Err(format!("layer {} isn't on the canvas, the recording was made on another project", layer.id));
+        }
+    }
+    Ok(())
+}
+
+fn write_entry(out: &mut impl Write, entry: &Entry) -> Result<(), Box<dyn Error>> {
+    serde_json::to_writer(&mut *out, entry)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn encode_mask(mask: &RgbaImage) -> Vec<(u8, u32)> {
+    let mut runs: Vec<(u8, u32)> = vec![];
+    for pixel in mask.pixels() {
+        match runs.last_mut() {
+            Some((value, length)) if *value == pixel.0[0] => *length += 1,
+            _ => runs.push((pixel.0[0], 1)),
+        }
+    }
+    runs
+}
+
+fn decode_mask(runs: &[(u8, u32)], width: u32, height: u32) -> RgbaImage {
+    let mut mask = RgbaImage::from_pixel(width, height, Rgba([255; 4]));
+    let mut pixels = mask.pixels_mut();
+    for &(value, length) in runs {
+        for pixel in pixels.by_ref().take(length as usize) {
+            *pixel = Rgba([value, value, value, 255]);
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use egui::Pos2;
+
+    fn dab(x: f32) -> Dab {
+        Dab { pos: [x, 10.0], pressure: 0.5, _padding: 0.0 }
+    }
+
+    fn frames() -> Vec<Frame> {
+        let first = Frame {
+            brush: "cone".to_owned(),
+            params: HashMap::from([("radius".to_owned(), ParamValue::Float(12.0))]),
+            color: [1.0, 0.5, 0.0, 1.0],
+            gradient: vec![Stop { pos: 0.0, color: [0.1, 1.0, 0.6] }, Stop { pos: 1.0, color: [0.0, 0.0, 1.0] }],
+            dabs: vec![dab(1.0), dab(2.0)],
+            weights: vec![WeightHandle::new(Pos2::new(3.0, 4.0))],
+            layers: vec![Layer::new(0, "background".to_owned())],
+            ..Frame::default()
+        };
+        // The stroke grows, then is merged and a new one starts on an added layer
+        let mut grown = first.clone();
+        grown.dabs.push(dab(3.0));
+        grown.cursor_a = [5.0, 6.0];
+        grown.dirty_margin = Some(13.0);
+        let mut merged = grown.clone();
+        merged.merge = true;
+        let mut next = grown.clone();
+        next.dabs = vec![dab(7.0)];
+        next.layers.push(Layer::new(1, "layer 1".to_owned()));
+        next.active_layer = 1;
+        let mut undone = next.clone();
+        undone.undo = true;
+        vec![first, grown, merged, next, undone]
+    }
+
+    #[test]
+    fn recording_round_trip() {
+        let path = std::env::temp_dir().join(format!("imlove-recording-{}.{}", std::process::id(), RECORDING_EXTENSION));
+        let mut mask = RgbaImage::from_pixel(4, 2, Rgba([255; 4]));
+        mask.put_pixel(1, 0, Rgba([0, 0, 0, 255]));
+        mask.put_pixel(2, 1, Rgba([128, 128, 128, 255]));
+
+        let mut recorder = Recorder::create(&path, 4, 2).unwrap();
+        for (i, frame) in frames().iter().enumerate() {
+            recorder.record(frame, (i == 1).then_some(&mask)).unwrap();
+        }
+        assert_eq!(recorder.frames, 5);
+        recorder.finish().unwrap();
+
+        let mut player = Player::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((player.width, player.height, player.frames), (4, 2, 5));
+        for (i, frame) in frames().into_iter().enumerate() {
+            let (replayed, selection) = player.next().unwrap();
+            assert_eq!(replayed, frame, "frame {}", i);
+            assert_eq!(selection.as_ref(), (i == 1).then_some(&mask));
+        }
+        assert!(player.next().is_none());
+        assert_eq!(player.played, 5);
+    }
+
+    /// Opens `text` as a recording file
+    fn open(name: &str, text: &str) -> Result<Player, String> {
+        let path = std::env::temp_dir().join(format!("imlove-{}-{}.{}", name, std::process::id(), RECORDING_EXTENSION));
+        std::fs::write(&path, text).unwrap();
+        let result = Player::open(&path).map_err(|e| e.to_string());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    /// Two frames of a stroke on a 4x2 canvas, merged in the second, as the recorder writes them
+    const RECORDING: &str = r#"{"header":{"version":2,"width":4,"height":2}}
+{"brush":{"name":"cone","params":{"radius":{"Float":12.0}},"blend":"Normal"}}
+{"color":[1.0,0.5,0.0,1.0]}
+{"background":[0.0,0.0,0.0,0.0]}
+{"gradient":[]}
+{"pointer":{"cursor_a":[1.0,10.0],"cursor_b":[0.0,0.0],"tilt":[0.0,0.0],"pressure":0.5}}
+{"dabs":{"keep":0,"dabs":[{"pos":[1.0,10.0],"pressure":0.5}]}}
+{"weights":[[3.0,4.0]]}
+{"dirty_margin":null}
+{"layers":{"layers":[{"id":0,"name":"background","visible":true,"opacity":1.0,"blend":"Normal"}],"active":0}}
+{"selection":{"runs":[[255,3],[0,5]]}}
+"frame"
+
+{"dabs":{"keep":1,"dabs":[{"pos":[2.0,10.0],"pressure":0.5}]}}
+"merge"
+"frame"
+"#;
+
+    #[test]
+    fn recordings_replay() {
+        let mut player = open("replay", RECORDING).unwrap();
+        assert_eq!((player.width, player.height, player.frames), (4, 2, 2));
+
+        let (first, selection) = player.next().unwrap();
+        assert_eq!((first.brush.as_str(), first.params["radius"]), ("cone", ParamValue::Float(12.0)));
+        assert_eq!((first.cursor_a, first.pressure), ([1.0, 10.0], 0.5));
+        // Weights from before handles had a strength and radius
+        assert_eq!(first.weights, [WeightHandle::new(Pos2::new(3.0, 4.0))]);
+        assert_eq!(first.layers, [Layer::new(0, "background".to_owned())]);
+        let selection = selection.unwrap();
+        assert_eq!((selection.get_pixel(2, 0).0[0], selection.get_pixel(3, 0).0[0]), (255, 0));
+
+        let (second, selection) = player.next().unwrap();
+        assert_eq!(second.dabs, [dab(1.0), dab(2.0)]);
+        assert!(second.merge && !first.merge);
+        assert!(selection.is_none());
+        assert!(player.next().is_none());
+    }
+
+    #[test]
+    fn broken_recordings_are_rejected() {
+        // Cut off while writing, with the line number counting the blank line
+        let cut = &RECORDING[..RECORDING.find(r#"{"pos":[2.0"#).unwrap() + 9];
+        assert!(open("cut", cut).err().unwrap().starts_with("line 14: EOF while parsing"));
+        let typo = RECORDING.replace(r#"{"color":[1.0,0.5,0.0,1.0]}"#, r#"{"colour":[1.0,0.5,0.0,1.0]}"#);
+        assert!(open("typo", &typo).err().unwrap().starts_with("line 3: unknown variant `colour`"));
+        let keep = RECORDING.replace(r#""keep":1"#, r#""keep":4"#);
+        assert_eq!(open("keep", &keep).err().unwrap(), "line 14: keeps 4 dabs of a stroke with 1");
+        let runs = RECORDING.replace("[0,5]", "[0,4]");
+        assert_eq!(open("runs", &runs).err().unwrap(), "line 11: selection doesn't cover the 4x2 canvas");
+
+        assert!(open("header", "{\"header\":{\"version\":2,\"width\":4").err().unwrap().starts_with("line 1: EOF"));
+        assert_eq!(open("headerless", "\"frame\"\n").err().unwrap(), "recording doesn't start with a header");
+        assert_eq!(open("empty", "").err().unwrap(), "empty recording");
+        let old = RECORDING.replacen(r#""version":2"#, r#""version":1"#, 1);
+        assert_eq!(open("old", &old).err().unwrap(), "recording version 1 is older than supported version 2");
+        let new = RECORDING.replacen(r#""version":2"#, r#""version":3"#, 1);
+        assert_eq!(open("new", &new).err().unwrap(), "recording version 3 is newer than supported version 2");
+    }
+
+    #[test]
+    fn replayed_layers_are_checked() {
+        let layers = |ids: &[u64]| ids.iter().map(|id| Layer::new(*id, String::new())).collect::<Vec<_>>();
+        assert!(check_layers(&layers(&[0, 2]), [0, 2]).is_ok());
+        // Layers added while recording
+        assert!(check_layers(&layers(&[0, 2, 3]), [0, 2]).is_ok());
+        assert!(check_layers(&layers(&[0, 1]), [0, 2]).is_err());
+        assert!(check_layers(&layers(&[0, 3, 3]), [0, 2]).is_err());
+    }
+}
@@ -0,0 +1,544 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use egui::{Button, Color32, ComboBox, Painter, Pos2, Rect, Shape, Slider, Stroke};
+use image::{GrayImage, Luma, Rgba, RgbaImage};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionTool {
+    Rectangle,
+    Ellipse,
+    Lasso,
+    MagicWand,
+}
+
+impl SelectionTool {
+    pub const ALL: [SelectionTool; 4] = [SelectionTool::Rectangle, SelectionTool::Ellipse, SelectionTool::Lasso, SelectionTool::MagicWand];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionTool::Rectangle => "rectangle",
+            SelectionTool::Ellipse => "ellipse",
+            SelectionTool::Lasso => "lasso",
+            SelectionTool::MagicWand => "magic wand",
+        }
+    }
+}
+
+/// How a new shape is combined with the current selection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionMode {
+    Replace,
+    Add,
+    Subtract,
+    Intersect,
+}
+
+impl SelectionMode {
+    pub const ALL: [SelectionMode; 4] = [SelectionMode::Replace, SelectionMode::Add, SelectionMode::Subtract, SelectionMode::Intersect];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SelectionMode::Replace => "replace",
+            SelectionMode::Add => "add",
+            SelectionMode::Subtract => "subtract",
+            SelectionMode::Intersect => "intersect",
+        }
+    }
+}
+
+/// Pixels picked by the magic wand, filled in once the layer has been read back from the gpu
+pub type WandResult = Arc<Mutex<Option<GrayImage>>>;
+
+/// The pixels brushes are allowed to change, as a coverage mask in the size of the canvas.
+/// Without a mask everything is selected.
+pub struct Selection {
+    pub tool: SelectionTool,
+    pub mode: SelectionMode,
+    /// Blur radius applied to new shapes, in pixels
+    pub feather: f32,
+    /// Largest colour distance the magic wand still includes, per channel in [0, 1]
+    pub tolerance: f32,
+    /// Seed pixel the editor still has to run the magic wand from
+    pub wand_request: Option<Pos2>,
+    pub wand_result: WandResult,
+    mask: Option<GrayImage>,
+    width: u32,
+    height: u32,
+    /// The mask changed and has to be uploaded
+    dirty: bool,
+    /// Points of the shape being drawn
+    shape: Vec<Pos2>,
+    /// Edges of the selection, merged into runs, for the marching ants
+    outline: Vec<[Pos2; 2]>,
+}
+
+impl Selection {
+    pub fn new() -> Self {
+        Self {
+            tool: SelectionTool::Rectangle,
+            mode: SelectionMode::Replace,
+            feather: 0.0,
+            tolerance: 0.1,
+            wand_request: None,
+            wand_result: Arc::new(Mutex::new(None)),
+            mask: None,
+            width: 0,
+            height: 0,
+            dirty: true,
+            shape: vec![],
+            outline: vec![],
+        }
+    }
+
+    /// Selects everything of a canvas of `width` by `height`
+    pub fn reset(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.mask = None;
+        self.shape.clear();
+        self.outline.clear();
+        self.wand_request = None;
+        self.dirty = true;
+    }
+
+    /// Resets the selection if the canvas size changed
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if (width, height) != (self.width, self.height) {
+            self.reset(width, height);
+        }
+    }
+
+    pub fn select_all(&mut self) {
+        self.reset(self.width, self.height);
+    }
+
+    pub fn invert(&mut self) {
+        let mask = self.mask.take().unwrap_or_else(|| GrayImage::from_pixel(self.width, self.height, Luma([255])));
+        self.set_mask(GrayImage::from_fn(self.width, self.height, |x, y| Luma([255 - mask.get_pixel(x, y).0[0]])));
+    }
+
+    /// Smallest rectangle around the selected pixels as x, y, width, height.
+    /// `None` when everything or nothing is selected.
+    pub fn bounds(&self) -> Option<[u32; 4]> {
+        let mask = self.mask.as_ref()?;
+        let (mut min, mut max) = ([u32::MAX; 2], [0; 2]);
+        for (x, y, m) in mask.enumerate_pixels() {
+            if m.0[0] > 0 {
+                min = [min[0].min(x), min[1].min(y)];
+                max = [max[0].max(x), max[1].max(y)];
+            }
+        }
+        (min[0] <= max[0]).then(|| [min[0], min[1], max[0] - min[0] + 1, max[1] - min[1] + 1])
+    }
+
+    /// The mask as an rgba image to upload, if it changed since the last call
+    pub fn take_upload(&mut self) -> Option<RgbaImage> {
+        if !std::mem::take(&mut self.dirty) {
+            return None;
+        }
+        Some(match &self.mask {
+            Some(mask) => RgbaImage::from_fn(self.width, self.height, |x, y| {
+                let m = mask.get_pixel(x, y).0[0];
+                Rgba([m, m, m, 255])
+            }),
+            None => RgbaImage::from_pixel(self.width, self.height, Rgba([255; 4])),
+        })
+    }
+
+    pub fn begin(&mut self, pos: Pos2) {
+        if self.tool == SelectionTool::MagicWand {
+            self.wand_request = Some(pos);
+        } else {
+            self.shape = vec![pos];
+        }
+    }
+
+    pub fn extend(&mut self, pos: Pos2) {
+        match self.tool {
+            SelectionTool::Rectangle | SelectionTool::Ellipse if !self.shape.is_empty() => {
+                self.shape.truncate(1);
+                self.shape.push(pos);
+            }
+            SelectionTool::Lasso if self.shape.last().is_some_and(|p| p.distance(pos) >= 1.0) => self.shape.push(pos),
+            _ => {}
+        }
+    }
+
+    pub fn finish(&mut self) {
+        let shape = std::mem::take(&mut self.shape);
+        let region = match (self.tool, shape.as_slice()) {
+            // Nothing was started in the view
+            (_, []) => return,
+            (SelectionTool::Rectangle, [a, b]) => rasterize(self.width, self.height, |p| Rect::from_two_pos(*a, *b).contains(p)),
+            (SelectionTool::Ellipse, [a, b]) => {
+                let rect = Rect::from_two_pos(*a, *b);
+                let radius = rect.size() / 2.0;
+                rasterize(self.width, self.height, |p| ((p - rect.center()) / radius).length_sq() <= 1.0)
+            }
+            (SelectionTool::Lasso, points) if points.len() >= 3 => fill_polygon(self.width, self.height, points),
+            // A click without a drag drops the selection
+            (SelectionTool::Rectangle | SelectionTool::Ellipse | SelectionTool::Lasso, _) => {
+                if self.mode == SelectionMode::Replace {
+                    self.select_all();
+                }
+                return;
+            }
+            (SelectionTool::MagicWand, _) => return,
+        };
+        self.combine(region);
+    }
+
+    /// Picks up a finished magic wand
+    pub fn poll(&mut self) {
+        let result = self.wand_result.lock().unwrap().take();
+        if let Some(region) = result.filter(|r| r.dimensions() == (self.width, self.height)) {
+            self.combine(region);
+        }
+    }
+
+    fn combine(&mut self, mut region: GrayImage) {
+        if self.feather > 0.0 {
+            region = feather(&region, self.feather);
+        }
+
+        let current = self.mask.take();
+        let mask = match (self.mode, current) {
+            (SelectionMode::Replace, _) => region,
+            // Combining with everything selected
+            (SelectionMode::Add, None) => return self.select_all(),
+            (SelectionMode::Intersect, None) => region,
+            (SelectionMode::Subtract, None) => GrayImage::from_fn(self.width, self.height, |x, y| Luma([255 - region.get_pixel(x, y).0[0]])),
+            (mode, Some(current)) => GrayImage::from_fn(self.width, self.height, |x, y| {
+                let a = current.get_pixel(x, y).0[0] as u32;
+                let b = region.get_pixel(x, y).0[0] as u32;
+                Luma([match mode {
+                    SelectionMode::Add => a.max(b),
+                    SelectionMode::Subtract => a * (255 - b) / 255,
+                    _ => a * b / 255,
+                } as u8])
+            }),
+        };
+        self.set_mask(mask);
+    }
+
+    fn set_mask(&mut self, mask: GrayImage) {
+        self.outline = outline(&mask);
+        self.mask = Some(mask);
+        self.dirty = true;
+    }
+
+    /// Draws the outline of the selection as marching ants and the shape being drawn,
+    /// in image coordinates
+    pub fn paint(&self, painter: &Painter, time: f64) {
+        let offset = (time * 8.0) as f32 % 8.0;
+        for segment in &self.outline {
+            painter.line_segment(*segment, Stroke::new(1.0, Color32::WHITE));
+            painter.extend(Shape::dashed_line_with_offset(segment, Stroke::new(1.0, Color32::BLACK), &[4.0], &[4.0], offset));
+        }
+
+        let stroke = Stroke::new(1.0, Color32::from_rgb(255, 200, 0));
+        match (self.tool, self.shape.as_slice()) {
+            (SelectionTool::Rectangle, [a, b]) => { painter.rect_stroke(Rect::from_two_pos(*a, *b), 0, stroke, egui::StrokeKind::Middle); }
+            (SelectionTool::Ellipse, [a, b]) => {
+                let rect = Rect::from_two_pos(*a, *b);
+                painter.add(Shape::ellipse_stroke(rect.center(), rect.size() / 2.0, stroke));
+            }
+            (SelectionTool::Lasso, points) if points.len() >= 2 => { painter.add(Shape::closed_line(points.to_vec(), stroke)); }
+            _ => {}
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ComboBox::from_label("selection")
+            .selected_text(self.tool.name())
+            .show_ui(ui, |ui| {
+                for tool in SelectionTool::ALL {
+                    ui.selectable_value(&mut self.tool, tool, tool.name());
+                }
+            });
+        ComboBox::from_label("mode")
+            .selected_text(self.mode.name())
+            .show_ui(ui, |ui| {
+                for mode in SelectionMode::ALL {
+                    ui.selectable_value(&mut self.mode, mode, mode.name());
+                }
+            });
+        ui.add(Slider::new(&mut self.feather, 0.0..=100.0).text("feather"));
+        if self.tool == SelectionTool::MagicWand {
+            ui.add(Slider::new(&mut self.tolerance, 0.0..=1.0).text("tolerance"));
+        }
+        ui.horizontal(|ui| {
+            if ui.add_enabled(self.mask.is_some(), Button::new("select all")).clicked() {
+                self.select_all();
+            }
+            if ui.button("invert").clicked() {
+                self.invert();
+            }
+        });
+    }
+}
+
+fn rasterize(width: u32, height: u32, inside: impl Fn(Pos2) -> bool) -> GrayImage {
+    GrayImage::from_fn(width, height, |x, y| Luma([if inside(Pos2::new(x as f32 + 0.5, y as f32 + 0.5)) { 255 } else { 0 }]))
+}
+
+/// Even-odd scanline fill through the pixel centres
+fn fill_polygon(width: u32, height: u32, points: &[Pos2]) -> GrayImage {
+    let mut mask = GrayImage::new(width, height);
+    let mut crossings = vec![];
+    for y in 0..height {
+        let cy = y as f32 + 0.5;
+        crossings.clear();
+        for (i, a) in points.iter().enumerate() {
+            let b = points[(i + 1) % points.len()];
+            if (a.y <= cy) != (b.y <= cy) {
+                crossings.push(a.x + (cy - a.y) / (b.y - a.y) * (b.x - a.x));
+            }
+        }
+        crossings.sort_by(f32::total_cmp);
+        for span in crossings.chunks_exact(2) {
+            let start = (span[0] - 0.5).ceil().max(0.0) as u32;
+            let end = ((span[1] - 0.5).floor() + 1.0).clamp(0.0, width as f32) as u32;
+            for x in start..end {
+                mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+    }
+    mask
+}
+
+/// Approximates a gaussian blur of `radius` with three box blurs
+fn feather(mask: &GrayImage, radius: f32) -> GrayImage {
+    let (width, height) = mask.dimensions();
+    let mut values: Vec<f32> = mask.pixels().map(|p| p.0[0] as f32).collect();
+    let r = (radius / 3.0).round().max(1.0) as usize;
+    for _ in 0..3 {
+        box_blur(&mut values, width as usize, height as usize, r, 1, width as usize);
+        box_blur(&mut values, height as usize, width as usize, r, width as usize, 1);
+    }
+    GrayImage::from_fn(width, height, |x, y| Luma([values[(y * width + x) as usize].round() as u8]))
+}
+
+/// Running sum blur along `len` elements spaced `step` apart, for `lines` lines spaced `stride` apart.
+/// Edges are clamped.
+fn box_blur(values: &mut [f32], len: usize, lines: usize, r: usize, step: usize, stride: usize) {
+    let mut line = vec![0.0; len];
+    let window = (2 * r + 1) as f32;
+    for l in 0..lines {
+        let at = |i: isize| l * stride + (i.clamp(0, len as isize - 1) as usize) * step;
+        let mut sum: f32 = (-(r as isize)..=r as isize).map(|i| values[at(i)]).sum();
+        for (i, out) in line.iter_mut().enumerate() {
+            *out = sum / window;
+            sum += values[at(i as isize + r as isize + 1)] - values[at(i as isize - r as isize)];
+        }
+        for (i, v) in line.iter().enumerate() {
+            values[l * stride + i * step] = *v;
+        }
+    }
+}
+
+/// Boundary between selected and unselected pixels, with horizontal and vertical runs merged
+fn outline(mask: &GrayImage) -> Vec<[Pos2; 2]> {
+    let (width, height) = mask.dimensions();
+    let inside = |x: i64, y: i64| x >= 0 && y >= 0 && x < width as i64 && y < height as i64 && mask.get_pixel(x as u32, y as u32).0[0] >= 128;
+    let mut segments = vec![];
+
+    // Horizontal edges lie between rows y - 1 and y
+    for y in 0..=height as i64 {
+        let mut start = None;
+        for x in 0..=width as i64 {
+            let edge = x < width as i64 && inside(x, y - 1) != inside(x, y);
+            match (edge, start) {
+                (true, None) => start = Some(x),
+                (false, Some(s)) => {
+                    segments.push([Pos2::new(s as f32, y as f32), Pos2::new(x as f32, y as f32)]);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for x in 0..=width as i64 {
+        let mut start = None;
+        for y in 0..=height as i64 {
+            let edge = y < height as i64 && inside(x - 1, y) != inside(x, y);
+            match (edge, start) {
+                (true, None) => start = Some(y),
+                (false, Some(s)) => {
+                    segments.push([Pos2::new(x as f32, s as f32), Pos2::new(x as f32, y as f32)]);
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    segments
+}
+
+/// Pixels connected to `seed` whose colour is within `tolerance` of it
+pub fn magic_wand(pixels: &RgbaImage, seed: Pos2, tolerance: f32) -> GrayImage {
+    let (width, height) = pixels.dimensions();
+    let mut mask = GrayImage::new(width, height);
+    if seed.x < 0.0 || seed.y < 0.0 || seed.x >= width as f32 || seed.y >= height as f32 {
+        return mask;
+    }
+
+    let seed = (seed.x as u32, seed.y as u32);
+    let reference = pixels.get_pixel(seed.0, seed.1).0;
+    let limit = (tolerance * 255.0).round() as i32;
+    let similar = |x: u32, y: u32| pixels.get_pixel(x, y).0.iter().zip(reference).all(|(a, b)| (*a as i32 - b as i32).abs() <= limit);
+
+    let mut queue = VecDeque::from([seed]);
+    mask.put_pixel(seed.0, seed.1, Luma([255]));
+    while let Some((x, y)) = queue.pop_front() {
+        let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)];
+        for (nx, ny) in neighbours {
+            if nx < width && ny < height && mask.get_pixel(nx, ny).0[0] == 0 && similar(nx, ny) {
+                mask.put_pixel(nx, ny, Luma([255]));
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selection(width: u32, height: u32) -> Selection {
+        let mut selection = Selection::new();
+        selection.reset(width, height);
+        selection
+    }
+
+    fn drag(selection: &mut Selection, mode: SelectionMode, points: &[[f32; 2]]) {
+        selection.mode = mode;
+        selection.begin(Pos2::from(points[0]));
+        for p in &points[1..] {
+            selection.extend(Pos2::from(*p));
+        }
+        selection.finish();
+    }
+
+    fn value(selection: &Selection, x: u32, y: u32) -> u8 {
+        selection.mask.as_ref().map_or(255, |m| m.get_pixel(x, y).0[0])
+    }
+
+    #[test]
+    fn rectangles_cover_pixel_centres() {
+        let mut selection = selection(8, 8);
+        drag(&mut selection, SelectionMode::Replace, &[[1.2, 1.0], [4.0, 3.4]]);
+        assert_eq!(selection.bounds(), Some([1, 1, 3, 2]));
+
+        // A click without a drag selects everything again
+        drag(&mut selection, SelectionMode::Replace, &[[2.0, 2.0]]);
+        assert!(selection.mask.is_none());
+        assert_eq!(selection.bounds(), None);
+    }
+
+    #[test]
+    fn combining_shapes() {
+        let mut selection = selection(8, 4);
+        drag(&mut selection, SelectionMode::Replace, &[[0.0, 0.0], [4.0, 4.0]]);
+        drag(&mut selection, SelectionMode::Add, &[[4.0, 0.0], [8.0, 2.0]]);
+        assert_eq!(selection.bounds(), Some([0, 0, 8, 4]));
+        assert_eq!(value(&selection, 6, 3), 0);
+
+        drag(&mut selection, SelectionMode::Subtract, &[[2.0, 0.0], [5.0, 4.0]]);
+        assert_eq!([value(&selection, 1, 1), value(&selection, 3, 1), value(&selection, 6, 1)], [255, 0, 255]);
+
+        drag(&mut selection, SelectionMode::Intersect, &[[0.0, 1.0], [8.0, 4.0]]);
+        assert_eq!(selection.bounds(), Some([0, 1, 8, 3]));
+        assert_eq!([value(&selection, 1, 0), value(&selection, 1, 2), value(&selection, 6, 1), value(&selection, 6, 2)], [0, 255, 255, 0]);
+    }
+
+    #[test]
+    fn combining_with_everything_selected() {
+        let mut selection = selection(4, 4);
+        drag(&mut selection, SelectionMode::Add, &[[0.0, 0.0], [2.0, 2.0]]);
+        assert!(selection.mask.is_none());
+
+        drag(&mut selection, SelectionMode::Subtract, &[[0.0, 0.0], [2.0, 2.0]]);
+        assert_eq!([value(&selection, 1, 1), value(&selection, 3, 3)], [0, 255]);
+
+        selection.select_all();
+        drag(&mut selection, SelectionMode::Intersect, &[[0.0, 0.0], [2.0, 2.0]]);
+        assert_eq!(selection.bounds(), Some([0, 0, 2, 2]));
+
+        selection.invert();
+        assert_eq!([value(&selection, 1, 1), value(&selection, 3, 3)], [0, 255]);
+    }
+
+    #[test]
+    fn lasso_fills_even_odd() {
+        let mut selection = selection(8, 8);
+        selection.tool = SelectionTool::Lasso;
+        drag(&mut selection, SelectionMode::Replace, &[[1.0, 1.0], [5.0, 1.0], [5.0, 4.0], [1.0, 4.0]]);
+        let square = selection.mask.clone().unwrap();
+        selection.tool = SelectionTool::Rectangle;
+        drag(&mut selection, SelectionMode::Replace, &[[1.0, 1.0], [5.0, 4.0]]);
+        assert_eq!(selection.mask.as_ref(), Some(&square));
+
+        // A ring drawn as one polygon leaves its hole open
+        let ring = [[0.0, 0.0], [8.0, 0.0], [8.0, 8.0], [0.0, 8.0], [0.0, 0.0], [2.0, 2.0], [2.0, 6.0], [6.0, 6.0], [6.0, 2.0], [2.0, 2.0]];
+        let mask = fill_polygon(8, 8, &ring.map(Pos2::from));
+        assert_eq!([mask.get_pixel(1, 4).0[0], mask.get_pixel(4, 4).0[0], mask.get_pixel(7, 4).0[0]], [255, 0, 255]);
+
+        // Points outside the canvas are clipped
+        let mask = fill_polygon(8, 8, &[[-3.0, -3.0], [3.0, -3.0], [3.0, 3.0], [-3.0, 3.0]].map(Pos2::from));
+        assert_eq!(mask.pixels().filter(|p| p.0[0] == 255).count(), 9);
+    }
+
+    #[test]
+    fn feathering_softens_edges() {
+        let mut selection = selection(32, 32);
+        selection.feather = 6.0;
+        drag(&mut selection, SelectionMode::Replace, &[[8.0, 8.0], [24.0, 24.0]]);
+        let row: Vec<u8> = (0..32).map(|x| value(&selection, x, 16)).collect();
+        assert_eq!((row[0], row[16]), (0, 255));
+        assert!(row[7] > 0 && row[8] < 255 && row[7] < row[8]);
+        assert!(row[..16].windows(2).all(|w| w[0] <= w[1]));
+        // Symmetric around the centre of the square
+        assert!((0..16).all(|x| row[x].abs_diff(row[31 - x]) <= 1));
+
+        let mass: u32 = selection.mask.as_ref().unwrap().pixels().map(|p| p.0[0] as u32).sum();
+        assert!((mass as f32 / (16.0 * 16.0 * 255.0) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn magic_wand_stays_connected() {
+        let red = Rgba([200, 0, 0, 255]);
+        let blue = Rgba([0, 0, 200, 255]);
+        let mut pixels = RgbaImage::from_fn(6, 4, |x, _| if x < 3 { red } else { blue });
+        // Diagonal to the red area and on its own, so not reached
+        pixels.put_pixel(4, 1, red);
+        pixels.put_pixel(3, 0, red);
+        pixels.put_pixel(5, 3, red);
+        // Slightly off red
+        pixels.put_pixel(0, 3, Rgba([220, 0, 0, 255]));
+
+        let count = |mask: &GrayImage| mask.pixels().filter(|p| p.0[0] == 255).count();
+        let mask = magic_wand(&pixels, Pos2::new(1.5, 1.5), 0.1);
+        assert_eq!(count(&mask), 13);
+        assert_eq!([mask.get_pixel(3, 0).0[0], mask.get_pixel(4, 1).0[0], mask.get_pixel(5, 3).0[0]], [255, 0, 0]);
+
+        assert_eq!(count(&magic_wand(&pixels, Pos2::new(1.5, 1.5), 0.05)), 12);
+        assert_eq!(count(&magic_wand(&pixels, Pos2::new(-1.0, 1.5), 1.0)), 0);
+        assert_eq!(count(&magic_wand(&pixels, Pos2::new(1.5, 1.5), 1.0)), 24);
+    }
+
+    #[test]
+    fn wand_results_for_another_size_are_dropped() {
+        let mut selection = selection(4, 4);
+        *selection.wand_result.lock().unwrap() = Some(GrayImage::new(2, 2));
+        selection.poll();
+        assert!(selection.mask.is_none());
+
+        *selection.wand_result.lock().unwrap() = Some(GrayImage::from_fn(4, 4, |x, _| Luma([if x < 2 { 255 } else { 0 }])));
+        selection.poll();
+        assert_eq!(selection.bounds(), Some([0, 0, 2, 4]));
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use bytemuck::{Pod, Zeroable};
+use egui::{ComboBox, Pos2, Rect, Slider};
+use serde::{Deserialize, Serialize};
+use crate::params::ParamValue;
+use crate::weights::WeightHandle;
+
+/// Upper bound of dabs drawn between merges, strokes that lay more are merged into the layer
+/// whenever the dabs fill up and carry on with the rest
+pub const MAX_DABS: usize = 8192;
+
+/// A single brush imprint, laid out to match `Dab` in `shaders/brush.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable, Serialize, Deserialize)]
+pub struct Dab {
+    pub pos: [f32; 2],
+    pub pressure: f32,
+    #[serde(skip)]
+    pub _padding: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    None,
+    /// Curve through the pointer samples
+    CatmullRom,
+    /// The brush trails the pointer on a string and only moves when it is pulled taut
+    PulledString,
+}
+
+impl Smoothing {
+    pub const ALL: [Smoothing; 3] = [Smoothing::None, Smoothing::CatmullRom, Smoothing::PulledString];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Smoothing::None => "none",
+            Smoothing::CatmullRom => "catmull-rom",
+            Smoothing::PulledString => "pulled string",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    pos: Pos2,
+    pressure: f32,
+}
+
+/// Turns the pointer samples collected between frames into evenly spaced dabs.
+/// Dabs accumulate over strokes until they are merged into the image.
+pub struct StrokeEngine {
+    /// Distance between dabs in image pixels
+    pub spacing: f32,
+    pub smoothing: Smoothing,
+    pub string_length: f32,
+    pub dabs: Vec<Dab>,
+    /// Dabs laid after `dabs` filled up, they replace them once those have been merged
+    overflow: Vec<Dab>,
+    samples: Vec<Sample>,
+    /// End of the curve dabs have been laid along so far
+    last: Option<Sample>,
+    /// Distance travelled since the last dab
+    travelled: f32,
+    active: bool,
+}
+
+impl StrokeEngine {
+    pub fn new() -> Self {
+        Self {
+            spacing: 4.0,
+            smoothing: Smoothing::CatmullRom,
+            string_length: 30.0,
+            dabs: vec![],
+            overflow: vec![],
+            samples: vec![],
+            last: None,
+            travelled: 0.0,
+            active: false,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.dabs.len() >= MAX_DABS
+    }
+
+    pub fn begin(&mut self, pos: Pos2, pressure: f32) {
+        let sample = Sample { pos, pressure };
+        self.active = true;
+        self.samples = vec![sample];
+        self.last = Some(sample);
+        self.travelled = 0.0;
+        self.emit(sample);
+    }
+
+    pub fn add_sample(&mut self, pos: Pos2, pressure: f32) {
+        if !self.active {
+            return self.begin(pos, pressure);
+        }
+
+        let sample = Sample { pos, pressure };
+        match self.smoothing {
+            Smoothing::None => self.line_to(sample),
+            Smoothing::PulledString => {
+                let last = self.last.unwrap();
+                let d = sample.pos - last.pos;
+                if d.length() > self.string_length {
+                    let pos = last.pos + d.normalized() * (d.length() - self.string_length);
+                    self.line_to(Sample { pos, pressure });
+                }
+            }
+            Smoothing::CatmullRom => {
+                // Ignore jitter below a pixel, it only produces degenerate segments
+                if self.samples.last().is_some_and(|s| s.pos.distance(pos) < 1.0) {
+                    return;
+                }
+                self.samples.push(sample);
+                let n = self.samples.len();
+                if n >= 3 {
+                    let p0 = self.samples[n.saturating_sub(4)];
+                    let p1 = self.samples[n - 3];
+                    let p2 = self.samples[n - 2];
+                    let p3 = self.samples[n - 1];
+                    self.curve_to(p0, p1, p2, p3);
+                }
+            }
+        }
+    }
+
+    pub fn end(&mut self) {
+        if !self.active {
+            return;
+        }
+
+        // The last catmull-rom segment needs a sample past its end, repeat the final one
+        if self.smoothing == Smoothing::CatmullRom && self.samples.len() >= 2 {
+            let n = self.samples.len();
+            let p0 = self.samples[n.saturating_sub(3)];
+            let p1 = self.samples[n - 2];
+            let p2 = self.samples[n - 1];
+            self.curve_to(p0, p1, p2, p2);
+        }
+
+        self.active = false;
+        self.samples.clear();
+    }
+
+    /// Drops all dabs, called once they have been merged
+    pub fn clear(&mut self) {
+        self.dabs.clear();
+        self.overflow.clear();
+        self.samples.clear();
+        self.last = None;
+        self.active = false;
+    }
+
+    /// Replaces the merged dabs of a full stroke with the ones that didn't fit, the stroke
+    /// in progress carries on
+    pub fn flush(&mut self) {
+        let n = self.overflow.len().min(MAX_DABS);
+        self.dabs = self.overflow.drain(..n).collect();
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(Slider::new(&mut self.spacing, 0.5..=100.0).logarithmic(true).text("spacing"));
+        ComboBox::from_label("smoothing")
+            .selected_text(self.smoothing.name())
+            .show_ui(ui, |ui| {
+                for smoothing in Smoothing::ALL {
+                    ui.selectable_value(&mut self.smoothing, smoothing, smoothing.name());
+                }
+            });
+        if self.smoothing == Smoothing::PulledString {
+            ui.add(Slider::new(&mut self.string_length, 1.0..=200.0).text("string length"));
+        }
+        ui.label(format!("{} dabs", self.dabs.len()));
+    }
+
+    /// Lays dabs along the catmull-rom segment between `p1` and `p2`
+    fn curve_to(&mut self, p0: Sample, p1: Sample, p2: Sample, p3: Sample) {
+        let steps = (p1.pos.distance(p2.pos) / 2.0).ceil().max(1.0) as usize;
+        for i in 1..=steps {
+            let t = i as f32 / steps as f32;
+            let pos = catmull_rom(p0.pos.to_vec2(), p1.pos.to_vec2(), p2.pos.to_vec2(), p3.pos.to_vec2(), t);
+            let pressure = p1.pressure + (p2.pressure - p1.pressure) * t;
+            self.line_to(Sample { pos: pos.to_pos2(), pressure });
+        }
+    }
+
+    /// Lays dabs every `spacing` pixels along the straight line to `to`
+    fn line_to(&mut self, to: Sample) {
+        let from = self.last.unwrap_or(to);
+        let length = from.pos.distance(to.pos);
+        let spacing = self.spacing.max(0.1);
+        // The spacing may have been lowered since the last dab, which is then due right away
+        self.travelled = self.travelled.min(spacing);
+        if length == 0.0 {
+            self.last = Some(to);
+            return;
+        }
+
+        let mut d = spacing - self.travelled;
+        while d <= length {
+            let t = d / length;
+            self.emit(Sample {
+                pos: from.pos + (to.pos - from.pos) * t,
+                pressure: from.pressure + (to.pressure - from.pressure) * t,
+            });
+            d += spacing;
+        }
+
+        self.travelled = length - (d - spacing);
+        self.last = Some(to);
+    }
+
+    fn emit(&mut self, sample: Sample) {
+        let dab = Dab {
+            pos: [sample.pos.x, sample.pos.y],
+            pressure: sample.pressure,
+            _padding: 0.0,
+        };
+        if self.is_full() {
+            self.overflow.push(dab);
+        } else {
+            self.dabs.push(dab);
+        }
+    }
+}
+
+/// Area covered by the centres of `dabs`
+pub fn bounds(dabs: &[Dab]) -> Option<Rect> {
+    dabs.iter()
+        .map(|d| Rect::from_pos(Pos2::new(d.pos[0], d.pos[1])))
+        .reduce(|a, b| a.union(b))
+}
+
+/// How far from a dab a brush can draw, its largest `radius` parameter or weight radius plus a
+/// pixel for antialiasing
+pub fn footprint(params: &HashMap<String, ParamValue>, weights: &[WeightHandle]) -> f32 {
+    let radii = params.iter()
+        .filter(|(name, _)| name.ends_with("radius"))
+        .filter_map(|(_, value)| match value {
+            ParamValue::Float(v) => Some(*v),
+            ParamValue::Int(v) => Some(*v as f32),
+            _ => None,
+        });
+    radii.chain(weights.iter().map(|w| w.radius))
+        .fold(0.0, f32::max) + 1.0
+}
+
+fn catmull_rom(p0: egui::Vec2, p1: egui::Vec2, p2: egui::Vec2, p3: egui::Vec2, t: f32) -> egui::Vec2 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (p1 * 2.0
+        + (p2 - p0) * t
+        + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2
+        + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * t3) * 0.5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(smoothing: Smoothing, spacing: f32) -> StrokeEngine {
+        let mut engine = StrokeEngine::new();
+        engine.smoothing = smoothing;
+        engine.spacing = spacing;
+        engine
+    }
+
+    fn xs(engine: &StrokeEngine) -> Vec<f32> {
+        engine.dabs.iter().map(|d| d.pos[0]).collect()
+    }
+
+    fn assert_spacing(dabs: &[Dab], spacing: f32) {
+        for pair in dabs.windows(2) {
+            let d = Pos2::from(pair[0].pos).distance(Pos2::from(pair[1].pos));
+            assert!((d - spacing).abs() < 1e-3, "dabs {:?} and {:?} are {} apart", pair[0].pos, pair[1].pos, d);
+        }
+    }
+
+    #[test]
+    fn dabs_are_evenly_spaced_across_samples() {
+        let mut engine = engine(Smoothing::None, 2.0);
+        engine.begin(Pos2::new(0.0, 0.0), 1.0);
+        engine.add_sample(Pos2::new(3.0, 0.0), 1.0);
+        engine.add_sample(Pos2::new(10.0, 0.0), 0.0);
+        engine.end();
+        assert_eq!(xs(&engine), [0.0, 2.0, 4.0, 6.0, 8.0, 10.0]);
+        // Pressure is interpolated along the segment
+        assert!((engine.dabs[3].pressure - 4.0 / 7.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn repeated_samples_lay_no_dabs() {
+        let mut engine = engine(Smoothing::None, 2.0);
+        engine.begin(Pos2::new(5.0, 5.0), 1.0);
+        engine.add_sample(Pos2::new(5.0, 5.0), 1.0);
+        engine.add_sample(Pos2::new(5.0, 5.0), 0.5);
+        assert_eq!(engine.dabs.len(), 1);
+        engine.add_sample(Pos2::new(9.0, 5.0), 1.0);
+        assert_eq!(xs(&engine), [5.0, 7.0, 9.0]);
+    }
+
+    #[test]
+    fn lowering_the_spacing_mid_stroke() {
+        let mut engine = engine(Smoothing::None, 10.0);
+        engine.begin(Pos2::new(0.0, 0.0), 1.0);
+        engine.add_sample(Pos2::new(7.0, 0.0), 1.0);
+        engine.spacing = 2.0;
+        engine.add_sample(Pos2::new(7.0, 0.0), 1.0);
+        engine.add_sample(Pos2::new(11.0, 0.0), 1.0);
+        assert!(engine.dabs.iter().all(|d| d.pos.iter().all(|v| v.is_finite())));
+        assert_eq!(xs(&engine), [0.0, 7.0, 9.0, 11.0]);
+    }
+
+    #[test]
+    fn full_strokes_overflow_and_flush() {
+        let mut engine = engine(Smoothing::None, 1.0);
+        engine.begin(Pos2::new(0.0, 0.0), 1.0);
+        engine.add_sample(Pos2::new((MAX_DABS + 100) as f32, 0.0), 1.0);
+        assert!(engine.is_full());
+        assert_eq!(engine.dabs.len(), MAX_DABS);
+
+        // The stroke carries on from the first dab that didn't fit
+        engine.flush();
+        assert!(!engine.is_full());
+        assert_eq!(engine.dabs.len(), 101);
+        assert_eq!(engine.dabs[0].pos, [MAX_DABS as f32, 0.0]);
+        engine.add_sample(Pos2::new((MAX_DABS + 102) as f32, 0.0), 1.0);
+        assert_eq!(engine.dabs.len(), 103);
+
+        engine.clear();
+        assert!(engine.dabs.is_empty() && !engine.is_active());
+    }
+
+    #[test]
+    fn pulled_string_trails_the_pointer() {
+        let mut engine = engine(Smoothing::PulledString, 1.0);
+        engine.string_length = 5.0;
+        engine.begin(Pos2::new(0.0, 0.0), 1.0);
+        // Within the string length the brush doesn't move
+        engine.add_sample(Pos2::new(3.0, 4.0), 1.0);
+        assert_eq!(engine.dabs.len(), 1);
+        engine.add_sample(Pos2::new(20.0, 0.0), 1.0);
+        assert_eq!(engine.dabs.last().unwrap().pos, [15.0, 0.0]);
+        assert_spacing(&engine.dabs, 1.0);
+    }
+
+    #[test]
+    fn catmull_rom_follows_the_samples() {
+        let mut engine = engine(Smoothing::CatmullRom, 3.0);
+        engine.begin(Pos2::new(0.0, 0.0), 1.0);
+        for x in [10.0, 20.0, 30.0] {
+            engine.add_sample(Pos2::new(x, 0.0), 1.0);
+        }
+        // Jitter below a pixel is ignored
+        engine.add_sample(Pos2::new(30.5, 0.0), 1.0);
+        engine.end();
+        assert!(engine.dabs.iter().all(|d| d.pos[1].abs() < 1e-4));
+        assert_spacing(&engine.dabs, 3.0);
+        assert_eq!(engine.dabs.last().unwrap().pos, [30.0, 0.0]);
+
+        // Around a corner the curve still passes through every sample
+        let mut engine = StrokeEngine::new();
+        engine.spacing = 0.5;
+        engine.begin(Pos2::new(0.0, 0.0), 1.0);
+        let samples = [Pos2::new(10.0, 0.0), Pos2::new(10.0, 10.0), Pos2::new(20.0, 10.0)];
+        for pos in samples {
+            engine.add_sample(pos, 1.0);
+        }
+        engine.end();
+        for pos in samples {
+            assert!(engine.dabs.iter().any(|d| Pos2::from(d.pos).distance(pos) < 0.5), "no dab near {:?}", pos);
+        }
+        // and bulges past the corner instead of cutting it
+        assert!(engine.dabs.iter().any(|d| d.pos[0] > 10.0 && d.pos[1] > 0.0 && d.pos[1] < 10.0));
+    }
+}
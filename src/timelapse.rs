@@ -0,0 +1,318 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+use egui::{ComboBox, Slider};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, ImageFormat, RgbaImage};
+use crate::color;
+use crate::color::ColorSpace;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// After every merge, one frame per finished stroke
+    Merge,
+    /// Every `every` rendered frames, strokes in progress included
+    Frames,
+}
+
+impl Trigger {
+    pub const ALL: [Trigger; 2] = [Trigger::Merge, Trigger::Frames];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trigger::Merge => "every merge",
+            Trigger::Frames => "every n frames",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Apng,
+    Gif,
+    /// Numbered pngs in a directory
+    PngSequence,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Apng, Format::Gif, Format::PngSequence];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Apng => "animated png",
+            Format::Gif => "gif",
+            Format::PngSequence => "png sequence",
+        }
+    }
+
+    fn extension(&self) -> Option<&'static str> {
+        match self {
+            Format::Apng => Some("png"),
+            Format::Gif => Some("gif"),
+            Format::PngSequence => None,
+        }
+    }
+}
+
+enum Job {
+    Frame(PathBuf, RgbaImage),
+    Stop,
+}
+
+/// A frame to capture, handed to the read back of the canvas
+pub struct Capture {
+    path: PathBuf,
+    sender: Sender<Job>,
+}
+
+impl Capture {
+    /// Queues `linear`, as read back from the gpu, for encoding on the session's worker thread
+    pub fn write(self, linear: RgbaImage) {
+        let _ = self.sender.send(Job::Frame(self.path, linear));
+    }
+}
+
+/// A timelapse being captured. Frames are encoded to `dir` on a worker thread as they are read
+/// back and turned into the animation once capturing stops.
+pub struct Session {
+    format: Format,
+    fps: u16,
+    output: PathBuf,
+    dir: PathBuf,
+    captured: usize,
+    sender: Sender<Job>,
+    worker: JoinHandle<()>,
+}
+
+impl Session {
+    fn new(format: Format, fps: u16, output: PathBuf, dir: PathBuf) -> Self {
+        let (sender, receiver) = channel();
+        let worker = std::thread::spawn(move || {
+            for job in receiver {
+                match job {
+                    Job::Frame(path, linear) => if let Err(e) = write_frame(&path, linear) {
+                        println!("Failed to write timelapse frame {}: {}", path.display(), e);
+                    },
+                    Job::Stop => break,
+                }
+            }
+        });
+        Self { format, fps, output, dir, captured: 0, sender, worker }
+    }
+
+    /// Writes the captured frames to the output, frames that were read back by now are included.
+    /// Only the png sequence keeps the frame directory.
+    pub fn finish(self) -> Result<usize, Box<dyn Error>> {
+        // Frames queued before the stop are on disk once the worker is done
+        let _ = self.sender.send(Job::Stop);
+        self.worker.join().map_err(|_| "the frame writer panicked")?;
+
+        if self.format == Format::PngSequence {
+            return Ok(self.captured);
+        }
+
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(&self.dir)?
+            .filter_map(|e| Some(e.ok()?.path()))
+            .filter(|p| p.extension().is_some_and(|e| e == "png"))
+            .collect();
+        paths.sort();
+        if paths.is_empty() {
+            return Err("no frames were captured".into());
+        }
+
+        let frames = paths.iter().map(|p| Ok(image::open(p)?.to_rgba8()));
+        match self.format {
+            Format::Apng => write_apng(&self.output, frames, paths.len(), self.fps)?,
+            Format::Gif => write_gif(&self.output, frames, self.fps)?,
+            Format::PngSequence => unreachable!(),
+        }
+        std::fs::remove_dir_all(&self.dir)?;
+        Ok(paths.len())
+    }
+}
+
+pub struct Timelapse {
+    pub trigger: Trigger,
+    pub every: u32,
+    pub format: Format,
+    pub fps: u16,
+    pub path: String,
+    session: Option<Session>,
+    /// Stopped session waiting for its last frames to be read back
+    finished: Option<Session>,
+    frame: u64,
+}
+
+impl Timelapse {
+    pub fn new() -> Self {
+        Self {
+            trigger: Trigger::Merge,
+            every: 10,
+            format: Format::Apng,
+            fps: 10,
+            path: "timelapse.png".to_owned(),
+            session: None,
+            finished: None,
+            frame: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Frames go to a new directory next to the animation, or to the png sequence directory,
+    /// which has to be empty so frames of earlier captures don't mix in
+    pub fn start(&mut self) -> Result<(), Box<dyn Error>> {
+        let output = PathBuf::from(&self.path);
+        let dir = match self.format.extension() {
+            Some(extension) => {
+                let output = output.with_extension(extension);
+                let stem = output.file_stem().and_then(|s| s.to_str()).unwrap_or("timelapse");
+                (0..)
+                    .map(|i| match i {
+                        0 => output.with_file_name(format!("{}_frames", stem)),
+                        i => output.with_file_name(format!("{}_frames_{}", stem, i)),
+                    })
+                    .find(|dir| !dir.exists())
+                    .unwrap()
+            }
+            None => {
+                if output.read_dir().is_ok_and(|mut entries| entries.next().is_some()) {
+                    return Err(format!("{} isn't empty", output.display()).into());
+                }
+                output.clone()
+            }
+        };
+        std::fs::create_dir_all(&dir)?;
+
+        let output = self.format.extension().map(|e| output.with_extension(e)).unwrap_or(output);
+        self.session = Some(Session::new(self.format, self.fps.max(1), output, dir));
+        self.frame = 0;
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.finished = self.session.take();
+    }
+
+    /// Session that was stopped since the last call
+    pub fn take_finished(&mut self) -> Option<Session> {
+        self.finished.take()
+    }
+
+    /// Called once per rendered frame, returns where the canvas goes if it should be captured
+    pub fn capture(&mut self, merged: bool) -> Option<Capture> {
+        let session = self.session.as_mut()?;
+        self.frame += 1;
+        let capture = match self.trigger {
+            Trigger::Merge => merged,
+            Trigger::Frames => self.frame % self.every.max(1) as u64 == 0,
+        };
+        if !capture {
+            return None;
+        }
+
+        let path = session.dir.join(format!("frame_{:05}.png", session.captured));
+        session.captured += 1;
+        Some(Capture { path, sender: session.sender.clone() })
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.add_enabled_ui(!self.is_active(), |ui| {
+            ComboBox::from_label("timelapse")
+                .selected_text(self.trigger.name())
+                .show_ui(ui, |ui| {
+                    for trigger in Trigger::ALL {
+                        ui.selectable_value(&mut self.trigger, trigger, trigger.name());
+                    }
+                });
+            if self.trigger == Trigger::Frames {
+                ui.add(Slider::new(&mut self.every, 1..=600).logarithmic(true).text("frames"));
+            }
+            ComboBox::from_label("timelapse format")
+                .selected_text(self.format.name())
+                .show_ui(ui, |ui| {
+                    for format in Format::ALL {
+                        ui.selectable_value(&mut self.format, format, format.name());
+                    }
+                });
+            if self.format != Format::PngSequence {
+                ui.add(Slider::new(&mut self.fps, 1..=60).text("fps"));
+            }
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.path);
+                if ui.button("…").clicked() {
+                    let dialog = rfd::FileDialog::new();
+                    let path = match self.format.extension() {
+                        Some(extension) => dialog.add_filter("animation", &[extension]).save_file(),
+                        None => dialog.pick_folder(),
+                    };
+                    if let Some(path) = path {
+                        self.path = path.to_string_lossy().into_owned();
+                    }
+                }
+            });
+        });
+
+        match &self.session {
+            Some(session) => {
+                ui.label(format!("{} frames captured", session.captured));
+                if ui.button("stop timelapse").clicked() {
+                    self.stop();
+                }
+            }
+            None => {
+                if ui.button("start timelapse").clicked() {
+                    if let Err(e) = self.start() {
+                        println!("Failed to start timelapse in {}: {}", self.path, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Writes a captured frame, `linear` as read back from the gpu
+fn write_frame(path: &Path, linear: RgbaImage) -> Result<(), Box<dyn Error>> {
+    color::encode(linear, ColorSpace::Srgb).save_with_format(path, ImageFormat::Png)?;
+    Ok(())
+}
+
+fn write_apng(
+    path: &Path,
+    frames: impl Iterator<Item = Result<RgbaImage, Box<dyn Error>>>,
+    count: usize,
+    fps: u16
+) -> Result<(), Box<dyn Error>> {
+    let mut frames = frames.peekable();
+    let (width, height) = match frames.peek() {
+        Some(Ok(first)) => first.dimensions(),
+        _ => return Err("no frames were captured".into()),
+    };
+
+    let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_animated(count as u32, 0)?;
+    encoder.set_frame_delay(1, fps)?;
+    let mut writer = encoder.write_header()?;
+    for frame in frames {
+        writer.write_image_data(frame?.as_raw())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+fn write_gif(path: &Path, frames: impl Iterator<Item = Result<RgbaImage, Box<dyn Error>>>, fps: u16) -> Result<(), Box<dyn Error>> {
+    let mut encoder = GifEncoder::new_with_speed(BufWriter::new(File::create(path)?), 10);
+    encoder.set_repeat(Repeat::Infinite)?;
+    let delay = Delay::from_numer_denom_ms(1000, fps as u32);
+    for frame in frames {
+        encoder.encode_frame(image::Frame::from_parts(frame?, 0, 0, delay))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use bytemuck::{Pod, Zeroable};
+use egui::{Button, Color32, ComboBox, Painter, Pos2, Rect, Slider, Stroke, StrokeKind, Vec2};
+use serde::{Deserialize, Serialize};
+
+/// Upper bound of handles uploaded to the brushes
+pub const MAX_WEIGHTS: usize = 256;
+/// Size of the handle squares in image pixels, also how close the pointer has to be to grab one
+const HANDLE_SIZE: f32 = 10.0;
+
+/// A user placed point brushes can draw around
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredWeight", into = "StoredWeight")]
+pub struct WeightHandle {
+    pub pos: Pos2,
+    pub strength: f32,
+    pub radius: f32,
+}
+
+impl WeightHandle {
+    pub fn new(pos: Pos2) -> Self {
+        Self { pos, strength: 1.0, radius: 25.0 }
+    }
+
+    pub fn gpu(&self) -> GpuWeight {
+        GpuWeight {
+            pos: [self.pos.x, self.pos.y],
+            strength: self.strength,
+            radius: self.radius,
+        }
+    }
+}
+
+/// Weight handle as written to project files
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredWeight {
+    Handle { pos: [f32; 2], strength: f32, radius: f32 },
+    /// Older projects only stored the position
+    Position([f32; 2]),
+}
+
+impl From<StoredWeight> for WeightHandle {
+    fn from(stored: StoredWeight) -> Self {
+        match stored {
+            StoredWeight::Handle { pos, strength, radius } => Self { pos: Pos2::new(pos[0], pos[1]), strength, radius },
+            StoredWeight::Position(pos) => Self::new(Pos2::new(pos[0], pos[1])),
+        }
+    }
+}
+
+impl From<WeightHandle> for StoredWeight {
+    fn from(handle: WeightHandle) -> Self {
+        StoredWeight::Handle { pos: [handle.pos.x, handle.pos.y], strength: handle.strength, radius: handle.radius }
+    }
+}
+
+/// A weight handle laid out to match `Weight` in `shaders/brush.comp`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct GpuWeight {
+    pub pos: [f32; 2],
+    pub strength: f32,
+    pub radius: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Snap {
+    None,
+    Pixel,
+    Grid,
+}
+
+impl Snap {
+    pub const ALL: [Snap; 3] = [Snap::None, Snap::Pixel, Snap::Grid];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Snap::None => "none",
+            Snap::Pixel => "pixel",
+            Snap::Grid => "grid",
+        }
+    }
+}
+
+pub struct Weights {
+    pub handles: Vec<WeightHandle>,
+    pub selected: Option<usize>,
+    pub hovered: Option<usize>,
+    pub snap: Snap,
+    pub grid: f32,
+    /// Handle being dragged and where it was grabbed relative to its centre
+    drag: Option<(usize, Vec2)>,
+}
+
+impl Weights {
+    pub fn new() -> Self {
+        Self::from_handles(vec![WeightHandle::new(Pos2::new(100.0, 100.0)), WeightHandle::new(Pos2::new(200.0, 100.0))])
+    }
+
+    pub fn from_handles(handles: Vec<WeightHandle>) -> Self {
+        let selected = if handles.is_empty() { None } else { Some(0) };
+        Self {
+            handles,
+            selected,
+            hovered: None,
+            snap: Snap::None,
+            grid: 16.0,
+            drag: None,
+        }
+    }
+
+    /// Adds a handle at `pos` and selects it
+    pub fn add(&mut self, pos: Pos2) {
+        if self.handles.len() >= MAX_WEIGHTS {
+            return;
+        }
+        // New handles take over the properties of the selected one
+        let mut handle = self.selected_handle().copied().unwrap_or(WeightHandle::new(pos));
+        handle.pos = self.snapped(pos);
+        self.handles.push(handle);
+        self.selected = Some(self.handles.len() - 1);
+    }
+
+    pub fn delete_selected(&mut self) {
+        if let Some(i) = self.selected {
+            self.handles.remove(i);
+            self.drag = None;
+            self.hovered = None;
+            self.selected = if self.handles.is_empty() { None } else { Some(i.min(self.handles.len() - 1)) };
+        }
+    }
+
+    pub fn selected_handle(&self) -> Option<&WeightHandle> {
+        self.handles.get(self.selected?)
+    }
+
+    pub fn selected_handle_mut(&mut self) -> Option<&mut WeightHandle> {
+        self.handles.get_mut(self.selected?)
+    }
+
+    /// Topmost handle under `pos`
+    pub fn hit(&self, pos: Pos2) -> Option<usize> {
+        self.handles.iter().rposition(|h| Rect::from_center_size(h.pos, Vec2::splat(HANDLE_SIZE)).contains(pos))
+    }
+
+    pub fn snapped(&self, pos: Pos2) -> Pos2 {
+        match self.snap {
+            Snap::None => pos,
+            // Pixel centres, where the brushes sample
+            Snap::Pixel => pos.floor() + Vec2::splat(0.5),
+            Snap::Grid => (pos / self.grid.max(1.0)).round() * self.grid.max(1.0),
+        }
+    }
+
+    /// Grabs the handle under `pos`, returns false if there is none
+    pub fn begin_drag(&mut self, pos: Pos2) -> bool {
+        match self.hit(pos) {
+            Some(i) => {
+                self.selected = Some(i);
+                self.drag = Some((i, self.handles[i].pos - pos));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn drag_to(&mut self, pos: Pos2) {
+        if let Some((i, offset)) = self.drag {
+            self.handles[i].pos = self.snapped(pos + offset);
+        }
+    }
+
+    pub fn end_drag(&mut self) {
+        self.drag = None;
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        self.drag.is_some()
+    }
+
+    /// Moves the selected handle by `steps`, a pixel or a grid cell each
+    pub fn nudge(&mut self, steps: Vec2) {
+        let step = if self.snap == Snap::Grid { self.grid.max(1.0) } else { 1.0 };
+        if let Some(i) = self.selected {
+            let pos = self.handles[i].pos + steps * step;
+            self.handles[i].pos = if self.snap == Snap::None { pos } else { self.snapped(pos) };
+        }
+    }
+
+    /// Draws the handles, their radius and the segments between them in image coordinates.
+    /// The first segment is the one `weight_1` and `weight_2` describe and is drawn brighter.
+    pub fn paint(&self, painter: &Painter) {
+        for (i, pair) in self.handles.windows(2).enumerate() {
+            let alpha = if i == 0 { 1.0 } else { 0.35 };
+            painter.line_segment([pair[0].pos, pair[1].pos], Stroke::new(1., Color32::WHITE.gamma_multiply(alpha)));
+        }
+
+        for (i, handle) in self.handles.iter().enumerate() {
+            let color = if self.selected == Some(i) { Color32::from_rgb(255, 200, 0) } else { Color32::WHITE };
+            let rect = Rect::from_center_size(handle.pos, Vec2::splat(HANDLE_SIZE));
+            if self.hovered == Some(i) || self.drag.is_some_and(|(d, _)| d == i) {
+                painter.rect_filled(rect, 0, color.gamma_multiply(0.4));
+            }
+            painter.rect_stroke(rect, 0, Stroke::new(1., color), StrokeKind::Inside);
+            painter.circle_stroke(handle.pos, handle.radius, Stroke::new(0.5, color.gamma_multiply(0.5)));
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("add").clicked() {
+                let pos = self.selected_handle().map(|h| h.pos + egui::vec2(20.0, 20.0)).unwrap_or(Pos2::new(100.0, 100.0));
+                self.add(pos);
+            }
+            if ui.add_enabled(self.selected.is_some(), Button::new("delete")).clicked() {
+                self.delete_selected();
+            }
+        });
+
+        for (i, handle) in self.handles.iter().enumerate() {
+            let label = format!("weight {} ({:.0}, {:.0})", i, handle.pos.x, handle.pos.y);
+            if ui.add(Button::new(label).selected(self.selected == Some(i))).clicked() {
+                self.selected = Some(i);
+            }
+        }
+
+        if let Some(handle) = self.selected_handle_mut() {
+            ui.add(Slider::new(&mut handle.strength, 0.0..=4.0).text("strength"));
+            ui.add(Slider::new(&mut handle.radius, 0.0..=500.0).text("radius"));
+        }
+
+        ComboBox::from_label("snap")
+            .selected_text(self.snap.name())
+            .show_ui(ui, |ui| {
+                for snap in Snap::ALL {
+                    ui.selectable_value(&mut self.snap, snap, snap.name());
+                }
+            });
+        if self.snap == Snap::Grid {
+            ui.add(Slider::new(&mut self.grid, 1.0..=256.0).text("grid"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_positions_load_with_defaults() {
+        let handles: Vec<WeightHandle> =
+            serde_json::from_str(r#"[[10.0, 20.0], {"pos": [1.0, 2.0], "strength": 0.5, "radius": 8.0}]"#).unwrap();
+        assert_eq!(handles[0], WeightHandle::new(Pos2::new(10.0, 20.0)));
+        assert_eq!(handles[1], WeightHandle { pos: Pos2::new(1.0, 2.0), strength: 0.5, radius: 8.0 });
+    }
+
+    #[test]
+    fn handles_are_written_in_full() {
+        let handle = WeightHandle { pos: Pos2::new(3.0, 4.0), strength: 0.25, radius: 12.0 };
+        let json = serde_json::to_value(handle).unwrap();
+        assert_eq!(json, serde_json::json!({ "pos": [3.0, 4.0], "strength": 0.25, "radius": 12.0 }));
+        assert_eq!(serde_json::from_value::<WeightHandle>(json).unwrap(), handle);
+    }
+
+    #[test]
+    fn incomplete_handles_are_rejected() {
+        assert!(serde_json::from_str::<WeightHandle>(r#"{"pos": [1.0, 2.0], "strength": 0.5}"#).is_err());
+        assert!(serde_json::from_str::<WeightHandle>("[1.0]").is_err());
+    }
+
+    #[test]
+    fn snapping() {
+        let mut weights = Weights::from_handles(Vec::new());
+        let pos = Pos2::new(10.2, 23.9);
+        assert_eq!(weights.snapped(pos), pos);
+        weights.snap = Snap::Pixel;
+        assert_eq!(weights.snapped(pos), Pos2::new(10.5, 23.5));
+        weights.snap = Snap::Grid;
+        assert_eq!(weights.snapped(pos), Pos2::new(16.0, 16.0));
+        // A grid below a pixel is treated as a pixel grid
+        weights.grid = 0.0;
+        assert_eq!(weights.snapped(pos), Pos2::new(10.0, 24.0));
+    }
+
+    #[test]
+    fn dragging_keeps_the_grab_offset() {
+        let mut weights = Weights::new();
+        assert!(!weights.begin_drag(Pos2::new(150.0, 100.0)));
+        assert!(weights.begin_drag(Pos2::new(202.0, 101.0)));
+        assert_eq!(weights.selected, Some(1));
+        weights.drag_to(Pos2::new(52.0, 51.0));
+        weights.end_drag();
+        assert_eq!(weights.handles[1].pos, Pos2::new(50.0, 50.0));
+        assert!(!weights.is_dragging());
+    }
+
+    #[test]
+    fn adding_and_deleting_handles() {
+        let mut weights = Weights::new();
+        weights.handles[0].radius = 40.0;
+        weights.add(Pos2::new(5.0, 5.0));
+        assert_eq!(weights.selected, Some(2));
+        assert_eq!(weights.handles[2], WeightHandle { pos: Pos2::new(5.0, 5.0), strength: 1.0, radius: 40.0 });
+
+        weights.selected = Some(1);
+        weights.add(Pos2::new(6.0, 6.0));
+        assert_eq!(weights.handles[3].radius, 25.0);
+
+        weights.delete_selected();
+        assert_eq!(weights.handles.len(), 3);
+        assert_eq!(weights.selected, Some(2));
+        while weights.selected.is_some() {
+            weights.delete_selected();
+        }
+        assert!(weights.handles.is_empty());
+
+        for i in 0..MAX_WEIGHTS + 4 {
+            weights.add(Pos2::new(i as f32, 0.0));
+        }
+        assert_eq!(weights.handles.len(), MAX_WEIGHTS);
+    }
+}